@@ -1,8 +1,15 @@
 #![cfg_attr(test, allow(dead_code))]
 
 extern crate mudpie;
-use mudpie::{WebServer, WebRequest, WebResponse};
+use mudpie::{WebServer, WebRequest, WebResponse, Middleware};
+use mudpie::{WebSocket, WebSocketMessage};
+use mudpie::{Event, EventHub};
+use mudpie::{Cookie, SameSite, StaticFiles};
 use mudpie::html_element_escape;
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 /*
 
@@ -17,6 +24,35 @@ Optional env vars:
 */
 
 
+// Application state, shared by all handlers
+struct DemoState {
+    hits: AtomicUsize,
+}
+
+
+// Adds a header to every response
+struct PoweredByHeader;
+
+impl Middleware for PoweredByHeader {
+    fn after(&self, _req: &WebRequest, resp: &mut WebResponse) {
+        resp.set_header("x-powered-by", "mudpie");
+    }
+}
+
+
+// Answers every request itself, so the handler never runs
+struct Forbidden;
+
+impl Middleware for Forbidden {
+    fn before(&self, _req: &mut WebRequest) -> Option<WebResponse> {
+        let mut resp = WebResponse::new();
+        resp.set_code(403, "Forbidden");
+        resp.set_body_str("Error 403: Forbidden by middleware");
+        return Some(resp);
+    }
+}
+
+
 fn main() {
     let mut args = Vec::new();
     args.extend(std::env::args());
@@ -38,20 +74,104 @@ fn main() {
 
     let mut svr = WebServer::new();
     //svr.set_max_request_body_size(10);
+    svr.set_state(DemoState { hits: AtomicUsize::new(0) });
+    svr.set_auto_etag(true);
+    svr.set_compression(true);
+    svr.set_request_decompression(true);
+    svr.add_middleware(PoweredByHeader);
+    svr.add_middleware_prefix("/private/", Forbidden);
 
     // Setup dispatch rules
     svr.add_path("get", "/bench", bench_page);
+    svr.add_path("get", "/bench/route/:id", bench_page);
     svr.add_path("GET, HEAD", "/", index_page);
     svr.add_path("get, head", "/hello", hello_page);
     svr.add_path_prefix("get,head", "/hello/", hello_page);
     svr.add_path("get", "/panic", panic_page);
+    svr.add_path("get", "/users/:id/posts/:post_id", user_post_page);
 
     svr.add_path("get", "/form_enter", form_enter);
     svr.add_path("post", "/form_post", form_post);
+    svr.add_path("post", "/upload_post", upload_post);
 
     svr.add_path("put,options,foo", "/silly_methods", hello_page);
 
-    svr.run(listen_addr, listen_port);
+    svr.add_path("get, head", "/stream", stream_page);
+    svr.add_path("get", "/counter", counter_page);
+    svr.add_path("get", "/cookies", cookie_page);
+    svr.add_path("get, head", "/squares.json", squares_page);
+    svr.add_path("get", "/echo", echo_page);
+    svr.add_websocket("/echo/ws", echo_websocket);
+
+    // Every open /ticks/events stream gets a tick each second
+    let hub = Arc::new(EventHub::new());
+    let ticker_hub = hub.clone();
+    thread::spawn(move || {
+        let mut n = 0;
+        loop {
+            thread::sleep_ms(1000);
+            n += 1;
+            let mut event = Event::new(&format!("tick {}", n));
+            event.set_id(&n.to_string());
+            ticker_hub.publish(&event);
+        }
+    });
+    svr.add_path("get", "/ticks", ticks_page);
+    svr.add_path("get", "/ticks/events", move |req: &WebRequest| {
+        let (resp, sender) = WebResponse::new_event_stream();
+        // A reconnecting browser says where it left off
+        match req.get_last_event_id() {
+            Some(id) => {
+                let mut event = Event::new(&format!("resumed after {}", id));
+                event.set_event("status");
+                sender.send(&event);
+            },
+            None => {},
+        }
+        hub.add(sender);
+        return resp;
+    });
+
+    // Serve the public directory.  It holds only files meant for clients,
+    // and directory listing is left off.
+    svr.add_static_files(StaticFiles::new("/files/", "./public"));
+
+    // Handlers can be closures too
+    let motd = format!("Started by: {}", args[0]);
+    svr.add_path("get", "/motd", move |_req: &WebRequest| {
+        return WebResponse::new_html(to_html(html_element_escape(&motd)));
+    });
+
+    // A few hundred API style routes, so /bench/route/N shows the routing
+    // cost with a big rule table is still tiny.
+    for i in 0..300 {
+        let path = format!("/api/v1/resource{}/:id/items/:item_id", i);
+        svr.add_path("get, put, delete", &path, bench_page);
+    }
+
+    run_server(&mut svr, listen_addr, listen_port, &args);
+}
+
+
+// Built with the "openssl" feature, certificate and key file arguments
+// serve HTTPS instead.  ex: cargo run --features openssl -- 127.0.0.1 8443
+// cert.pem key.pem
+#[cfg(feature = "openssl")]
+fn run_server(svr: &mut WebServer, addr: &str, port: i32, args: &[String]) {
+    if args.len() > 4 {
+        let acceptor = match mudpie::OpenSslAcceptor::new(&args[3], &args[4]) {
+            Ok(acceptor) => acceptor,
+            Err(err) => panic!("loading certificate failed: {}", err),
+        };
+        svr.run_tls(addr, port, acceptor);
+    } else {
+        svr.run(addr, port);
+    }
+}
+
+#[cfg(not(feature = "openssl"))]
+fn run_server(svr: &mut WebServer, addr: &str, port: i32, _args: &[String]) {
+    svr.run(addr, port);
 }
 
 
@@ -108,11 +228,38 @@ fn index_page(_req: &WebRequest) -> WebResponse {
 <dt><a href="/hello/some/resource">/hello/some/resource</a> 
 <dd>Anything under the "/hello/" prefix also works
 
+<dt><a href="/users/42/posts/7">/users/42/posts/7</a> 
+<dd>Route pattern "/users/:id/posts/:post_id", with captured params
+
+<dt><a href="/counter">/counter</a> 
+<dd>Hit counter kept in application state (see set_state)
+
+<dt><a href="/motd">/motd</a> 
+<dd>A closure handler, with captured data
+
+<dt><a href="/cookies">/cookies</a> 
+<dd>Counts your visits with a cookie
+
+<dt><a href="/squares.json">/squares.json</a> 
+<dd>A large JSON response, sent gzip compressed if the browser accepts it
+
+<dt><a href="/echo">/echo</a> 
+<dd>A WebSocket that echoes what you send
+
+<dt><a href="/ticks">/ticks</a> 
+<dd>Server-Sent Events, broadcast to every open page
+
+<dt><a href="/files/">/files/</a> 
+<dd>Files in the public directory, with Range and conditional requests
+
+<dt><a href="/private/hello">/private/hello</a> 
+<dd>Blocked by middleware registered for the "/private/" prefix
+
 <dt><a href="/panic">/panic</a> 
 <dd>A crashing handler
 
 <dt><a href="/form_enter">/form_enter</a> 
-<dd>Form Submission Example
+<dd>Form Submission and File Upload Examples
 
 <dt><a href="/form_post">/form_post</a> 
 <dd>Only allows POST
@@ -120,9 +267,16 @@ fn index_page(_req: &WebRequest) -> WebResponse {
 <dt><a href="/silly_methods">/silly_methods</a> 
 <dd>Only allows PUT, OPTIONS, and FOO methods. See Allow: header
 
+<dt><a href="/stream">/stream</a> 
+<dd>A 10MB streamed (chunked) response, generated while it is sent
+
 <dt><a href="/bench">/bench</a> 
 <dd>A super-tiny resource useful for benchmarking socket performance
 
+<dt><a href="/bench/route/42">/bench/route/42</a> 
+<dd>Same as /bench, but routed through a pattern, with a few hundred other
+routes in the table
+
 </dl>
 "##);
     page = to_html(page);
@@ -142,12 +296,139 @@ fn hello_page(req: &WebRequest) -> WebResponse {
 }
 
 
+fn user_post_page(req: &WebRequest) -> WebResponse {
+    let mut page = String::new();
+    page.push_str("<h1>User Post</h1>");
+    match req.get_param_as::<u64>("id") {
+        Some(id) => page.push_str(&format!("<p>User id: {}</p>", id)),
+        None => page.push_str("<p>User id is not a number</p>"),
+    }
+    let post_id = req.get_param("post_id").unwrap();
+    page.push_str(&format!("<p>Post id: {}</p>",
+                html_element_escape(post_id)));
+    page = to_html(page);
+    return WebResponse::new_html(page);
+}
+
+
+fn counter_page(req: &WebRequest) -> WebResponse {
+    let state = req.get_state::<DemoState>().unwrap();
+    let hits = state.hits.fetch_add(1, Ordering::SeqCst) + 1;
+    let page = format!("<h1>This page has been viewed {} times</h1>", hits);
+    return WebResponse::new_html(to_html(page));
+}
+
+
+// Counts visits per browser, using a cookie
+fn cookie_page(req: &WebRequest) -> WebResponse {
+    let visits = match req.get_cookies().get("visits") {
+        Some(val) => val.parse::<u64>().unwrap_or(0) + 1,
+        None => 1,
+    };
+    let page = format!("<h1>You have visited this page {} times</h1>",
+            visits);
+    let mut ret = WebResponse::new_html(to_html(page));
+
+    let mut cookie = Cookie::new("visits", &visits.to_string()).unwrap();
+    cookie.set_path("/cookies").unwrap();
+    cookie.set_max_age(3600);
+    cookie.set_same_site(SameSite::Lax);
+    ret.add_cookie(cookie);
+
+    let mut cookie = Cookie::new("demo", "1").unwrap();
+    cookie.set_path("/cookies").unwrap();
+    cookie.set_http_only(true);
+    ret.add_cookie(cookie);
+    return ret;
+}
+
+
+// Large and repetitive, so it compresses well
+fn squares_page(_req: &WebRequest) -> WebResponse {
+    let mut items = Vec::new();
+    for i in 0..1000 {
+        items.push(format!("{{\"n\": {}, \"square\": {}}}", i, i * i));
+    }
+    let mut ret = WebResponse::new();
+    ret.set_header("Content-Type", "application/json");
+    ret.set_body_str(&format!("[{}]", items.join(", ")));
+    return ret;
+}
+
+
+fn echo_page(_req: &WebRequest) -> WebResponse {
+    let mut page = String::new();
+    page.push_str("<h1>WebSocket Echo</h1>");
+    page.push_str(r##"
+<input type="text" id="msg"> <button id="send">Send</button>
+<pre id="log"></pre>
+<script>
+var log = document.getElementById("log");
+var ws = new WebSocket("ws://" + location.host + "/echo/ws");
+ws.onmessage = function(e) { log.textContent += e.data + "\n"; };
+ws.onclose = function(e) { log.textContent += "closed: " + e.code + "\n"; };
+document.getElementById("send").onclick = function() {
+    ws.send(document.getElementById("msg").value);
+};
+</script>
+"##);
+    page = to_html(page);
+    return WebResponse::new_html(page);
+}
+
+
+fn ticks_page(_req: &WebRequest) -> WebResponse {
+    let mut page = String::new();
+    page.push_str("<h1>Server-Sent Events</h1>");
+    page.push_str(r##"
+<pre id="log"></pre>
+<script>
+var log = document.getElementById("log");
+var source = new EventSource("/ticks/events");
+source.onmessage = function(e) { log.textContent += e.data + "\n"; };
+source.addEventListener("status", function(e) {
+    log.textContent += "(" + e.data + ")\n";
+});
+</script>
+"##);
+    page = to_html(page);
+    return WebResponse::new_html(page);
+}
+
+
+// Runs until the client closes the connection
+fn echo_websocket(_req: &WebRequest, ws: &mut WebSocket) {
+    loop {
+        let ret = match ws.recv() {
+            Ok(WebSocketMessage::Text(text)) => {
+                ws.send_text(&format!("echo: {}", text))
+            },
+            Ok(WebSocketMessage::Binary(data)) => ws.send_binary(&data),
+            Err(..) => return,
+        };
+        if ret.is_err() {
+            return;
+        }
+    }
+}
+
+
 fn bench_page(_req: &WebRequest) -> WebResponse {
     let page = "Hello World!".to_string();
     return WebResponse::new_html(page);
 }
 
 
+// The body is generated as it's sent, instead of being built in memory
+fn stream_page(_req: &WebRequest) -> WebResponse {
+    let body = std::io::repeat(b'x').take(10_000_000);
+    let mut ret = WebResponse::new();
+    ret.set_body_reader(body);
+    ret.set_header("Content-Type", "text/plain");
+    return ret;
+}
+
+
 // This will automatically generate a 500 Internal Server Error
 fn panic_page(_req: &WebRequest) -> WebResponse {
     panic!("I can't go on!");
@@ -166,6 +447,14 @@ Last Name: <input type="text" name="lname">
 <br/>
 <input type="submit" value="Submit">
 </form>
+<h2>Upload to /upload_post</h2>
+<form action="/upload_post" method="Post" enctype="multipart/form-data">
+Description: <input type="text" name="description">
+<br/>
+File: <input type="file" name="file" multiple>
+<br/>
+<input type="submit" value="Upload">
+</form>
 "##);
     page = to_html(page);
     return WebResponse::new_html(page);
@@ -175,6 +464,40 @@ Last Name: <input type="text" name="lname">
 fn form_post(req: &WebRequest) -> WebResponse {
     let mut page = String::new();
     page.push_str("<h1>Thank you for the POST</h1>");
+    let form = req.get_form();
+    let fname = form.get("fname").unwrap_or(String::new());
+    let lname = form.get("lname").unwrap_or(String::new());
+    page.push_str(&format!("<p>Hello, {} {}!</p>",
+                html_element_escape(&fname), html_element_escape(&lname)));
     page.push_str(&get_debug_info(req));
     return WebResponse::new_html(page);
 }
+
+
+fn upload_post(req: &WebRequest) -> WebResponse {
+    let parts = match req.get_multipart(20, 4096) {
+        Ok(parts) => parts,
+        Err(e) => {
+            let mut resp = WebResponse::new();
+            resp.set_code(400, "Bad Request");
+            resp.set_body_str(&format!("Bad upload: {:?}", e));
+            return resp;
+        },
+    };
+    let mut page = String::new();
+    page.push_str("<h1>Thank you for the upload</h1>");
+    page.push_str("<table><tr><th>Name</th><th>Filename</th>\
+                  <th>Content-Type</th><th>Size</th></tr>");
+    for part in parts.iter() {
+        let content_type = part.get_content_type().unwrap_or(b"");
+        page.push_str(&format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    html_element_escape(part.get_name().unwrap_or("")),
+                    html_element_escape(part.get_filename().unwrap_or("")),
+                    html_element_escape(
+                        &String::from_utf8_lossy(content_type)),
+                    part.get_data().len()));
+    }
+    page.push_str("</table>");
+    return WebResponse::new_html(page);
+}
@@ -2,9 +2,30 @@
 #![feature(owned_ascii_ext)]
 #![feature(vec_resize)]
 #![feature(tcp)]
+#![feature(socket_timeout)]
+#![feature(duration)]
+#![feature(fs_canonicalize)]
+#![feature(wait_timeout)]
+#![cfg_attr(test, feature(test))]
+
+#[cfg(feature = "openssl")]
+extern crate openssl;
+#[cfg(test)]
+extern crate test;
 
 pub use webserver::{WebServer, WebRequest, WebResponse};
-pub use webserver::{PageFunction};
+pub use webserver::{PageFunction, WebSocketFunction, Middleware};
+pub use webserver::StaticFiles;
+pub use webserver::{WebSocket, WebSocketMessage, WebSocketError};
+pub use webserver::{Event, EventSender, EventHub};
+pub use webserver::{TlsAcceptor, TlsInfo};
+#[cfg(feature = "openssl")]
+pub use webserver::OpenSslAcceptor;
+pub use utils::genericsocket::GenericSocket;
 pub use utils::escape::html_element_escape;
+pub use utils::urlencoded::FormData;
+pub use utils::multipart::{MultipartPart, MultipartError};
+pub use utils::cookie::{Cookie, CookieJar, CookieError, SameSite};
+pub use utils::etag::ETag;
 mod utils;
 mod webserver;
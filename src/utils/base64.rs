@@ -0,0 +1,102 @@
+//! Base64 (RFC 4648), with the standard alphabet and padding
+
+static ALPHABET: &'static [u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+
+/// Encode bytes as base64
+pub fn encode(data: &[u8]) -> String {
+    let mut ret = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b0 = chunk[0] as usize;
+        let b1 = if chunk.len() > 1 { chunk[1] as usize } else { 0 };
+        let b2 = if chunk.len() > 2 { chunk[2] as usize } else { 0 };
+        ret.push(ALPHABET[b0 >> 2] as char);
+        ret.push(ALPHABET[((b0 & 0x03) << 4) | (b1 >> 4)] as char);
+        if chunk.len() > 1 {
+            ret.push(ALPHABET[((b1 & 0x0f) << 2) | (b2 >> 6)] as char);
+        } else {
+            ret.push('=');
+        }
+        if chunk.len() > 2 {
+            ret.push(ALPHABET[b2 & 0x3f] as char);
+        } else {
+            ret.push('=');
+        }
+    }
+    return ret;
+}
+
+
+/// Decode base64.  Returns None if the input isn't valid, padded base64.
+pub fn decode(input: &[u8]) -> Option<Vec<u8>> {
+    if input.len() % 4 != 0 {
+        return None;
+    }
+    let mut ret = Vec::with_capacity(input.len() / 4 * 3);
+    let nr_chunks = input.len() / 4;
+    for (i, chunk) in input.chunks(4).enumerate() {
+        // Padding is only allowed at the end
+        let padding = if i + 1 == nr_chunks {
+            chunk.iter().rev().take_while(|c| **c == b'=').count()
+        } else {
+            0
+        };
+        if padding > 2 {
+            return None;
+        }
+        let mut n: u32 = 0;
+        for c in chunk[..4 - padding].iter() {
+            let val = match decode_char(*c) {
+                Some(val) => val,
+                None => return None,
+            };
+            n = (n << 6) | val;
+        }
+        n <<= 6 * padding as u32;
+        ret.push((n >> 16) as u8);
+        if padding < 2 {
+            ret.push((n >> 8) as u8);
+        }
+        if padding < 1 {
+            ret.push(n as u8);
+        }
+    }
+    return Some(ret);
+}
+
+
+fn decode_char(c: u8) -> Option<u32> {
+    return match c {
+        b'A'...b'Z' => Some((c - b'A') as u32),
+        b'a'...b'z' => Some((c - b'a') as u32 + 26),
+        b'0'...b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+}
+
+
+#[test]
+fn test_base64() {
+    let cases: [(&[u8], &str); 7] = [
+        (b"", ""),
+        (b"f", "Zg=="),
+        (b"fo", "Zm8="),
+        (b"foo", "Zm9v"),
+        (b"foob", "Zm9vYg=="),
+        (b"fooba", "Zm9vYmE="),
+        (b"foobar", "Zm9vYmFy"),
+    ];
+    for &(raw, encoded) in cases.iter() {
+        assert_eq!(encode(raw), encoded);
+        assert_eq!(decode(encoded.as_bytes()), Some(raw.to_vec()));
+    }
+    assert_eq!(encode(&[0xfb, 0xff]), "+/8=");
+
+    assert_eq!(decode(b"Zg="), None);
+    assert_eq!(decode(b"Z==="), None);
+    assert_eq!(decode(b"Zg==Zg=="), None);
+    assert_eq!(decode(b"Zm9v YmFy"), None);
+}
@@ -0,0 +1,159 @@
+//! Parsing of the HTTP Range request header (RFC 7233), byte ranges only
+
+use std::ascii::OwnedAsciiExt;
+
+use super::byteutils;
+
+
+/// What to do with a Range header
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Ranges {
+    /// Invalid or unsupported; send the whole body as usual
+    Ignore,
+    /// No range overlaps the body; send a 416 error
+    Unsatisfiable,
+    /// (first, last) byte positions, inclusive, sorted, and with
+    /// overlapping or adjacent ranges merged
+    Satisfiable(Vec<(u64, u64)>),
+}
+
+
+/// Parse a Range header value, ex: "bytes=0-499, -500", for a body of `len`
+/// bytes.
+///
+/// Open ranges ("500-") and suffix ranges ("-500") are clamped to the body.
+/// Ranges that start past the end are dropped, and the rest are sorted and
+/// merged, so no byte is sent twice.  If there are more than `max_ranges`
+/// ranges, the header is ignored.
+pub fn parse_range_header(value: &[u8], len: u64, max_ranges: usize)
+        -> Ranges {
+    let parts = byteutils::split_bytes_on(value, b'=', 1);
+    if parts.len() != 2 {
+        return Ranges::Ignore;
+    }
+    let unit = byteutils::strip(parts[0]).to_vec().into_ascii_lowercase();
+    if unit != b"bytes" {
+        return Ranges::Ignore;
+    }
+
+    let mut ret = Vec::new();
+    let mut nr_specs = 0;
+    for spec in parts[1].split(|c| *c == b',') {
+        let spec = byteutils::strip(spec);
+        if spec.is_empty() {
+            continue;
+        }
+        nr_specs += 1;
+        if nr_specs > max_ranges {
+            return Ranges::Ignore;
+        }
+
+        let positions = byteutils::split_bytes_on(spec, b'-', 1);
+        if positions.len() != 2 {
+            return Ranges::Ignore;
+        }
+        if positions[0].is_empty() {
+            // Suffix range: the last N bytes
+            let suffix_len = match byteutils::parse_u64(positions[1]) {
+                Some(n) => n,
+                None => return Ranges::Ignore,
+            };
+            if suffix_len > 0 && len > 0 {
+                let first = if suffix_len > len { 0 } else { len - suffix_len };
+                ret.push((first, len - 1));
+            }
+            continue;
+        }
+
+        let first = match byteutils::parse_u64(positions[0]) {
+            Some(n) => n,
+            None => return Ranges::Ignore,
+        };
+        let mut last = u64::max_value();
+        if !positions[1].is_empty() {
+            last = match byteutils::parse_u64(positions[1]) {
+                Some(n) => n,
+                None => return Ranges::Ignore,
+            };
+        }
+        if last < first {
+            return Ranges::Ignore;
+        }
+        if first < len {
+            if last >= len {
+                last = len - 1;
+            }
+            ret.push((first, last));
+        }
+    }
+
+    if nr_specs == 0 {
+        return Ranges::Ignore;
+    }
+    if ret.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+    return Ranges::Satisfiable(coalesce(ret));
+}
+
+
+// Sort the ranges, and merge the ones that overlap or touch
+fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort();
+    let mut ret: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for &(first, last) in ranges.iter() {
+        match ret.last_mut() {
+            Some(prev) => {
+                // prev.1 < len, so this can't overflow
+                if first <= prev.1 + 1 {
+                    if last > prev.1 {
+                        prev.1 = last;
+                    }
+                    continue;
+                }
+            },
+            None => {},
+        }
+        ret.push((first, last));
+    }
+    return ret;
+}
+
+
+#[test]
+fn test_parse_range_header() {
+    assert_eq!(parse_range_header(b"bytes=0-499", 1000, 10),
+        Ranges::Satisfiable(vec![(0, 499)]));
+    assert_eq!(parse_range_header(b"Bytes = 500- , -100,,0-0", 1000, 10),
+        Ranges::Satisfiable(vec![(0, 0), (500, 999)]));
+    assert_eq!(parse_range_header(b"bytes=-2000", 1000, 10),
+        Ranges::Satisfiable(vec![(0, 999)]));
+    assert_eq!(parse_range_header(b"bytes=990-2000", 1000, 10),
+        Ranges::Satisfiable(vec![(990, 999)]));
+    assert_eq!(parse_range_header(b"bytes=1000-,5-5", 1000, 10),
+        Ranges::Satisfiable(vec![(5, 5)]));
+
+    // Overlapping and adjacent ranges are merged
+    assert_eq!(parse_range_header(b"bytes=20-29,0-9,5-14,15-15,17-18",
+                1000, 10),
+        Ranges::Satisfiable(vec![(0, 15), (17, 18), (20, 29)]));
+    assert_eq!(parse_range_header(b"bytes=0-,0-,0-,-1000", 1000, 10),
+        Ranges::Satisfiable(vec![(0, 999)]));
+
+    assert_eq!(parse_range_header(b"bytes=1000-", 1000, 10),
+        Ranges::Unsatisfiable);
+    assert_eq!(parse_range_header(b"bytes=-0", 1000, 10),
+        Ranges::Unsatisfiable);
+    assert_eq!(parse_range_header(b"bytes=0-", 0, 10),
+        Ranges::Unsatisfiable);
+
+    assert_eq!(parse_range_header(b"items=0-1", 1000, 10), Ranges::Ignore);
+    assert_eq!(parse_range_header(b"bytes=5-1", 1000, 10), Ranges::Ignore);
+    assert_eq!(parse_range_header(b"bytes=a-1", 1000, 10), Ranges::Ignore);
+    assert_eq!(parse_range_header(b"bytes=1", 1000, 10), Ranges::Ignore);
+    assert_eq!(parse_range_header(b"bytes=", 1000, 10), Ranges::Ignore);
+    assert_eq!(parse_range_header(b"0-1", 1000, 10), Ranges::Ignore);
+    assert_eq!(parse_range_header(b"bytes=0-1,2-3,4-5", 1000, 2),
+        Ranges::Ignore);
+}
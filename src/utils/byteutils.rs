@@ -4,6 +4,10 @@
 
 /// Return position of needle in haystack.
 ///
+/// Uses Horspool's algorithm: when the haystack byte under the end of the
+/// needle doesn't line up, it skips ahead as far as that byte allows, so
+/// longer needles search faster.
+///
 /// # Panics
 /// Needle must be not empty.
 ///
@@ -11,6 +15,36 @@ pub fn memmem(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     if needle.is_empty() {
         panic!("memmem: empty needle");
     }
+    let n = needle.len();
+    if n == 1 {
+        return haystack.iter().position(|c| *c == needle[0]);
+    }
+    if haystack.len() < n {
+        return None;
+    }
+
+    // How far to skip, by the byte under the last position of the needle
+    let mut skip = [n; 256];
+    for i in 0..n - 1 {
+        skip[needle[i] as usize] = n - 1 - i;
+    }
+
+    let last = needle[n - 1];
+    let mut pos = 0;
+    while pos + n <= haystack.len() {
+        let c = haystack[pos + n - 1];
+        if c == last && &haystack[pos..pos + n - 1] == &needle[..n - 1] {
+            return Some(pos);
+        }
+        pos += skip[c as usize];
+    }
+    return None;
+}
+
+
+// The original memmem, to check and benchmark against
+#[cfg(test)]
+fn memmem_naive(haystack: &[u8], needle: &[u8]) -> Option<usize> {
     let mut idx = 0;
     for w in haystack.windows(needle.len()) {
         if w == needle {
@@ -102,6 +136,34 @@ pub fn percent_decode(input: &[u8]) -> Vec<u8> {
 }
 
 
+/// Encode every byte except unreserved URI characters (RFC 3986: letters,
+/// digits, and "-._~") as %XX.  The result is safe in a URL path segment,
+/// query value, or HTML attribute.
+pub fn percent_encode(input: &[u8]) -> String {
+    let mut ret = String::new();
+    for c in input.iter() {
+        match *c {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9'
+                | b'-' | b'.' | b'_' | b'~' => ret.push(*c as char),
+            _ => ret.push_str(&format!("%{:02X}", *c)),
+        }
+    }
+    return ret;
+}
+
+
+/// True if byte is allowed in an HTTP token (RFC 7230 tchar), e.g. a header
+/// name
+pub fn is_token_char(byte: u8) -> bool {
+    match byte {
+        b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.'
+            | b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => false,
+    }
+}
+
+
 /// Remove leading and trailing spaces (b' ') from input
 pub fn strip(input: &[u8]) -> &[u8] {
     return lstrip(rstrip(input));
@@ -151,6 +213,23 @@ pub fn parse_u64(input: &[u8]) -> Option<u64> {
 }
 
 
+/// Parse a hexadecimal number from ascii text.  Returns None on overflow.
+pub fn parse_hex_u64(input: &[u8]) -> Option<u64> {
+    if input.is_empty() || input.len() > 16 {
+        return None;
+    }
+    let mut ret: u64 = 0;
+    for c in input.iter() {
+        let c_val = to_hexval(*c);
+        match c_val {
+            Some(n) => ret = (ret << 4) + n as u64,
+            None => return None
+        }
+    }
+    return Some(ret);
+}
+
+
 #[test]
 fn test_memmem() {
     let a = b"hello world dude";
@@ -166,6 +245,31 @@ fn test_memmem() {
     assert!(res.unwrap() == 0);
 }
 
+#[test]
+fn test_memmem_matches_naive() {
+    // Small alphabet, so there are lots of partial matches
+    let mut haystack = Vec::new();
+    let mut x: u32 = 1;
+    for _ in 0..2000 {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        haystack.push(b"ab\r\n"[(x >> 16) as usize % 4]);
+    }
+    for start in 0..40 {
+        for len in 1..9 {
+            let needle = &haystack[start * 50..start * 50 + len];
+            assert_eq!(memmem(&haystack, needle),
+                memmem_naive(&haystack, needle));
+        }
+    }
+    let needles = [&b"\r\n\r\n"[..], &b"aaaaaaaaaa"[..], &b"x"[..],
+        &b"ba\r"[..]];
+    for needle in needles.iter() {
+        assert_eq!(memmem(&haystack, needle), memmem_naive(&haystack, needle));
+    }
+    assert_eq!(memmem(b"ab", b"abc"), None);
+    assert_eq!(memmem(b"abc", b"abc"), Some(0));
+}
+
 #[test]
 fn test_split_bytes() {
     let a = b"hello world dude";
@@ -253,3 +357,67 @@ fn test_decval() {
     assert!(parse_u64(b"-123").is_none());
     assert!(parse_u64(b"bcd").is_none());
 }
+
+#[test]
+fn test_hexval() {
+    assert_eq!(parse_hex_u64(b"0").unwrap(), 0);
+    assert_eq!(parse_hex_u64(b"1a").unwrap(), 26);
+    assert_eq!(parse_hex_u64(b"FFff").unwrap(), 65535);
+    assert_eq!(parse_hex_u64(b"ffffffffffffffff").unwrap(), 0xffffffffffffffff);
+
+    assert!(parse_hex_u64(b"10000000000000000").is_none());
+    assert!(parse_hex_u64(b"").is_none());
+    assert!(parse_hex_u64(b" 1a").is_none());
+    assert!(parse_hex_u64(b"1g").is_none());
+    assert!(parse_hex_u64(b"-1").is_none());
+}
+
+#[test]
+fn test_percent_encode() {
+    assert_eq!(percent_encode(b"a-Z_0.~"), "a-Z_0.~");
+    assert_eq!(percent_encode(b"a b/\"<%\xff"), "a%20b%2F%22%3C%25%FF");
+    assert_eq!(percent_decode(percent_encode(b"x y&z").as_bytes()), b"x y&z");
+}
+
+
+
+// A header block, as memmem sees it when looking for the end of headers
+#[cfg(test)]
+fn bench_headers() -> Vec<u8> {
+    let mut ret = b"GET /some/path?query=1 HTTP/1.1\r\n".to_vec();
+    for i in 0..100 {
+        ret.push_all(format!("X-Header-{}: some value here\r\n", i).as_bytes());
+    }
+    ret.push_all(b"\r\n");
+    return ret;
+}
+
+#[bench]
+fn bench_memmem_headers(b: &mut ::test::Bencher) {
+    let headers = bench_headers();
+    b.bytes = headers.len() as u64;
+    b.iter(|| memmem(::test::black_box(&headers), b"\r\n\r\n"));
+}
+
+#[bench]
+fn bench_memmem_naive_headers(b: &mut ::test::Bencher) {
+    let headers = bench_headers();
+    b.bytes = headers.len() as u64;
+    b.iter(|| memmem_naive(::test::black_box(&headers), b"\r\n\r\n"));
+}
+
+#[bench]
+fn bench_memmem_boundary(b: &mut ::test::Bencher) {
+    let body = vec![b'x'; 100_000];
+    b.bytes = body.len() as u64;
+    b.iter(|| memmem(::test::black_box(&body),
+                b"\r\n--------------------------boundary1234"));
+}
+
+#[bench]
+fn bench_memmem_naive_boundary(b: &mut ::test::Bencher) {
+    let body = vec![b'x'; 100_000];
+    b.bytes = body.len() as u64;
+    b.iter(|| memmem_naive(::test::black_box(&body),
+                b"\r\n--------------------------boundary1234"));
+}
@@ -0,0 +1,34 @@
+//! A coarse monotonic clock, for timeouts.  std has no clock yet, so a
+//! background thread advances a counter every 100 ms.  Under heavy load the
+//! thread may tick late, which only makes timeouts a bit longer.
+
+use std::sync::{Once, ONCE_INIT};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::thread;
+
+static TICK_MS: u32 = 100;
+static TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+static START: Once = ONCE_INIT;
+
+
+/// Milliseconds since the clock started (the first call), in steps of 100
+pub fn now_ms() -> u64 {
+    START.call_once(|| {
+        thread::spawn(|| {
+            loop {
+                thread::sleep_ms(TICK_MS);
+                TICKS.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+    });
+    return TICKS.load(Ordering::SeqCst) as u64 * TICK_MS as u64;
+}
+
+
+#[test]
+fn test_clock() {
+    let start = now_ms();
+    thread::sleep_ms(350);
+    let elapsed = now_ms() - start;
+    assert!(elapsed >= 200 && elapsed <= 1000);
+}
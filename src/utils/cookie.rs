@@ -0,0 +1,295 @@
+//! Cookies (RFC 6265): parsing the request Cookie header, and building
+//! Set-Cookie response headers
+
+use super::byteutils;
+use super::httpdate;
+
+
+/// The cookies sent by the client, in the order received.
+///
+/// Browsers send more specific cookies (longer Path) first, so when a name is
+/// repeated, `get` returns the most specific one.
+pub struct CookieJar {
+    pairs: Vec<(String, String)>,
+}
+
+impl CookieJar {
+    /// Parse a Cookie header value, ex: `a=1; b="two"`.
+    ///
+    /// Surrounding double quotes are removed from values.  Values are not
+    /// otherwise decoded.  Items without '=' or with an empty name are
+    /// skipped.
+    pub fn parse(input: &[u8]) -> CookieJar {
+        let mut pairs = Vec::new();
+        for item in input.split(|c| *c == b';') {
+            let parts = byteutils::split_bytes_on(item, b'=', 1);
+            if parts.len() != 2 {
+                continue;
+            }
+            let name = byteutils::strip(parts[0]);
+            if name.is_empty() {
+                continue;
+            }
+            let mut value = byteutils::strip(parts[1]);
+            if value.len() >= 2 && value[0] == b'"'
+                    && value[value.len() - 1] == b'"' {
+                value = &value[1 .. value.len() - 1];
+            }
+            pairs.push((String::from_utf8_lossy(name).into_owned(),
+                        String::from_utf8_lossy(value).into_owned()));
+        }
+        return CookieJar { pairs: pairs };
+    }
+
+    /// First value for name, or None
+    pub fn get(&self, name: &str) -> Option<&str> {
+        for &(ref k, ref v) in self.pairs.iter() {
+            if k == name {
+                return Some(v);
+            }
+        }
+        return None;
+    }
+
+    /// All values for name, in order
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        let mut ret = Vec::new();
+        for &(ref k, ref v) in self.pairs.iter() {
+            if k == name {
+                ret.push(&v[..]);
+            }
+        }
+        return ret;
+    }
+
+    /// All (name, value) pairs, in order
+    pub fn get_pairs(&self) -> &[(String, String)] {
+        return &self.pairs;
+    }
+}
+
+
+/// Why a cookie can't be sent as given
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum CookieError {
+    /// The name isn't an HTTP token
+    InvalidName,
+    /// The value has characters a cookie value can't
+    InvalidValue,
+    /// A Path or Domain has ';' or control characters
+    InvalidAttribute,
+}
+
+
+/// The SameSite cookie attribute
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+
+/// A cookie to send to the client, i.e. one Set-Cookie header.
+///
+/// ```ignore
+/// let mut cookie = Cookie::new("session", "abc123").unwrap();
+/// cookie.set_path("/").unwrap();
+/// cookie.set_http_only(true);
+/// response.add_cookie(cookie);
+/// ```
+///
+/// To delete a cookie, send it with the same name, path, and domain, and a
+/// max age of 0.
+#[derive(Clone)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<i64>,
+    expires: Option<u64>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// Create a cookie with no attributes, which the browser keeps until it
+    /// is closed.
+    ///
+    /// The name must be an HTTP token, and the value may only contain
+    /// printable ASCII other than space, '"', ',', ';', and '\\'.  Encode
+    /// anything else (e.g. percent encoding or base64).
+    ///
+    /// Returns an error if the name or value is invalid.
+    pub fn new(name: &str, value: &str) -> Result<Cookie, CookieError> {
+        if name.is_empty() || !name.bytes().all(byteutils::is_token_char) {
+            return Err(CookieError::InvalidName);
+        }
+        if !value.bytes().all(is_cookie_octet) {
+            return Err(CookieError::InvalidValue);
+        }
+        return Ok(Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        });
+    }
+
+    /// The URL path prefix the cookie is sent for.  Returns an error (and
+    /// leaves the cookie alone) if the path contains ';' or control
+    /// characters.
+    pub fn set_path(&mut self, path: &str) -> Result<(), CookieError> {
+        try!(check_attribute_value(path));
+        self.path = Some(path.to_string());
+        return Ok(());
+    }
+
+    /// The host (and its subdomains) the cookie is sent to.  Without this,
+    /// only the exact host that set it.  Returns an error (and leaves the
+    /// cookie alone) if the domain contains ';' or control characters.
+    pub fn set_domain(&mut self, domain: &str) -> Result<(), CookieError> {
+        try!(check_attribute_value(domain));
+        self.domain = Some(domain.to_string());
+        return Ok(());
+    }
+
+    /// Seconds until the cookie expires.  0 or less expires it now.
+    pub fn set_max_age(&mut self, secs: i64) {
+        self.max_age = Some(secs);
+    }
+
+    /// Expiration time, in seconds since the Unix epoch.  Max-Age takes
+    /// precedence in browsers that understand both.
+    pub fn set_expires(&mut self, unix_secs: u64) {
+        self.expires = Some(unix_secs);
+    }
+
+    /// Only send the cookie over HTTPS
+    pub fn set_secure(&mut self, secure: bool) {
+        self.secure = secure;
+    }
+
+    /// Hide the cookie from JavaScript
+    pub fn set_http_only(&mut self, http_only: bool) {
+        self.http_only = http_only;
+    }
+
+    /// Whether the cookie is sent with cross-site requests.  Note that
+    /// browsers require Secure for SameSite::None.
+    pub fn set_same_site(&mut self, same_site: SameSite) {
+        self.same_site = Some(same_site);
+    }
+
+    /// The Set-Cookie header value, ex: "a=1; Path=/; HttpOnly"
+    pub fn to_header_value(&self) -> String {
+        let mut ret = format!("{}={}", self.name, self.value);
+        match self.path {
+            Some(ref path) => ret.push_str(&format!("; Path={}", path)),
+            None => {},
+        }
+        match self.domain {
+            Some(ref domain) => ret.push_str(&format!("; Domain={}", domain)),
+            None => {},
+        }
+        match self.max_age {
+            Some(secs) => ret.push_str(&format!("; Max-Age={}", secs)),
+            None => {},
+        }
+        match self.expires {
+            Some(secs) => ret.push_str(&format!("; Expires={}",
+                        httpdate::format_http_date(secs))),
+            None => {},
+        }
+        if self.secure {
+            ret.push_str("; Secure");
+        }
+        if self.http_only {
+            ret.push_str("; HttpOnly");
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => ret.push_str("; SameSite=Strict"),
+            Some(SameSite::Lax) => ret.push_str("; SameSite=Lax"),
+            Some(SameSite::None) => ret.push_str("; SameSite=None"),
+            None => {},
+        }
+        return ret;
+    }
+}
+
+
+// RFC 6265 cookie-octet
+fn is_cookie_octet(c: u8) -> bool {
+    match c {
+        0x21 | 0x23...0x2b | 0x2d...0x3a | 0x3c...0x5b | 0x5d...0x7e => true,
+        _ => false,
+    }
+}
+
+
+fn check_attribute_value(value: &str) -> Result<(), CookieError> {
+    for c in value.bytes() {
+        if c < 0x20 || c == 0x7f || c == b';' {
+            return Err(CookieError::InvalidAttribute);
+        }
+    }
+    return Ok(());
+}
+
+
+#[test]
+fn test_parse() {
+    let jar = CookieJar::parse(b"a=1; b=\"two\";c=x=y ; junk; =bad; a=3;;d=");
+    assert_eq!(jar.get_pairs().len(), 5);
+    assert_eq!(jar.get("a"), Some("1"));
+    assert_eq!(jar.get_all("a"), vec!["1", "3"]);
+    assert_eq!(jar.get("b"), Some("two"));
+    assert_eq!(jar.get("c"), Some("x=y"));
+    assert_eq!(jar.get("d"), Some(""));
+    assert_eq!(jar.get("junk"), None);
+
+    assert!(CookieJar::parse(b"").get_pairs().is_empty());
+}
+
+#[test]
+fn test_set_cookie() {
+    let c = Cookie::new("id", "a3fWa").unwrap();
+    assert_eq!(c.to_header_value(), "id=a3fWa");
+
+    let mut c = Cookie::new("id", "a3fWa").unwrap();
+    c.set_path("/docs").unwrap();
+    c.set_domain("example.com").unwrap();
+    c.set_max_age(3600);
+    c.set_expires(784111777);
+    c.set_secure(true);
+    c.set_http_only(true);
+    c.set_same_site(SameSite::Lax);
+    assert_eq!(c.to_header_value(), "id=a3fWa; Path=/docs; \
+            Domain=example.com; Max-Age=3600; \
+            Expires=Sun, 06 Nov 1994 08:49:37 GMT; Secure; HttpOnly; \
+            SameSite=Lax");
+}
+
+#[test]
+fn test_set_cookie_bad_value() {
+    assert_eq!(Cookie::new("id", "a;b").err(), Some(CookieError::InvalidValue));
+    assert_eq!(Cookie::new("i d", "1").err(), Some(CookieError::InvalidName));
+    assert_eq!(Cookie::new("", "1").err(), Some(CookieError::InvalidName));
+}
+
+#[test]
+fn test_set_cookie_bad_path() {
+    let mut c = Cookie::new("id", "1").unwrap();
+    assert_eq!(c.set_path("/\r\nX-Evil: 1"),
+        Err(CookieError::InvalidAttribute));
+    assert_eq!(c.set_domain("a;b"), Err(CookieError::InvalidAttribute));
+    assert_eq!(c.to_header_value(), "id=1");
+}
@@ -0,0 +1,769 @@
+//! DEFLATE compression (RFC 1951), with gzip (RFC 1952) and zlib (RFC 1950)
+//! framing.
+//!
+//! The compressor uses LZ77 with hash chains and one step of lazy matching,
+//! and picks the smallest of a stored, fixed Huffman, or dynamic Huffman
+//! encoding for each block.  It favors speed over the last few percent of
+//! compression.
+
+use std::cmp;
+
+pub static MIN_MATCH: usize = 3;
+pub static MAX_MATCH: usize = 258;
+pub static WINDOW_SIZE: usize = 32768;
+
+// Base lengths and extra bits for length codes 257-285
+pub static LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17,
+    19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+pub static LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2,
+    2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+// Base distances and extra bits for distance codes 0-29
+pub static DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49,
+    65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577];
+pub static DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5,
+    6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// The order code length code lengths are sent in, in a dynamic block header
+pub static CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5,
+    11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+static HASH_BITS: usize = 15;
+// How many earlier positions to try for each match
+static MAX_CHAIN: usize = 128;
+// Stop searching once a match is this long
+static NICE_MATCH: usize = 128;
+// Don't look for a better match at the next byte if this one is this long
+static LAZY_LIMIT: usize = 32;
+// Symbols per block.  Each block gets its own Huffman codes.
+static BLOCK_SYMBOLS: usize = 16384;
+static MAX_STORED: usize = 65535;
+
+
+/// Compress `data` into a raw DEFLATE stream
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    let mut finder = MatchFinder::new();
+    let mut symbols = Vec::with_capacity(BLOCK_SYMBOLS);
+    let mut block_start = 0;
+    let mut pos = 0;
+    // A match for pos that was already searched for, by lazy matching
+    let mut cached = None;
+
+    while pos < data.len() {
+        let (len, dist) = match cached.take() {
+            Some(m) => m,
+            None => finder.find(data, pos),
+        };
+        if len < MIN_MATCH {
+            symbols.push(Symbol::Literal(data[pos]));
+            pos += 1;
+        } else {
+            // If the next byte starts a longer match, use that instead
+            let mut searched_next = false;
+            if len < LAZY_LIMIT && pos + 1 < data.len() {
+                let next = finder.find(data, pos + 1);
+                searched_next = true;
+                if next.0 > len {
+                    symbols.push(Symbol::Literal(data[pos]));
+                    pos += 1;
+                    cached = Some(next);
+                    continue;
+                }
+            }
+            symbols.push(Symbol::Match(len as u16, dist as u16));
+            let first = if searched_next { pos + 2 } else { pos + 1 };
+            for p in first .. pos + len {
+                finder.insert(data, p);
+            }
+            pos += len;
+        }
+
+        if symbols.len() >= BLOCK_SYMBOLS && cached.is_none() {
+            let is_final = pos == data.len();
+            write_block(&mut writer, &symbols, &data[block_start..pos],
+                    is_final);
+            symbols.clear();
+            block_start = pos;
+            if is_final {
+                return writer.finish();
+            }
+        }
+    }
+    write_block(&mut writer, &symbols, &data[block_start..], true);
+    return writer.finish();
+}
+
+
+/// Compress `data` into the gzip format (Content-Encoding: gzip)
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    // No file name or timestamp; "unknown" OS
+    let mut ret = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    ret.push_all(&deflate(data));
+    ret.push_all(&u32_le(crc32(data)));
+    ret.push_all(&u32_le(data.len() as u32));
+    return ret;
+}
+
+
+/// Compress `data` into the zlib format (Content-Encoding: deflate)
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    // 32K window, default compression level, no dictionary
+    let mut ret = vec![0x78, 0x9c];
+    ret.push_all(&deflate(data));
+    let checksum = adler32(data);
+    ret.push_all(&[(checksum >> 24) as u8, (checksum >> 16) as u8,
+        (checksum >> 8) as u8, checksum as u8]);
+    return ret;
+}
+
+
+// The CRC-32 of each byte value (polynomial 0xedb88320, reflected)
+static CRC32_TABLE: [u32; 256] = [
+    0x00000000, 0x77073096, 0xee0e612c, 0x990951ba, 0x076dc419, 0x706af48f,
+    0xe963a535, 0x9e6495a3, 0x0edb8832, 0x79dcb8a4, 0xe0d5e91e, 0x97d2d988,
+    0x09b64c2b, 0x7eb17cbd, 0xe7b82d07, 0x90bf1d91, 0x1db71064, 0x6ab020f2,
+    0xf3b97148, 0x84be41de, 0x1adad47d, 0x6ddde4eb, 0xf4d4b551, 0x83d385c7,
+    0x136c9856, 0x646ba8c0, 0xfd62f97a, 0x8a65c9ec, 0x14015c4f, 0x63066cd9,
+    0xfa0f3d63, 0x8d080df5, 0x3b6e20c8, 0x4c69105e, 0xd56041e4, 0xa2677172,
+    0x3c03e4d1, 0x4b04d447, 0xd20d85fd, 0xa50ab56b, 0x35b5a8fa, 0x42b2986c,
+    0xdbbbc9d6, 0xacbcf940, 0x32d86ce3, 0x45df5c75, 0xdcd60dcf, 0xabd13d59,
+    0x26d930ac, 0x51de003a, 0xc8d75180, 0xbfd06116, 0x21b4f4b5, 0x56b3c423,
+    0xcfba9599, 0xb8bda50f, 0x2802b89e, 0x5f058808, 0xc60cd9b2, 0xb10be924,
+    0x2f6f7c87, 0x58684c11, 0xc1611dab, 0xb6662d3d, 0x76dc4190, 0x01db7106,
+    0x98d220bc, 0xefd5102a, 0x71b18589, 0x06b6b51f, 0x9fbfe4a5, 0xe8b8d433,
+    0x7807c9a2, 0x0f00f934, 0x9609a88e, 0xe10e9818, 0x7f6a0dbb, 0x086d3d2d,
+    0x91646c97, 0xe6635c01, 0x6b6b51f4, 0x1c6c6162, 0x856530d8, 0xf262004e,
+    0x6c0695ed, 0x1b01a57b, 0x8208f4c1, 0xf50fc457, 0x65b0d9c6, 0x12b7e950,
+    0x8bbeb8ea, 0xfcb9887c, 0x62dd1ddf, 0x15da2d49, 0x8cd37cf3, 0xfbd44c65,
+    0x4db26158, 0x3ab551ce, 0xa3bc0074, 0xd4bb30e2, 0x4adfa541, 0x3dd895d7,
+    0xa4d1c46d, 0xd3d6f4fb, 0x4369e96a, 0x346ed9fc, 0xad678846, 0xda60b8d0,
+    0x44042d73, 0x33031de5, 0xaa0a4c5f, 0xdd0d7cc9, 0x5005713c, 0x270241aa,
+    0xbe0b1010, 0xc90c2086, 0x5768b525, 0x206f85b3, 0xb966d409, 0xce61e49f,
+    0x5edef90e, 0x29d9c998, 0xb0d09822, 0xc7d7a8b4, 0x59b33d17, 0x2eb40d81,
+    0xb7bd5c3b, 0xc0ba6cad, 0xedb88320, 0x9abfb3b6, 0x03b6e20c, 0x74b1d29a,
+    0xead54739, 0x9dd277af, 0x04db2615, 0x73dc1683, 0xe3630b12, 0x94643b84,
+    0x0d6d6a3e, 0x7a6a5aa8, 0xe40ecf0b, 0x9309ff9d, 0x0a00ae27, 0x7d079eb1,
+    0xf00f9344, 0x8708a3d2, 0x1e01f268, 0x6906c2fe, 0xf762575d, 0x806567cb,
+    0x196c3671, 0x6e6b06e7, 0xfed41b76, 0x89d32be0, 0x10da7a5a, 0x67dd4acc,
+    0xf9b9df6f, 0x8ebeeff9, 0x17b7be43, 0x60b08ed5, 0xd6d6a3e8, 0xa1d1937e,
+    0x38d8c2c4, 0x4fdff252, 0xd1bb67f1, 0xa6bc5767, 0x3fb506dd, 0x48b2364b,
+    0xd80d2bda, 0xaf0a1b4c, 0x36034af6, 0x41047a60, 0xdf60efc3, 0xa867df55,
+    0x316e8eef, 0x4669be79, 0xcb61b38c, 0xbc66831a, 0x256fd2a0, 0x5268e236,
+    0xcc0c7795, 0xbb0b4703, 0x220216b9, 0x5505262f, 0xc5ba3bbe, 0xb2bd0b28,
+    0x2bb45a92, 0x5cb36a04, 0xc2d7ffa7, 0xb5d0cf31, 0x2cd99e8b, 0x5bdeae1d,
+    0x9b64c2b0, 0xec63f226, 0x756aa39c, 0x026d930a, 0x9c0906a9, 0xeb0e363f,
+    0x72076785, 0x05005713, 0x95bf4a82, 0xe2b87a14, 0x7bb12bae, 0x0cb61b38,
+    0x92d28e9b, 0xe5d5be0d, 0x7cdcefb7, 0x0bdbdf21, 0x86d3d2d4, 0xf1d4e242,
+    0x68ddb3f8, 0x1fda836e, 0x81be16cd, 0xf6b9265b, 0x6fb077e1, 0x18b74777,
+    0x88085ae6, 0xff0f6a70, 0x66063bca, 0x11010b5c, 0x8f659eff, 0xf862ae69,
+    0x616bffd3, 0x166ccf45, 0xa00ae278, 0xd70dd2ee, 0x4e048354, 0x3903b3c2,
+    0xa7672661, 0xd06016f7, 0x4969474d, 0x3e6e77db, 0xaed16a4a, 0xd9d65adc,
+    0x40df0b66, 0x37d83bf0, 0xa9bcae53, 0xdebb9ec5, 0x47b2cf7f, 0x30b5ffe9,
+    0xbdbdf21c, 0xcabac28a, 0x53b39330, 0x24b4a3a6, 0xbad03605, 0xcdd70693,
+    0x54de5729, 0x23d967bf, 0xb3667a2e, 0xc4614ab8, 0x5d681b02, 0x2a6f2b94,
+    0xb40bbe37, 0xc30c8ea1, 0x5a05df1b, 0x2d02ef8d];
+
+/// The CRC-32 used by gzip (and zip, PNG, Ethernet)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffff;
+    for b in data.iter() {
+        crc = CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    return crc ^ 0xffffffff;
+}
+
+
+/// The Adler-32 checksum used by zlib
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    // 5552 is the most bytes that can be summed before b could overflow
+    for chunk in data.chunks(5552) {
+        for c in chunk.iter() {
+            a += *c as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    return (b << 16) | a;
+}
+
+
+fn u32_le(n: u32) -> [u8; 4] {
+    return [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8];
+}
+
+
+// Output of the LZ77 stage: (length, distance) for matches
+enum Symbol {
+    Literal(u8),
+    Match(u16, u16),
+}
+
+
+// Finds earlier occurrences of the bytes at a position, with a hash table of
+// chains of positions that start with the same 3 bytes.  Positions are
+// stored plus one, so 0 means "none".
+struct MatchFinder {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl MatchFinder {
+    fn new() -> MatchFinder {
+        return MatchFinder {
+            head: vec![0; 1 << HASH_BITS],
+            prev: vec![0; WINDOW_SIZE],
+        };
+    }
+
+    fn hash(data: &[u8], pos: usize) -> usize {
+        let h = ((data[pos] as usize) << 10) ^ ((data[pos + 1] as usize) << 5)
+            ^ (data[pos + 2] as usize);
+        return h & ((1 << HASH_BITS) - 1);
+    }
+
+    // Add a position to its chain
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_MATCH > data.len() {
+            return;
+        }
+        let h = MatchFinder::hash(data, pos);
+        self.prev[pos % WINDOW_SIZE] = self.head[h];
+        self.head[h] = pos + 1;
+    }
+
+    // Add a position to its chain, and return the longest earlier match
+    // for it as (length, distance).  Length is 0 if there's no match.
+    fn find(&mut self, data: &[u8], pos: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > data.len() {
+            return (0, 0);
+        }
+        let h = MatchFinder::hash(data, pos);
+        let mut candidate = self.head[h];
+        self.prev[pos % WINDOW_SIZE] = candidate;
+        self.head[h] = pos + 1;
+
+        let max_len = cmp::min(MAX_MATCH, data.len() - pos);
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        let mut chain = MAX_CHAIN;
+        while candidate != 0 && chain > 0 {
+            let start = candidate - 1;
+            let dist = pos - start;
+            if dist > WINDOW_SIZE {
+                break;
+            }
+            // Check the byte that would make this match longer first
+            if data[start + best_len] == data[pos + best_len] {
+                let mut len = 0;
+                while len < max_len && data[start + len] == data[pos + len] {
+                    len += 1;
+                }
+                if len > best_len {
+                    best_len = len;
+                    best_dist = dist;
+                    if len >= cmp::min(max_len, NICE_MATCH) {
+                        break;
+                    }
+                }
+            }
+            // The chain entry may have been reused by a newer position, in
+            // which case it no longer leads further back
+            let next = self.prev[start % WINDOW_SIZE];
+            if next == 0 || next - 1 >= start {
+                break;
+            }
+            candidate = next;
+            chain -= 1;
+        }
+        return (best_len, best_dist);
+    }
+}
+
+
+// Writes bits least significant first, as DEFLATE requires
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    nr_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        return BitWriter { out: Vec::new(), bits: 0, nr_bits: 0 };
+    }
+
+    fn write_bits(&mut self, value: u32, nr_bits: u32) {
+        self.bits |= (value as u64) << self.nr_bits;
+        self.nr_bits += nr_bits;
+        while self.nr_bits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nr_bits -= 8;
+        }
+    }
+
+    // Pad to a byte boundary with zero bits
+    fn align(&mut self) {
+        if self.nr_bits > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.nr_bits = 0;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.align();
+        return self.out;
+    }
+}
+
+
+/// The length code (0-28, i.e. symbol 257-285) for a match length
+pub fn length_code(len: usize) -> usize {
+    return LENGTH_BASE.iter().rposition(|b| *b as usize <= len).unwrap();
+}
+
+
+/// The distance code (0-29) for a match distance
+pub fn dist_code(dist: usize) -> usize {
+    return DIST_BASE.iter().rposition(|b| *b as usize <= dist).unwrap();
+}
+
+
+/// The code lengths of the fixed Huffman codes: (literal/length, distance)
+pub fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut lit_lengths = vec![8; 288];
+    for i in 144..256 {
+        lit_lengths[i] = 9;
+    }
+    for i in 256..280 {
+        lit_lengths[i] = 7;
+    }
+    return (lit_lengths, vec![5; 30]);
+}
+
+
+// Write one block, choosing whichever encoding is smallest
+fn write_block(writer: &mut BitWriter, symbols: &[Symbol], input: &[u8],
+        is_final: bool) {
+    let mut lit_freqs = vec![0u32; 286];
+    let mut dist_freqs = vec![0u32; 30];
+    // Extra bits cost the same in every Huffman encoding
+    let mut extra_bits = 0;
+    for symbol in symbols.iter() {
+        match *symbol {
+            Symbol::Literal(c) => lit_freqs[c as usize] += 1,
+            Symbol::Match(len, dist) => {
+                let lc = length_code(len as usize);
+                let dc = dist_code(dist as usize);
+                lit_freqs[257 + lc] += 1;
+                dist_freqs[dc] += 1;
+                extra_bits += LENGTH_EXTRA[lc] as u64 + DIST_EXTRA[dc] as u64;
+            },
+        }
+    }
+    lit_freqs[256] += 1;
+
+    let (fixed_lit, fixed_dist) = fixed_lengths();
+    let fixed_cost = 3 + extra_bits + data_cost(&lit_freqs, &fixed_lit)
+        + data_cost(&dist_freqs, &fixed_dist);
+
+    let lit_lengths = huffman_lengths(&lit_freqs, 15);
+    let dist_lengths = huffman_lengths(&dist_freqs, 15);
+    let header = DynamicHeader::new(&lit_lengths, &dist_lengths);
+    let dynamic_cost = 3 + header.cost() + extra_bits
+        + data_cost(&lit_freqs, &lit_lengths)
+        + data_cost(&dist_freqs, &dist_lengths);
+
+    let nr_chunks = cmp::max(1, (input.len() + MAX_STORED - 1) / MAX_STORED);
+    let stored_cost = (input.len() as u64 + 5 * nr_chunks as u64) * 8 + 7;
+
+    if stored_cost <= fixed_cost && stored_cost <= dynamic_cost {
+        write_stored(writer, input, is_final);
+    } else if fixed_cost <= dynamic_cost {
+        writer.write_bits(is_final as u32, 1);
+        writer.write_bits(1, 2);
+        write_symbols(writer, symbols, &fixed_lit, &fixed_dist);
+    } else {
+        writer.write_bits(is_final as u32, 1);
+        writer.write_bits(2, 2);
+        header.write(writer);
+        write_symbols(writer, symbols, &lit_lengths, &dist_lengths);
+    }
+}
+
+
+// Bits for the symbols with the given frequencies and code lengths
+fn data_cost(freqs: &[u32], lengths: &[u8]) -> u64 {
+    let mut ret = 0;
+    for (f, l) in freqs.iter().zip(lengths.iter()) {
+        ret += *f as u64 * *l as u64;
+    }
+    return ret;
+}
+
+
+// Uncompressed blocks, of up to 65535 bytes each
+fn write_stored(writer: &mut BitWriter, input: &[u8], is_final: bool) {
+    let mut chunks: Vec<&[u8]> = input.chunks(MAX_STORED).collect();
+    if chunks.is_empty() {
+        chunks.push(input);
+    }
+    let nr_chunks = chunks.len();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let last = is_final && i + 1 == nr_chunks;
+        writer.write_bits(last as u32, 1);
+        writer.write_bits(0, 2);
+        writer.align();
+        let len = chunk.len() as u32;
+        writer.write_bits(len & 0xffff, 16);
+        writer.write_bits(!len & 0xffff, 16);
+        writer.out.push_all(chunk);
+    }
+}
+
+
+fn write_symbols(writer: &mut BitWriter, symbols: &[Symbol],
+        lit_lengths: &[u8], dist_lengths: &[u8]) {
+    let lit_codes = huffman_codes(lit_lengths);
+    let dist_codes = huffman_codes(dist_lengths);
+    for symbol in symbols.iter() {
+        match *symbol {
+            Symbol::Literal(c) => {
+                let c = c as usize;
+                writer.write_bits(lit_codes[c] as u32, lit_lengths[c] as u32);
+            },
+            Symbol::Match(len, dist) => {
+                let len = len as usize;
+                let dist = dist as usize;
+                let lc = length_code(len);
+                let sym = 257 + lc;
+                writer.write_bits(lit_codes[sym] as u32,
+                        lit_lengths[sym] as u32);
+                writer.write_bits((len - LENGTH_BASE[lc] as usize) as u32,
+                        LENGTH_EXTRA[lc] as u32);
+                let dc = dist_code(dist);
+                writer.write_bits(dist_codes[dc] as u32,
+                        dist_lengths[dc] as u32);
+                writer.write_bits((dist - DIST_BASE[dc] as usize) as u32,
+                        DIST_EXTRA[dc] as u32);
+            },
+        }
+    }
+    writer.write_bits(lit_codes[256] as u32, lit_lengths[256] as u32);
+}
+
+
+// The header of a dynamic block: the code lengths for the literal/length
+// and distance codes, run length encoded, and themselves Huffman coded.
+struct DynamicHeader {
+    nr_lit: usize,
+    nr_dist: usize,
+    nr_cl: usize,
+    // (code length symbol 0-18, extra bits value)
+    runs: Vec<(u8, u8)>,
+    cl_lengths: Vec<u8>,
+}
+
+impl DynamicHeader {
+    fn new(lit_lengths: &[u8], dist_lengths: &[u8]) -> DynamicHeader {
+        let mut nr_lit = 286;
+        while nr_lit > 257 && lit_lengths[nr_lit - 1] == 0 {
+            nr_lit -= 1;
+        }
+        let mut nr_dist = 30;
+        while nr_dist > 1 && dist_lengths[nr_dist - 1] == 0 {
+            nr_dist -= 1;
+        }
+        // Runs can cross from one list to the other
+        let mut all_lengths = lit_lengths[..nr_lit].to_vec();
+        all_lengths.push_all(&dist_lengths[..nr_dist]);
+        let runs = run_length_encode(&all_lengths);
+
+        let mut cl_freqs = vec![0u32; 19];
+        for &(sym, _) in runs.iter() {
+            cl_freqs[sym as usize] += 1;
+        }
+        let cl_lengths = huffman_lengths(&cl_freqs, 7);
+        let mut nr_cl = 19;
+        while nr_cl > 4 && cl_lengths[CODE_LENGTH_ORDER[nr_cl - 1]] == 0 {
+            nr_cl -= 1;
+        }
+        return DynamicHeader {
+            nr_lit: nr_lit,
+            nr_dist: nr_dist,
+            nr_cl: nr_cl,
+            runs: runs,
+            cl_lengths: cl_lengths,
+        };
+    }
+
+    // Size in bits
+    fn cost(&self) -> u64 {
+        let mut ret = 14 + 3 * self.nr_cl as u64;
+        for &(sym, _) in self.runs.iter() {
+            ret += self.cl_lengths[sym as usize] as u64
+                + run_extra_bits(sym) as u64;
+        }
+        return ret;
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        writer.write_bits((self.nr_lit - 257) as u32, 5);
+        writer.write_bits((self.nr_dist - 1) as u32, 5);
+        writer.write_bits((self.nr_cl - 4) as u32, 4);
+        for i in 0..self.nr_cl {
+            writer.write_bits(self.cl_lengths[CODE_LENGTH_ORDER[i]] as u32, 3);
+        }
+        let cl_codes = huffman_codes(&self.cl_lengths);
+        for &(sym, extra) in self.runs.iter() {
+            let sym = sym as usize;
+            writer.write_bits(cl_codes[sym] as u32,
+                    self.cl_lengths[sym] as u32);
+            writer.write_bits(extra as u32, run_extra_bits(sym as u8));
+        }
+    }
+}
+
+
+// Extra bits after code length symbols 16 (repeat the previous length 3-6
+// times), 17 (3-10 zeros), and 18 (11-138 zeros)
+fn run_extra_bits(sym: u8) -> u32 {
+    return match sym {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    };
+}
+
+
+fn run_length_encode(lengths: &[u8]) -> Vec<(u8, u8)> {
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+        let len = lengths[i];
+        let mut run = 1;
+        while i + run < lengths.len() && lengths[i + run] == len {
+            run += 1;
+        }
+        i += run;
+
+        if len == 0 {
+            while run >= 11 {
+                let n = cmp::min(run, 138);
+                ret.push((18, (n - 11) as u8));
+                run -= n;
+            }
+            if run >= 3 {
+                ret.push((17, (run - 3) as u8));
+                run = 0;
+            }
+        } else {
+            ret.push((len, 0));
+            run -= 1;
+            while run >= 3 {
+                let n = cmp::min(run, 6);
+                ret.push((16, (n - 3) as u8));
+                run -= n;
+            }
+        }
+        for _ in 0..run {
+            ret.push((len, 0));
+        }
+    }
+    return ret;
+}
+
+
+// Huffman code lengths for the symbol frequencies, none longer than
+// max_bits.  At least two symbols get codes, since some decoders reject a
+// code with only one.
+fn huffman_lengths(freqs: &[u32], max_bits: u8) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    let mut nr_used = freqs.iter().filter(|f| **f > 0).count();
+    for f in freqs.iter_mut() {
+        if nr_used >= 2 {
+            break;
+        }
+        if *f == 0 {
+            *f = 1;
+            nr_used += 1;
+        }
+    }
+
+    // If the tree is too deep, flatten the frequencies and try again.  This
+    // terminates, since equal frequencies give a balanced tree.
+    loop {
+        let lengths = build_lengths(&freqs);
+        if lengths.iter().all(|l| *l <= max_bits) {
+            return lengths;
+        }
+        for f in freqs.iter_mut() {
+            if *f > 1 {
+                *f = (*f + 1) / 2;
+            }
+        }
+    }
+}
+
+
+// Code lengths (tree depths) of an unlimited Huffman tree, built with the
+// two queue method: leaves sorted by weight, and internal nodes, which are
+// created in order of weight.
+fn build_lengths(freqs: &[u32]) -> Vec<u8> {
+    let mut leaves: Vec<usize> = (0..freqs.len()).filter(|i| freqs[*i] > 0)
+        .collect();
+    leaves.sort_by(|a, b| freqs[*a].cmp(&freqs[*b]));
+
+    // Nodes 0..n are the leaves, in sorted order; the rest are internal
+    let n = leaves.len();
+    let mut weights: Vec<u64> = leaves.iter().map(|i| freqs[*i] as u64)
+        .collect();
+    let mut parents = vec![0; n];
+    let mut next_leaf = 0;
+    let mut next_internal = n;
+    for _ in 1..n {
+        let mut children = [0; 2];
+        for child in children.iter_mut() {
+            let use_leaf = next_leaf < n && (next_internal == weights.len()
+                || weights[next_leaf] <= weights[next_internal]);
+            if use_leaf {
+                *child = next_leaf;
+                next_leaf += 1;
+            } else {
+                *child = next_internal;
+                next_internal += 1;
+            }
+        }
+        let node = weights.len();
+        let weight = weights[children[0]] + weights[children[1]];
+        weights.push(weight);
+        parents.push(0);
+        parents[children[0]] = node;
+        parents[children[1]] = node;
+    }
+
+    // The root is the last node.  Parents always come after their children,
+    // so depths can be filled in from the root down.
+    let mut depths = vec![0u8; weights.len()];
+    for node in (0..weights.len().saturating_sub(1)).rev() {
+        depths[node] = depths[parents[node]] + 1;
+    }
+    let mut ret = vec![0u8; freqs.len()];
+    for (i, symbol) in leaves.iter().enumerate() {
+        ret[*symbol] = depths[i];
+    }
+    return ret;
+}
+
+
+/// The canonical Huffman codes for the code lengths, bit reversed so they
+/// can be written (or matched) least significant bit first
+pub fn huffman_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut count = [0u16; 16];
+    for l in lengths.iter() {
+        count[*l as usize] += 1;
+    }
+    count[0] = 0;
+    let mut next_code = [0u16; 16];
+    let mut code = 0u16;
+    for bits in 1..16 {
+        code = (code + count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+
+    let mut ret = vec![0u16; lengths.len()];
+    for (i, l) in lengths.iter().enumerate() {
+        let l = *l as usize;
+        if l > 0 {
+            ret[i] = reverse_bits(next_code[l], l);
+            next_code[l] += 1;
+        }
+    }
+    return ret;
+}
+
+
+fn reverse_bits(code: u16, nr_bits: usize) -> u16 {
+    let mut ret = 0;
+    for i in 0..nr_bits {
+        ret = (ret << 1) | ((code >> i) & 1);
+    }
+    return ret;
+}
+
+
+#[test]
+fn test_checksums() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+    // The table matches the polynomial
+    for i in 0..256 {
+        let mut c = i as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        assert_eq!(CRC32_TABLE[i], c);
+    }
+    assert_eq!(adler32(b""), 1);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+    let big = vec![0xffu8; 100000];
+    assert_eq!(adler32(&big), 0x149a302c);
+}
+
+#[test]
+fn test_deflate() {
+    // An empty fixed Huffman block
+    assert_eq!(deflate(b""), vec![0x03, 0x00]);
+
+    let text = b"{\"id\": 1, \"name\": \"mudpie\", \"tags\": [\"a\", \"b\"]},";
+    let mut data = Vec::new();
+    for _ in 0..1000 {
+        data.push_all(text);
+    }
+    let compressed = deflate(&data);
+    assert!(compressed.len() < data.len() / 20);
+
+    // Incompressible data falls back to stored blocks, with little overhead
+    let mut noise = Vec::new();
+    let mut x: u32 = 12345;
+    for _ in 0..200000 {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        noise.push((x >> 16) as u8);
+    }
+    assert!(deflate(&noise).len() < noise.len() + 100);
+}
+
+#[test]
+fn test_framing() {
+    let gz = gzip(b"hello");
+    assert_eq!(&gz[..3], &[0x1f, 0x8b, 8]);
+    assert_eq!(&gz[gz.len() - 8..], &[0x86, 0xa6, 0x10, 0x36, 5, 0, 0, 0]);
+    let z = zlib(b"hello");
+    assert_eq!(&z[..2], &[0x78, 0x9c]);
+    assert_eq!(&z[z.len() - 4..], &[0x06, 0x2c, 0x02, 0x15]);
+}
+
+#[test]
+fn test_huffman_lengths() {
+    let lengths = huffman_lengths(&[1, 1, 2, 4], 15);
+    assert_eq!(lengths, vec![3, 3, 2, 1]);
+    // Only one symbol used: a second one gets a code too
+    assert_eq!(huffman_lengths(&[0, 0, 7], 15), vec![1, 0, 1]);
+    // Fibonacci frequencies make the deepest tree; check the limit
+    let mut freqs = vec![1u32, 1];
+    for i in 2..30 {
+        let f = freqs[i - 1] + freqs[i - 2];
+        freqs.push(f);
+    }
+    let lengths = huffman_lengths(&freqs, 15);
+    assert!(lengths.iter().all(|l| *l > 0 && *l <= 15));
+    assert_eq!(huffman_codes(&[2, 1, 3, 3]), vec![1, 0, 3, 7]);
+}
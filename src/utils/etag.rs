@@ -0,0 +1,172 @@
+//! Entity tags (RFC 7232), the ETag response header and the tag lists in
+//! If-Match and If-None-Match
+
+use super::byteutils;
+
+
+/// An entity tag identifies one version of a resource.
+///
+/// A strong tag means the body is byte-for-byte identical to another with
+/// the same tag.  A weak tag only means the bodies are equivalent (e.g. the
+/// same page with a different timestamp in it).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ETag {
+    tag: String,
+    weak: bool,
+}
+
+impl ETag {
+    /// A strong tag.  The tag may contain printable ASCII except '"' and
+    /// space.  Panics if the tag is invalid.
+    pub fn strong(tag: &str) -> ETag {
+        return ETag::new(tag, false);
+    }
+
+    /// A weak tag.  The tag may contain printable ASCII except '"' and
+    /// space.  Panics if the tag is invalid.
+    pub fn weak(tag: &str) -> ETag {
+        return ETag::new(tag, true);
+    }
+
+    fn new(tag: &str, weak: bool) -> ETag {
+        if !tag.bytes().all(is_etag_char) {
+            panic!("invalid etag: {:?}", tag);
+        }
+        return ETag { tag: tag.to_string(), weak: weak };
+    }
+
+    /// The tag, without quotes or weak marker
+    pub fn get_tag(&self) -> &str {
+        return &self.tag;
+    }
+
+    pub fn is_weak(&self) -> bool {
+        return self.weak;
+    }
+
+    /// The header form, ex: "\"abc\"" or "W/\"abc\""
+    pub fn to_header_value(&self) -> String {
+        if self.weak {
+            return format!("W/\"{}\"", self.tag);
+        }
+        return format!("\"{}\"", self.tag);
+    }
+
+    /// Parse the header form of one tag.  Returns None if invalid.
+    pub fn parse(value: &[u8]) -> Option<ETag> {
+        let mut list = match parse_etag_list(value) {
+            Some(list) => list,
+            None => return None,
+        };
+        if list.len() != 1 {
+            return None;
+        }
+        return list.pop();
+    }
+
+    /// Strong comparison: both tags are strong, and equal
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        return !self.weak && !other.weak && self.tag == other.tag;
+    }
+
+    /// Weak comparison: the tags are equal, ignoring the weak markers
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        return self.tag == other.tag;
+    }
+}
+
+
+/// Parse a comma separated list of tags, ex: `"a", W/"b"`.  Returns None
+/// if the list is invalid.  (Note that "*" is not a tag; check for it first)
+pub fn parse_etag_list(value: &[u8]) -> Option<Vec<ETag>> {
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    loop {
+        // Skip whitespace and empty list elements
+        while pos < value.len()
+                && (value[pos] == b' ' || value[pos] == b'\t'
+                    || value[pos] == b',') {
+            pos += 1;
+        }
+        if pos == value.len() {
+            break;
+        }
+
+        let mut weak = false;
+        if value[pos..].starts_with(b"W/") {
+            weak = true;
+            pos += 2;
+        }
+        if pos == value.len() || value[pos] != b'"' {
+            return None;
+        }
+        pos += 1;
+        let start = pos;
+        while pos < value.len() && value[pos] != b'"' {
+            if !is_etag_char(value[pos]) && value[pos] < 0x80 {
+                return None;
+            }
+            pos += 1;
+        }
+        if pos == value.len() {
+            return None;
+        }
+        let tag = String::from_utf8_lossy(&value[start..pos]).into_owned();
+        pos += 1;
+        ret.push(ETag { tag: tag, weak: weak });
+
+        // The next thing must be a separator
+        let rest = byteutils::lstrip(&value[pos..]);
+        if !rest.is_empty() && rest[0] != b',' {
+            return None;
+        }
+    }
+    if ret.is_empty() {
+        return None;
+    }
+    return Some(ret);
+}
+
+
+// etagc, minus obs-text (which we don't generate)
+fn is_etag_char(c: u8) -> bool {
+    return c == 0x21 || (c >= 0x23 && c <= 0x7e);
+}
+
+
+#[test]
+fn test_etag() {
+    let a = ETag::strong("abc");
+    let w = ETag::weak("abc");
+    assert_eq!(a.to_header_value(), "\"abc\"");
+    assert_eq!(w.to_header_value(), "W/\"abc\"");
+    assert!(a.strong_eq(&a));
+    assert!(!a.strong_eq(&w));
+    assert!(!w.strong_eq(&w));
+    assert!(a.weak_eq(&w));
+    assert!(!a.weak_eq(&ETag::strong("abd")));
+
+    assert_eq!(ETag::parse(b"W/\"abc\""), Some(w));
+    assert_eq!(ETag::parse(b" \"abc\" "), Some(a));
+    assert_eq!(ETag::parse(b"\"\""), Some(ETag::strong("")));
+    assert_eq!(ETag::parse(b"abc"), None);
+    assert_eq!(ETag::parse(b"\"a\", \"b\""), None);
+}
+
+#[test]
+fn test_parse_etag_list() {
+    let list = parse_etag_list(b"\"a,b\" ,W/\"c\",, \"d\"").unwrap();
+    assert_eq!(list, vec![ETag::strong("a,b"), ETag::weak("c"),
+        ETag::strong("d")]);
+    assert!(parse_etag_list(b"").is_none());
+    assert!(parse_etag_list(b"*").is_none());
+    assert!(parse_etag_list(b"\"a\" \"b\"").is_none());
+    assert!(parse_etag_list(b"\"a").is_none());
+    assert!(parse_etag_list(b"w/\"a\"").is_none());
+}
+
+#[test]
+#[should_panic]
+fn test_bad_etag() {
+    ETag::strong("a\"b");
+}
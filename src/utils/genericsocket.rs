@@ -1,20 +1,51 @@
 use std::io;
+use std::net::TcpStream;
+use std::time::Duration;
 
-/*
- * Trait for reading and writing to a socket.  
- * Designed to be easily wrappable by SSL.
- */
-
+/// Trait for reading and writing to a socket.  Designed to be easily
+/// wrappable by SSL; see `TlsAcceptor`.
 pub trait GenericSocket : Send {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error>;
     fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error>;
+    /// Bound how long `read` blocks.  None means reads block forever.
+    /// Timed out reads fail with `WouldBlock` or `TimedOut`.
+    fn set_read_timeout(&mut self, timeout: Option<Duration>)
+        -> Result<(), io::Error>;
+}
+
+/// Whether an error is a read or write timeout.  Timeouts show up as
+/// WouldBlock on unix, and TimedOut on windows.
+pub fn is_timeout(err: &io::Error) -> bool {
+    return match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => false,
+    };
 }
 
-impl<T: io::Read + io::Write + Send> GenericSocket for T {
+impl GenericSocket for TcpStream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
-        return self.read(buf);
+        return io::Read::read(self, buf);
     }
     fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error> {
-        return self.write_all(buf);
+        return io::Write::write_all(self, buf);
+    }
+    fn set_read_timeout(&mut self, timeout: Option<Duration>)
+            -> Result<(), io::Error> {
+        return TcpStream::set_read_timeout(self, timeout);
+    }
+}
+
+// For tests that write to memory
+#[cfg(test)]
+impl GenericSocket for io::Cursor<Vec<u8>> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        return io::Read::read(self, buf);
+    }
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        return io::Write::write_all(self, buf);
+    }
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>)
+            -> Result<(), io::Error> {
+        return Ok(());
     }
 }
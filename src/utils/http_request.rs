@@ -1,11 +1,15 @@
 //! Low level parsing of an HTTP Request (path and headers)
 
+use std::borrow::Cow;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::ascii::AsciiExt;
 use std::ascii::OwnedAsciiExt; // the magic for into_ascii_lowercase
 
 use super::byteutils;
 
+// The owned form of a parsed request, for tests
+#[cfg(test)]
 pub struct Request {
     pub environ: HashMap<Vec<u8>, Vec<u8>>,
     pub path: String,
@@ -24,11 +28,155 @@ pub enum ParseError {
 }
 
 
+// Where a part of the request is in the buffer: start and end offsets
+type Span = (usize, usize);
+
+
+/// Where the parts of a parsed request are in its buffer.  Kept alongside
+/// the buffer, it makes a `RequestView` again without parsing.
+#[derive(Clone)]
+pub struct RequestLayout {
+    method: Span,
+    path: Span,
+    query_string: Span,
+    protocol: Span,
+    // (name, value), with the optional whitespace around the value stripped
+    headers: Vec<(Span, Span)>,
+}
+
+
+/// A parsed request that borrows from the bytes it was parsed from.  Nothing
+/// is copied or lowercased; `to_environ` builds the owned form, when it's
+/// needed.
+pub struct RequestView<'a> {
+    buf: &'a [u8],
+    layout: Cow<'a, RequestLayout>,
+}
+
+impl<'a> RequestView<'a> {
+    /// A view of a request parsed earlier, from the bytes and their layout
+    pub fn new(buf: &'a [u8], layout: &'a RequestLayout) -> RequestView<'a> {
+        return RequestView { buf: buf, layout: Cow::Borrowed(layout) };
+    }
+
+    /// The layout, to keep with the bytes
+    pub fn into_layout(self) -> RequestLayout {
+        return self.layout.into_owned();
+    }
+
+    /// As sent, ex: b"GET"
+    pub fn method(&self) -> &'a [u8] {
+        return self.slice(self.layout.method);
+    }
+
+    /// The request target up to any '?', not percent decoded
+    pub fn path(&self) -> &'a [u8] {
+        return self.slice(self.layout.path);
+    }
+
+    pub fn query_string(&self) -> &'a [u8] {
+        return self.slice(self.layout.query_string);
+    }
+
+    /// As sent, ex: b"HTTP/1.1"
+    pub fn protocol(&self) -> &'a [u8] {
+        return self.slice(self.layout.protocol);
+    }
+
+    /// The number of header lines
+    pub fn nr_headers(&self) -> usize {
+        return self.layout.headers.len();
+    }
+
+    /// The (name, value) of the i'th header line, in the order received.
+    /// The value has the optional whitespace around it stripped.
+    pub fn get_header_line(&self, i: usize) -> (&'a [u8], &'a [u8]) {
+        let (name, value) = self.layout.headers[i];
+        return (self.slice(name), self.slice(value));
+    }
+
+    /// The value of a header, by case insensitive name.  A repeated header
+    /// is joined like `parse_header_into` does, which is the only case that
+    /// copies.
+    pub fn get_header(&self, name: &[u8]) -> Option<Cow<'a, [u8]>> {
+        let mut ret: Option<Cow<'a, [u8]>> = None;
+        for i in 0..self.nr_headers() {
+            let (header_name, value) = self.get_header_line(i);
+            if !header_name.eq_ignore_ascii_case(name) {
+                continue;
+            }
+            ret = match ret {
+                None => Some(Cow::Borrowed(value)),
+                Some(prev) => {
+                    let mut joined = prev.into_owned();
+                    joined.push_all(header_separator(name));
+                    joined.push_all(value);
+                    Some(Cow::Owned(joined))
+                },
+            };
+        }
+        return ret;
+    }
+
+    /// The percent decoded and utf8 (lossy) decoded path
+    pub fn decoded_path(&self) -> String {
+        let path_decoded = byteutils::percent_decode(self.path());
+        return String::from_utf8_lossy(&path_decoded).into_owned();
+    }
+
+    /// The utf8 (lossy) decoded method, in lowercase
+    pub fn lowercase_method(&self) -> String {
+        return String::from_utf8_lossy(self.method()).into_owned()
+            .into_ascii_lowercase();
+    }
+
+    /// Build the owned environ: method, protocol, path, query_string, and
+    /// http_ + lowercased name for each header
+    pub fn to_environ(&self) -> HashMap<Vec<u8>, Vec<u8>> {
+        let mut environ = HashMap::<Vec<u8>, Vec<u8>>::new();
+        environ.insert(b"method".to_vec(),
+                self.method().to_vec().into_ascii_lowercase());
+        environ.insert(b"protocol".to_vec(),
+                self.protocol().to_vec().into_ascii_lowercase());
+        environ.insert(b"path".to_vec(), self.path().to_vec());
+        environ.insert(b"query_string".to_vec(), self.query_string().to_vec());
+        for i in 0..self.nr_headers() {
+            let (name, value) = self.get_header_line(i);
+            add_header(&mut environ, b"http_", name, value);
+        }
+        return environ;
+    }
+
+    // Build the owned Request
+    #[cfg(test)]
+    fn to_request(&self) -> Request {
+        return Request {
+            environ: self.to_environ(),
+            path: self.decoded_path(),
+            method: self.lowercase_method(),
+        };
+    }
+
+    fn slice(&self, span: Span) -> &'a [u8] {
+        return &self.buf[span.0..span.1];
+    }
+}
+
+
+// Parse a HTTP 1.0/1.1 request into the owned form
+#[cfg(test)]
+pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
+    let view = try!(parse_view(request_bytes));
+    return Ok(view.to_request());
+}
+
+
 /// Parse a HTTP 1.0/1.1 request.  Must end with \r\n\r\n, which is typically
 /// what your recv() loop waits for.  (Note: this does not include the body)
 ///
 /// request_bytes: raw request including final \r\n\r\n
-pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
+pub fn parse_view<'a>(request_bytes: &'a [u8])
+        -> Result<RequestView<'a>, ParseError> {
     /*
     http://tools.ietf.org/html/rfc7230#section-5.3.1
 
@@ -57,171 +205,254 @@ pub fn parse(request_bytes: &[u8]) -> Result<Request, ParseError> {
         return Err(ParseError::BadRequestLine);
     }
 
-    let method = request_parts[0].to_vec().into_ascii_lowercase();
-    let path = request_parts[1];  // NB: don't copy yet
-    let protocol = request_parts[2].to_vec().into_ascii_lowercase();
+    let method = request_parts[0];
+    let target = request_parts[1];
+    let protocol = request_parts[2];
 
     // Split doesn't coalesce spaces for us
-    if method.is_empty() || path.is_empty() || protocol.is_empty() {
+    if method.is_empty() || target.is_empty() || protocol.is_empty() {
         return Err(ParseError::BadRequestLine);
     }
 
-    if protocol != b"http/1.0" && protocol != b"http/1.1" {
+    if !protocol.eq_ignore_ascii_case(b"http/1.0")
+            && !protocol.eq_ignore_ascii_case(b"http/1.1") {
         return Err(ParseError::BadVersion);
     }
 
-    let mut environ = HashMap::<Vec<u8>, Vec<u8>>::new();
-    environ.insert(b"method".to_vec(), method.clone());
-    environ.insert(b"protocol".to_vec(), protocol.clone());
-
     // Parse path and query string
     // The OPTIONS method is allowed a path of '*'.
     // All other methods must have a path starting with '/'.
-    if method == b"options" && path == b"*" {
-        environ.insert(b"path".to_vec(), path.to_vec());
-        environ.insert(b"query_string".to_vec(), b"".to_vec());
+    let (path, query_string) = if method.eq_ignore_ascii_case(b"options")
+            && target == b"*" {
+        (target, &target[target.len()..])
     } else {
-        if path[0] != b'/' {
+        if target[0] != b'/' {
             return Err(ParseError::InvalidAbsolutePath);
         }
-        let parts = byteutils::split_bytes_on(path, b'?', 1); 
+        let parts = byteutils::split_bytes_on(target, b'?', 1); 
         if parts.len() > 1 {
-            environ.insert(b"path".to_vec(), parts[0].to_vec());
-            environ.insert(b"query_string".to_vec(), parts[1].to_vec());
+            (parts[0], parts[1])
         } else {
-            environ.insert(b"path".to_vec(), path.to_vec());
-            environ.insert(b"query_string".to_vec(), b"".to_vec());
+            (target, &target[target.len()..])
         }
-    }
-
-    // Also decode path into a normalized form.
-    let path_decoded = byteutils::percent_decode(
-            &environ.get(&b"path"[..]).unwrap());
-    let path_decoded_utf8 = String::from_utf8_lossy(
-            &path_decoded).into_owned();
-
-    // Decode method too, to make application code simpler
-    let method_utf8 = String::from_utf8_lossy(
-            &method).into_owned();
+    };
 
     // Now process the headers
+    let mut headers = Vec::new();
     for line in lines.iter().skip(1) {
         if line.is_empty() {
             // The last part (\r\n\r\n) appears as an empty header
             break;
         }
+        let (name, value) = try!(split_header(*line));
+        headers.push((span(request_bytes, name), span(request_bytes, value)));
+    }
 
-        // "Header: Value"
-        let header_parts = byteutils::split_bytes_on(*line, b':', 1);
-        if header_parts.len() != 2 {
-            return Err(ParseError::InvalidHeaderSeparator);
-        }
+    let layout = RequestLayout {
+        method: span(request_bytes, method),
+        path: span(request_bytes, path),
+        query_string: span(request_bytes, query_string),
+        protocol: span(request_bytes, protocol),
+        headers: headers,
+    };
+    return Ok(RequestView { buf: request_bytes, layout: Cow::Owned(layout) });
+}
 
-        // Reject obsolete header folding, or illegal space around header name,
-        // per RFC 7231
-        let header_name = header_parts[0];
-        if byteutils::strip(header_name) != header_name {
-            return Err(ParseError::InvalidHeaderWhitespace);
-        }
 
-        let mut nice_header_name = b"http_".to_vec();
-        nice_header_name.push_all(header_parts[0]);
-        let nice_header_name = nice_header_name.into_ascii_lowercase();
-        
-        // Strip optional whitespace around header value
-        let header_value = byteutils::strip(header_parts[1]).to_vec();
-
-        // If a header is repeated, make the values comma separated.
-        // Entry API is nice (gets around borrow checker frustration)
-        match environ.entry(nice_header_name) {
-            Entry::Vacant(entry) => { 
-                entry.insert(header_value); 
-            },
-            Entry::Occupied(mut entry) => {
-                (*entry.get_mut()).push_all(b",");
-                (*entry.get_mut()).push_all(&header_value);
-            }
+// Where part, a slice of buf, is in buf
+fn span(buf: &[u8], part: &[u8]) -> Span {
+    let start = part.as_ptr() as usize - buf.as_ptr() as usize;
+    return (start, start + part.len());
+}
+
+
+/// Parse a single "Name: Value" header line (without the trailing \r\n) and
+/// add it to `environ` under the key `prefix` + lowercased name.
+///
+/// If a header is repeated, the values are joined in order with a comma,
+/// except Cookie values are joined with "; " so they stay one cookie list.
+pub fn parse_header_into(environ: &mut HashMap<Vec<u8>, Vec<u8>>,
+        prefix: &[u8], line: &[u8]) -> Result<(), ParseError> {
+    let (name, value) = try!(split_header(line));
+    add_header(environ, prefix, name, value);
+    return Ok(());
+}
+
+
+// Split a "Name: Value" header line into the name and stripped value
+fn split_header(line: &[u8]) -> Result<(&[u8], &[u8]), ParseError> {
+    // "Header: Value"
+    let header_parts = byteutils::split_bytes_on(line, b':', 1);
+    if header_parts.len() != 2 {
+        return Err(ParseError::InvalidHeaderSeparator);
+    }
+
+    // Reject obsolete header folding, or illegal space around header name,
+    // per RFC 7231
+    let header_name = header_parts[0];
+    if byteutils::strip(header_name) != header_name {
+        return Err(ParseError::InvalidHeaderWhitespace);
+    }
+
+    // Strip optional whitespace around header value
+    return Ok((header_name, byteutils::strip(header_parts[1])));
+}
+
+
+// Add a header to environ, joining it to any earlier value
+fn add_header(environ: &mut HashMap<Vec<u8>, Vec<u8>>, prefix: &[u8],
+        name: &[u8], value: &[u8]) {
+    let mut nice_header_name = prefix.to_vec();
+    nice_header_name.push_all(name);
+    let nice_header_name = nice_header_name.into_ascii_lowercase();
+
+    // If a header is repeated, join the values with the separator.
+    // Entry API is nice (gets around borrow checker frustration)
+    match environ.entry(nice_header_name) {
+        Entry::Vacant(entry) => { 
+            entry.insert(value.to_vec()); 
+        },
+        Entry::Occupied(mut entry) => {
+            (*entry.get_mut()).push_all(header_separator(name));
+            (*entry.get_mut()).push_all(value);
         }
     }
+}
+
 
-    return Ok(Request {
-        environ: environ,
-        path: path_decoded_utf8,
-        method: method_utf8,
-    });
+// What repeated values of a header are joined with
+fn header_separator(name: &[u8]) -> &'static [u8] {
+    if name.eq_ignore_ascii_case(b"cookie") {
+        return b"; ";
+    }
+    return b",";
 }
 
+
 #[cfg(test)]
 fn assert_header_eq(req: &Request, header: &[u8], val: &[u8]) {
     assert_eq!(&**req.environ.get(header).unwrap(), val);
 }
 
+// Parse both ways.  The owned request is built from the view, so only
+// errors can differ.
+#[cfg(test)]
+fn parse_both(s: &[u8]) -> Result<(RequestView, Request), ParseError> {
+    let view = parse_view(s);
+    let req = parse(s);
+    assert_eq!(view.as_ref().err(), req.as_ref().err());
+    return Ok((try!(view), try!(req)));
+}
+
 
 #[test]
 fn test_request_ok() {
     let s = b"GET / HTTP/1.0\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert!(r.is_ok());
 
     let s = b"GET /foo%20bar HTTP/1.0\r\nFoo: Bar\r\nA B C:   D E F  \r\n\r\n";
-    let r = parse(s).ok().unwrap();
+    let (v, r) = parse_both(s).ok().unwrap();
     assert_header_eq(&r, b"method", b"get");
     assert_header_eq(&r, b"path", b"/foo%20bar");
     assert_header_eq(&r, b"protocol", b"http/1.0");
+    assert_eq!(v.method(), b"GET");
+    assert_eq!(v.path(), b"/foo%20bar");
+    assert_eq!(v.protocol(), b"HTTP/1.0");
 
     assert_header_eq(&r, b"http_foo", b"Bar");
     assert_header_eq(&r, b"http_a b c", b"D E F");
+    assert_eq!(v.get_header(b"foo").unwrap(), &b"Bar"[..]);
+    assert_eq!(v.get_header(b"a b c").unwrap(), &b"D E F"[..]);
+    assert!(v.get_header(b"bar").is_none());
 
     assert_eq!(r.path, "/foo bar");
+    assert_eq!(r.method, "get");
+
+    let s = b"POST /a?b=c?d HTTP/1.1\r\n\r\n";
+    let (v, r) = parse_both(s).ok().unwrap();
+    assert_header_eq(&r, b"path", b"/a");
+    assert_header_eq(&r, b"query_string", b"b=c?d");
+    assert_eq!(v.path(), b"/a");
+    assert_eq!(v.query_string(), b"b=c?d");
 
     let s = b"OPTIONS * HTTP/1.1\r\n\r\n";
-    let r = parse(s);
-    assert!(r.is_ok());
+    let (v, r) = parse_both(s).ok().unwrap();
+    assert_header_eq(&r, b"path", b"*");
+    assert_eq!(v.path(), b"*");
+    assert_eq!(v.query_string(), b"");
 }
 
 #[test]
 fn test_request_multi_header() {
     let s = b"GET / HTTP/1.0\r\nH: foo\r\nH: bar\r\nZ: baz\r\nH:   hello again  \r\n\r\n";
-    let r = parse(s).ok().unwrap();
+    let (v, r) = parse_both(s).ok().unwrap();
     assert_header_eq(&r, b"http_h", b"foo,bar,hello again");
     assert_header_eq(&r, b"http_z", b"baz");
+    assert_eq!(v.get_header(b"h").unwrap(), &b"foo,bar,hello again"[..]);
+    assert_eq!(v.nr_headers(), 4);
+    assert_eq!(v.get_header_line(3), (&b"H"[..], &b"hello again"[..]));
+
+    // Only a repeated header is copied
+    match v.get_header(b"Z") {
+        Some(Cow::Borrowed(val)) => assert_eq!(val, b"baz"),
+        _ => panic!("expected a borrowed value"),
+    }
+
+    let s = b"GET / HTTP/1.1\r\nCookie: a=1\r\ncookie: b=2\r\n\r\n";
+    let (v, r) = parse_both(s).ok().unwrap();
+    assert_header_eq(&r, b"http_cookie", b"a=1; b=2");
+    assert_eq!(v.get_header(b"cookie").unwrap(), &b"a=1; b=2"[..]);
 }
 
 #[test]
 fn test_request_bad() {
     let s = b"\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::BadRequestLine);
 
     let s = b"GET /\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::BadRequestLine);
 
     let s = b"GET  HTTP/1.0\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::BadRequestLine);
 
     let s = b"     \r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::BadRequestLine);
 
     let s = b"GET / HTTP/3.0\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::BadVersion);
 
     let s = b"GET * HTTP/1.0\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::InvalidAbsolutePath);
 
     let s = b"GET / HTTP/1.0\r\nABC DEF\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::InvalidHeaderSeparator);
 
     let s = b"GET / HTTP/1.0\r\nABC : DEF\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::InvalidHeaderWhitespace);
 
     let s = b"GET / HTTP/1.0\r\n ABC: DEF\r\n\r\n";
-    let r = parse(s);
+    let r = parse_both(s);
     assert_eq!(r.err().unwrap(), ParseError::InvalidHeaderWhitespace);
 }
+
+#[test]
+fn test_parse_header_into() {
+    let mut environ = HashMap::new();
+    assert!(parse_header_into(&mut environ, b"trailer_", b"X-Sum:  abc ").is_ok());
+    assert!(parse_header_into(&mut environ, b"trailer_", b"x-sum: def").is_ok());
+    assert_eq!(&**environ.get(&b"trailer_x-sum"[..]).unwrap(), b"abc,def");
+
+    assert_eq!(parse_header_into(&mut environ, b"trailer_", b"X-Sum").err(),
+        Some(ParseError::InvalidHeaderSeparator));
+    assert_eq!(parse_header_into(&mut environ, b"trailer_", b"X-Sum : a").err(),
+        Some(ParseError::InvalidHeaderWhitespace));
+}
@@ -0,0 +1,157 @@
+//! HTTP dates (RFC 7231 IMF-fixdate), ex: "Sun, 06 Nov 1994 08:49:37 GMT"
+
+use super::byteutils;
+
+static DAY_NAMES: [&'static str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu",
+    "Fri", "Sat"];
+static MONTH_NAMES: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May",
+    "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+
+/// Format seconds since the Unix epoch as an HTTP date
+pub fn format_http_date(secs: u64) -> String {
+    let days = secs / 86400;
+    let day_secs = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    // 1970-01-01 was a Thursday
+    let weekday = (days + 4) % 7;
+    return format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAY_NAMES[weekday as usize], day, MONTH_NAMES[month as usize - 1],
+            year, day_secs / 3600, day_secs % 3600 / 60, day_secs % 60);
+}
+
+
+/// Parse an HTTP date in any of the three formats HTTP/1.1 allows, into
+/// seconds since the Unix epoch.  Returns None if invalid, or before 1970.
+///
+/// * IMF-fixdate: "Sun, 06 Nov 1994 08:49:37 GMT"
+/// * RFC 850: "Sunday, 06-Nov-94 08:49:37 GMT"
+/// * asctime: "Sun Nov  6 08:49:37 1994"
+pub fn parse_http_date(input: &[u8]) -> Option<u64> {
+    let input = match ::std::str::from_utf8(input) {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+    let tokens: Vec<&str> = input.split(' ').filter(|t| !t.is_empty())
+        .collect();
+    let (day, month, year, time) = match tokens.len() {
+        6 if tokens[5] == "GMT" => {
+            (tokens[1], tokens[2], tokens[3], tokens[4])
+        },
+        4 if tokens[3] == "GMT" => {
+            let date: Vec<&str> = tokens[1].split('-').collect();
+            if date.len() != 3 {
+                return None;
+            }
+            (date[0], date[1], date[2], tokens[2])
+        },
+        5 => (tokens[2], tokens[1], tokens[4], tokens[3]),
+        _ => return None,
+    };
+
+    let time: Vec<&str> = time.split(':').collect();
+    if time.len() != 3 {
+        return None;
+    }
+    let day = parse_number(day, 1, 2);
+    let month = MONTH_NAMES.iter().position(|m| *m == month);
+    let year = parse_number(year, 2, 4);
+    let hour = parse_number(time[0], 2, 2);
+    let minute = parse_number(time[1], 2, 2);
+    let second = parse_number(time[2], 2, 2);
+    if day.is_none() || month.is_none() || year.is_none() || hour.is_none()
+            || minute.is_none() || second.is_none() {
+        return None;
+    }
+    let day = day.unwrap();
+    let month = month.unwrap() as u64 + 1;
+    let mut year = year.unwrap();
+    let hour = hour.unwrap();
+    let minute = minute.unwrap();
+    let second = second.unwrap();
+
+    if year < 100 {
+        // RFC 850 two digit years
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+    if day < 1 || day > 31 || hour > 23 || minute > 59 || second > 60
+            || year < 1970 {
+        return None;
+    }
+    let days = days_from_civil(year, month, day);
+    return Some(days * 86400 + hour * 3600 + minute * 60 + second);
+}
+
+
+// Parse a decimal number with between min_digits and max_digits digits
+fn parse_number(input: &str, min_digits: usize, max_digits: usize)
+        -> Option<u64> {
+    if input.len() < min_digits || input.len() > max_digits {
+        return None;
+    }
+    return byteutils::parse_u64(input.as_bytes());
+}
+
+
+// Convert (year >= 1970, month 1-12, day 1-31) to days since 1970-01-01.
+// The inverse of civil_from_days.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    return era * 146097 + doe - 719468;
+}
+
+
+// Convert days since 1970-01-01 to (year, month 1-12, day 1-31).
+// This is Howard Hinnant's civil_from_days algorithm, for the proleptic
+// Gregorian calendar, using 400 year eras that start on March 1st.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return (year, month, day);
+}
+
+
+#[test]
+fn test_format_http_date() {
+    assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    assert_eq!(format_http_date(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert_eq!(format_http_date(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+    assert_eq!(format_http_date(4102444799), "Thu, 31 Dec 2099 23:59:59 GMT");
+}
+
+#[test]
+fn test_parse_http_date() {
+    assert_eq!(parse_http_date(b"Sun, 06 Nov 1994 08:49:37 GMT"),
+        Some(784111777));
+    assert_eq!(parse_http_date(b"Sunday, 06-Nov-94 08:49:37 GMT"),
+        Some(784111777));
+    assert_eq!(parse_http_date(b"Sun Nov  6 08:49:37 1994"), Some(784111777));
+    assert_eq!(parse_http_date(b"Tue, 29 Feb 2000 00:00:00 GMT"),
+        Some(951782400));
+    assert_eq!(parse_http_date(b"Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+    for secs in [0, 86399, 951782400, 4102444799].iter() {
+        let date = format_http_date(*secs);
+        assert_eq!(parse_http_date(date.as_bytes()), Some(*secs));
+    }
+
+    assert!(parse_http_date(b"").is_none());
+    assert!(parse_http_date(b"Sun, 06 Nov 1994 08:49:37 PST").is_none());
+    assert!(parse_http_date(b"Sun, 06 Foo 1994 08:49:37 GMT").is_none());
+    assert!(parse_http_date(b"Sun, 32 Nov 1994 08:49:37 GMT").is_none());
+    assert!(parse_http_date(b"Sun, 06 Nov 1994 24:49:37 GMT").is_none());
+    assert!(parse_http_date(b"Sun, 06 Nov 1969 08:49:37 GMT").is_none());
+    assert!(parse_http_date(b"Sun, 06 Nov 1994 08:49 GMT").is_none());
+    assert!(parse_http_date(b"1994").is_none());
+}
@@ -0,0 +1,495 @@
+//! DEFLATE decompression (RFC 1951), and the gzip (RFC 1952) and zlib
+//! (RFC 1950) formats around it.
+//!
+//! The output size is limited by the caller, so a small compressed input
+//! can't expand into an arbitrarily large one (a "zip bomb").
+
+use super::deflate;
+use super::deflate::{LENGTH_BASE, LENGTH_EXTRA, DIST_BASE, DIST_EXTRA};
+
+
+#[derive(Debug, PartialEq)]
+pub enum InflateError {
+    // Corrupt, truncated, or failed a checksum
+    Invalid,
+    // The output would be larger than the limit
+    TooLarge,
+}
+
+
+/// Decompress a raw DEFLATE stream, of at most `max_size` bytes
+pub fn inflate(data: &[u8], max_size: usize)
+        -> Result<Vec<u8>, InflateError> {
+    let (ret, used) = try!(inflate_stream(data, max_size));
+    if used != data.len() {
+        return Err(InflateError::Invalid);
+    }
+    return Ok(ret);
+}
+
+
+/// Decompress gzip data, of at most `max_size` bytes.  Concatenated gzip
+/// members are decompressed one after the other.
+pub fn inflate_gzip(data: &[u8], max_size: usize)
+        -> Result<Vec<u8>, InflateError> {
+    let mut ret = Vec::new();
+    let mut pos = 0;
+    loop {
+        let start = try!(skip_gzip_header(&data[pos..])) + pos;
+        let (member, used) = try!(inflate_stream(&data[start..],
+                    max_size - ret.len()));
+        let end = start + used;
+        if end + 8 > data.len() {
+            return Err(InflateError::Invalid);
+        }
+        let crc = read_u32_le(&data[end..]);
+        let size = read_u32_le(&data[end + 4..]);
+        if crc != deflate::crc32(&member) || size != member.len() as u32 {
+            return Err(InflateError::Invalid);
+        }
+        ret.push_all(&member);
+        pos = end + 8;
+        if pos == data.len() {
+            return Ok(ret);
+        }
+    }
+}
+
+
+/// Decompress zlib data, of at most `max_size` bytes
+pub fn inflate_zlib(data: &[u8], max_size: usize)
+        -> Result<Vec<u8>, InflateError> {
+    if !has_zlib_header(data) {
+        return Err(InflateError::Invalid);
+    }
+    let (ret, used) = try!(inflate_stream(&data[2..], max_size));
+    let end = 2 + used;
+    if end + 4 != data.len() {
+        return Err(InflateError::Invalid);
+    }
+    let checksum = ((data[end] as u32) << 24) | ((data[end + 1] as u32) << 16)
+        | ((data[end + 2] as u32) << 8) | (data[end + 3] as u32);
+    if checksum != deflate::adler32(&ret) {
+        return Err(InflateError::Invalid);
+    }
+    return Ok(ret);
+}
+
+
+/// Does the data start with a zlib header (DEFLATE, no preset dictionary)?
+pub fn has_zlib_header(data: &[u8]) -> bool {
+    if data.len() < 2 {
+        return false;
+    }
+    let (cmf, flg) = (data[0] as u32, data[1] as u32);
+    return cmf & 0x0f == 8 && cmf >> 4 <= 7 && (cmf * 256 + flg) % 31 == 0
+        && flg & 0x20 == 0;
+}
+
+
+// Returns the size of the gzip member header at the start of data
+fn skip_gzip_header(data: &[u8]) -> Result<usize, InflateError> {
+    if data.len() < 10 || data[0] != 0x1f || data[1] != 0x8b || data[2] != 8 {
+        return Err(InflateError::Invalid);
+    }
+    let flags = data[3];
+    if flags & 0xe0 != 0 {
+        return Err(InflateError::Invalid);
+    }
+    let mut pos = 10;
+    // FEXTRA: length prefixed extra fields
+    if flags & 0x04 != 0 {
+        if pos + 2 > data.len() {
+            return Err(InflateError::Invalid);
+        }
+        pos += 2 + (data[pos] as usize | (data[pos + 1] as usize) << 8);
+    }
+    // FNAME and FCOMMENT: zero terminated strings
+    for flag in [0x08, 0x10].iter() {
+        if flags & *flag != 0 {
+            while pos < data.len() && data[pos] != 0 {
+                pos += 1;
+            }
+            pos += 1;
+        }
+    }
+    // FHCRC: a CRC of the header, which we don't check
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err(InflateError::Invalid);
+    }
+    return Ok(pos);
+}
+
+
+fn read_u32_le(data: &[u8]) -> u32 {
+    return (data[0] as u32) | ((data[1] as u32) << 8)
+        | ((data[2] as u32) << 16) | ((data[3] as u32) << 24);
+}
+
+
+// Decompress one DEFLATE stream from the start of data.  Returns the output,
+// and the number of bytes of data the stream used (rounded up to a byte).
+fn inflate_stream(data: &[u8], max_size: usize)
+        -> Result<(Vec<u8>, usize), InflateError> {
+    let mut out = Vec::new();
+    let mut reader = BitReader::new(data);
+    loop {
+        let is_final = try!(reader.read_bits(1)) == 1;
+        match try!(reader.read_bits(2)) {
+            0 => try!(inflate_stored(&mut reader, &mut out, max_size)),
+            1 => {
+                let (lit_lengths, dist_lengths) = deflate::fixed_lengths();
+                let lit = try!(Huffman::new(&lit_lengths));
+                let dist = try!(Huffman::new(&dist_lengths));
+                try!(inflate_codes(&mut reader, &mut out, max_size, &lit,
+                            &dist));
+            },
+            2 => {
+                let (lit, dist) = try!(read_dynamic_header(&mut reader));
+                try!(inflate_codes(&mut reader, &mut out, max_size, &lit,
+                            &dist));
+            },
+            _ => return Err(InflateError::Invalid),
+        }
+        if is_final {
+            break;
+        }
+    }
+    try!(reader.align());
+    let used = reader.byte_pos();
+    return Ok((out, used));
+}
+
+
+fn inflate_stored(reader: &mut BitReader, out: &mut Vec<u8>,
+        max_size: usize) -> Result<(), InflateError> {
+    try!(reader.align());
+    let len = try!(reader.read_bits(16));
+    let nlen = try!(reader.read_bits(16));
+    if len != !nlen & 0xffff {
+        return Err(InflateError::Invalid);
+    }
+    let len = len as usize;
+    if out.len() + len > max_size {
+        return Err(InflateError::TooLarge);
+    }
+    out.push_all(try!(reader.take_bytes(len)));
+    return Ok(());
+}
+
+
+// Decode literals and matches until the end of block code
+fn inflate_codes(reader: &mut BitReader, out: &mut Vec<u8>, max_size: usize,
+        lit: &Huffman, dist: &Huffman) -> Result<(), InflateError> {
+    loop {
+        let sym = try!(lit.decode(reader)) as usize;
+        if sym < 256 {
+            if out.len() >= max_size {
+                return Err(InflateError::TooLarge);
+            }
+            out.push(sym as u8);
+            continue;
+        }
+        if sym == 256 {
+            return Ok(());
+        }
+
+        let lc = sym - 257;
+        if lc >= LENGTH_BASE.len() {
+            return Err(InflateError::Invalid);
+        }
+        let len = LENGTH_BASE[lc] as usize
+            + try!(reader.read_bits(LENGTH_EXTRA[lc] as u32)) as usize;
+        let dc = try!(dist.decode(reader)) as usize;
+        if dc >= DIST_BASE.len() {
+            return Err(InflateError::Invalid);
+        }
+        let distance = DIST_BASE[dc] as usize
+            + try!(reader.read_bits(DIST_EXTRA[dc] as u32)) as usize;
+        if distance > out.len() {
+            return Err(InflateError::Invalid);
+        }
+        if out.len() + len > max_size {
+            return Err(InflateError::TooLarge);
+        }
+        // The match can overlap the bytes it produces, so copy one by one
+        let start = out.len() - distance;
+        for i in 0..len {
+            let c = out[start + i];
+            out.push(c);
+        }
+    }
+}
+
+
+// Read the code lengths at the start of a dynamic block, and build the
+// literal/length and distance codes from them
+fn read_dynamic_header(reader: &mut BitReader)
+        -> Result<(Huffman, Huffman), InflateError> {
+    let nr_lit = try!(reader.read_bits(5)) as usize + 257;
+    let nr_dist = try!(reader.read_bits(5)) as usize + 1;
+    let nr_cl = try!(reader.read_bits(4)) as usize + 4;
+    if nr_lit > 286 || nr_dist > 30 {
+        return Err(InflateError::Invalid);
+    }
+
+    let mut cl_lengths = [0u8; 19];
+    for i in 0..nr_cl {
+        cl_lengths[deflate::CODE_LENGTH_ORDER[i]] =
+            try!(reader.read_bits(3)) as u8;
+    }
+    let cl = try!(Huffman::new(&cl_lengths));
+
+    let total = nr_lit + nr_dist;
+    let mut lengths: Vec<u8> = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let sym = try!(cl.decode(reader));
+        let (value, repeat) = match sym {
+            0...15 => (sym as u8, 1),
+            16 => {
+                let prev = match lengths.last() {
+                    Some(prev) => *prev,
+                    None => return Err(InflateError::Invalid),
+                };
+                (prev, 3 + try!(reader.read_bits(2)) as usize)
+            },
+            17 => (0, 3 + try!(reader.read_bits(3)) as usize),
+            18 => (0, 11 + try!(reader.read_bits(7)) as usize),
+            _ => return Err(InflateError::Invalid),
+        };
+        if lengths.len() + repeat > total {
+            return Err(InflateError::Invalid);
+        }
+        for _ in 0..repeat {
+            lengths.push(value);
+        }
+    }
+    // A block without an end of block code can't end
+    if lengths[256] == 0 {
+        return Err(InflateError::Invalid);
+    }
+
+    let lit = try!(Huffman::new(&lengths[..nr_lit]));
+    let dist = try!(Huffman::new(&lengths[nr_lit..]));
+    return Ok((lit, dist));
+}
+
+
+// A Huffman code, decoded with a table indexed by the next max_len bits of
+// input.  Each entry is (symbol, code length); length 0 means no code starts
+// with those bits.
+struct Huffman {
+    table: Vec<(u16, u8)>,
+    max_len: u32,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, InflateError> {
+        // Reject over-subscribed codes (more codes of a length than fit).
+        // Incomplete codes are allowed, but fail if an unused code is read.
+        let mut count = [0i32; 16];
+        for l in lengths.iter() {
+            count[*l as usize] += 1;
+        }
+        let mut left = 1;
+        for bits in 1..16 {
+            left = left * 2 - count[bits];
+            if left < 0 {
+                return Err(InflateError::Invalid);
+            }
+        }
+
+        let max_len = lengths.iter().fold(0, |m, l| ::std::cmp::max(m, *l))
+            as u32;
+        let mut table = vec![(0u16, 0u8); 1 << max_len];
+        let codes = deflate::huffman_codes(lengths);
+        for (sym, l) in lengths.iter().enumerate() {
+            let l = *l as u32;
+            if l == 0 {
+                continue;
+            }
+            // Codes are read least significant bit first, so every index
+            // whose low l bits are the (reversed) code decodes to sym
+            let mut idx = codes[sym] as usize;
+            while idx < table.len() {
+                table[idx] = (sym as u16, l as u8);
+                idx += 1 << l;
+            }
+        }
+        return Ok(Huffman { table: table, max_len: max_len });
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, InflateError> {
+        let bits = reader.peek_bits(self.max_len) as usize;
+        let (sym, len) = self.table[bits];
+        if len == 0 {
+            return Err(InflateError::Invalid);
+        }
+        try!(reader.consume(len as u32));
+        return Ok(sym);
+    }
+}
+
+
+// Reads bits least significant first.  Reading past the end of the data
+// gives zero bits, so a code can be peeked at near the end, but consuming
+// them is an error.
+struct BitReader<'a> {
+    data: &'a [u8],
+    // Next byte to load into bits; may be past the end
+    pos: usize,
+    bits: u64,
+    nr_bits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        return BitReader { data: data, pos: 0, bits: 0, nr_bits: 0 };
+    }
+
+    fn refill(&mut self) {
+        while self.nr_bits <= 56 {
+            let byte = if self.pos < self.data.len() {
+                self.data[self.pos]
+            } else {
+                0
+            };
+            self.bits |= (byte as u64) << self.nr_bits;
+            self.nr_bits += 8;
+            self.pos += 1;
+        }
+    }
+
+    fn peek_bits(&mut self, nr_bits: u32) -> u32 {
+        if self.nr_bits < nr_bits {
+            self.refill();
+        }
+        return (self.bits & ((1 << nr_bits) - 1)) as u32;
+    }
+
+    fn consume(&mut self, nr_bits: u32) -> Result<(), InflateError> {
+        if self.nr_bits < nr_bits {
+            self.refill();
+        }
+        self.bits >>= nr_bits;
+        self.nr_bits -= nr_bits;
+        if self.pos * 8 - self.nr_bits as usize > self.data.len() * 8 {
+            return Err(InflateError::Invalid);
+        }
+        return Ok(());
+    }
+
+    fn read_bits(&mut self, nr_bits: u32) -> Result<u32, InflateError> {
+        let ret = self.peek_bits(nr_bits);
+        try!(self.consume(nr_bits));
+        return Ok(ret);
+    }
+
+    // Skip to the next byte boundary
+    fn align(&mut self) -> Result<(), InflateError> {
+        let extra = self.nr_bits % 8;
+        return self.consume(extra);
+    }
+
+    // Position of the next unread byte.  Only valid when aligned.
+    fn byte_pos(&self) -> usize {
+        return self.pos - (self.nr_bits / 8) as usize;
+    }
+
+    // Take bytes directly from the data.  Only valid when aligned.
+    fn take_bytes(&mut self, len: usize) -> Result<&'a [u8], InflateError> {
+        let start = self.byte_pos();
+        if start + len > self.data.len() {
+            return Err(InflateError::Invalid);
+        }
+        self.pos = start + len;
+        self.bits = 0;
+        self.nr_bits = 0;
+        return Ok(&self.data[start .. start + len]);
+    }
+}
+
+
+#[test]
+fn test_inflate_round_trip() {
+    let mut inputs: Vec<Vec<u8>> = vec![Vec::new(), b"a".to_vec(),
+        vec![0; 100000]];
+    let mut text = Vec::new();
+    for i in 0..5000 {
+        text.push_all(format!("{{\"id\": {}, \"ok\": true}}, ", i).as_bytes());
+    }
+    inputs.push(text);
+    let mut noise = Vec::new();
+    let mut x: u32 = 1;
+    for _ in 0..100000 {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        noise.push((x >> 16) as u8);
+    }
+    inputs.push(noise);
+
+    for input in inputs.iter() {
+        let max = input.len();
+        assert_eq!(inflate(&deflate::deflate(input), max).as_ref(),
+            Ok(input));
+        assert_eq!(inflate_gzip(&deflate::gzip(input), max).as_ref(),
+            Ok(input));
+        assert_eq!(inflate_zlib(&deflate::zlib(input), max).as_ref(),
+            Ok(input));
+    }
+}
+
+#[test]
+fn test_inflate_other_encoders() {
+    // From Python's gzip module: with a file name, and a fixed block
+    let gz = [0x1f, 0x8b, 0x08, 0x08, 0x01, 0x00, 0x00, 0x00, 0x02, 0xff,
+        0x61, 0x2e, 0x74, 0x78, 0x74, 0x00, 0xcb, 0x48, 0xcd, 0xc9, 0xc9,
+        0xd7, 0x51, 0xc8, 0x40, 0xa2, 0x14, 0xca, 0xf3, 0x8b, 0x72, 0x52,
+        0xb8, 0x00, 0x87, 0x5d, 0x46, 0x2b, 0x1a, 0x00, 0x00, 0x00];
+    assert_eq!(inflate_gzip(&gz, 100).unwrap(),
+        b"hello, hello, hello world\n".to_vec());
+
+    // Two members
+    let mut two = gz.to_vec();
+    two.push_all(&gz);
+    assert_eq!(inflate_gzip(&two, 100).unwrap().len(), 52);
+
+    // From zlib, raw
+    let raw = [0xcb, 0x2d, 0x4d, 0x29, 0xc8, 0x4c, 0x55, 0xc8, 0x1d, 0x0c,
+        0x54, 0x49, 0x46, 0xaa, 0x42, 0x6a, 0x5e, 0x0a, 0x00];
+    let mut expected = Vec::new();
+    for _ in 0..20 {
+        expected.push_all(b"mudpie ");
+    }
+    expected.push_all(b"the end");
+    assert_eq!(inflate(&raw, 1000).unwrap(), expected);
+}
+
+#[test]
+fn test_inflate_errors() {
+    // Expands past the limit
+    let bomb = deflate::gzip(&vec![0; 1000000]);
+    assert!(bomb.len() < 2000);
+    assert_eq!(inflate_gzip(&bomb, 999999), Err(InflateError::TooLarge));
+    assert_eq!(inflate_gzip(&bomb, 1000000).unwrap().len(), 1000000);
+
+    let gz = deflate::gzip(b"hello hello hello");
+    for i in 0..gz.len() {
+        assert!(inflate_gzip(&gz[..i], 100).is_err());
+    }
+    let mut bad_crc = gz.clone();
+    let len = bad_crc.len();
+    bad_crc[len - 8] ^= 1;
+    assert_eq!(inflate_gzip(&bad_crc, 100), Err(InflateError::Invalid));
+    let mut trailing = gz.clone();
+    trailing.push(0);
+    assert_eq!(inflate_gzip(&trailing, 100), Err(InflateError::Invalid));
+
+    // Reserved block type, and a distance before the start
+    assert_eq!(inflate(&[0x07], 100), Err(InflateError::Invalid));
+    assert_eq!(inflate(&[0x03, 0x02, 0x00], 100),
+        Err(InflateError::Invalid));
+    assert!(!has_zlib_header(b"\x1f\x8b"));
+}
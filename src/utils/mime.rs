@@ -0,0 +1,71 @@
+//! Content-Type guessing from file extensions
+
+use std::ascii::AsciiExt;
+
+
+// Text types are assumed to be UTF-8
+static MIME_TYPES: &'static [(&'static str, &'static str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "application/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("adoc", "text/plain; charset=utf-8"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("wasm", "application/wasm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+
+/// The Content-Type for a file name, from its extension (case-insensitive).
+/// Unknown extensions get "application/octet-stream".
+pub fn guess_mime_type(file_name: &str) -> &'static str {
+    let base_name = match file_name.rfind('/') {
+        Some(n) => &file_name[n + 1 ..],
+        None => file_name,
+    };
+    match base_name.rfind('.') {
+        Some(n) => {
+            let extension = &base_name[n + 1 ..];
+            for &(ext, mime_type) in MIME_TYPES.iter() {
+                if ext.eq_ignore_ascii_case(extension) {
+                    return mime_type;
+                }
+            }
+        },
+        None => {},
+    }
+    return "application/octet-stream";
+}
+
+
+#[test]
+fn test_guess_mime_type() {
+    assert_eq!(guess_mime_type("index.html"), "text/html; charset=utf-8");
+    assert_eq!(guess_mime_type("/a.b/PHOTO.JPG"), "image/jpeg");
+    assert_eq!(guess_mime_type("archive.tar.gz"), "application/gzip");
+    assert_eq!(guess_mime_type("a.b/README"), "application/octet-stream");
+    assert_eq!(guess_mime_type("file."), "application/octet-stream");
+}
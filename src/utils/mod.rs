@@ -3,3 +3,15 @@ pub mod byteutils;
 pub mod http_request;
 pub mod escape;
 pub mod genericsocket;
+pub mod urlencoded;
+pub mod multipart;
+pub mod httpdate;
+pub mod cookie;
+pub mod mime;
+pub mod byteranges;
+pub mod etag;
+pub mod deflate;
+pub mod inflate;
+pub mod sha1;
+pub mod base64;
+pub mod clock;
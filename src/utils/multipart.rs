@@ -0,0 +1,314 @@
+//! Parsing of multipart/form-data bodies (RFC 7578), i.e. browser file uploads
+
+use std::collections::HashMap;
+use std::ascii::OwnedAsciiExt;
+
+use super::byteutils;
+use super::http_request;
+
+
+/// One part of a multipart/form-data body.  The data is a slice of the
+/// request body; nothing is copied.
+pub struct MultipartPart<'a> {
+    headers: HashMap<Vec<u8>, Vec<u8>>,
+    name: Option<String>,
+    filename: Option<String>,
+    data: &'a [u8],
+}
+
+impl<'a> MultipartPart<'a> {
+    /// The part headers.  Names are lowercased, and repeated headers are
+    /// joined with a comma, like the request environ.
+    pub fn get_headers(&self) -> &HashMap<Vec<u8>, Vec<u8>> {
+        return &self.headers;
+    }
+
+    /// The form field name, from Content-Disposition
+    pub fn get_name(&self) -> Option<&str> {
+        return self.name.as_ref().map(|s| &**s);
+    }
+
+    /// The uploaded file's name, from Content-Disposition, if this part is a
+    /// file.  This comes from the client; don't use it as a path.
+    pub fn get_filename(&self) -> Option<&str> {
+        return self.filename.as_ref().map(|s| &**s);
+    }
+
+    /// The part's Content-Type header, if any.  The RFC default for a missing
+    /// one is "text/plain".
+    pub fn get_content_type(&self) -> Option<&[u8]> {
+        return self.headers.get(&b"content-type"[..]).map(|v| &v[..]);
+    }
+
+    /// The part body
+    pub fn get_data(&self) -> &'a [u8] {
+        return self.data;
+    }
+}
+
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum MultipartError {
+    /// Content-Type isn't multipart/form-data, or has no usable boundary
+    NotMultipart,
+    /// The body doesn't follow the multipart format
+    Malformed,
+    /// More parts than allowed
+    TooManyParts,
+    /// A part's header block is larger than allowed
+    HeaderTooLarge,
+}
+
+
+/// Parse a multipart/form-data body.
+///
+/// content_type: the request Content-Type header, which has the boundary
+///
+/// max_parts: maximum number of parts
+///
+/// max_header_size: maximum size of each part's header block
+pub fn parse<'a>(content_type: &[u8], body: &'a [u8], max_parts: usize,
+        max_header_size: usize)
+        -> Result<Vec<MultipartPart<'a>>, MultipartError> {
+    let boundary = try!(get_boundary(content_type));
+    let mut delimiter = b"--".to_vec();
+    delimiter.push_all(&boundary);
+    let mut crlf_delimiter = b"\r\n".to_vec();
+    crlf_delimiter.push_all(&delimiter);
+
+    // Skip the preamble.  pos is just past a delimiter from here on.
+    let mut pos = {
+        if body.starts_with(&delimiter) {
+            delimiter.len()
+        } else {
+            match byteutils::memmem(body, &crlf_delimiter) {
+                Some(n) => n + crlf_delimiter.len(),
+                None => return Err(MultipartError::Malformed),
+            }
+        }
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        // The close delimiter ends the body; the epilogue is ignored
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+
+        // Optional whitespace (transport padding) is allowed before CRLF
+        while pos < body.len() && (body[pos] == b' ' || body[pos] == b'\t') {
+            pos += 1;
+        }
+        if !body[pos..].starts_with(b"\r\n") {
+            return Err(MultipartError::Malformed);
+        }
+        pos += 2;
+
+        if parts.len() == max_parts {
+            return Err(MultipartError::TooManyParts);
+        }
+
+        // Header block, which may be empty
+        let mut headers = HashMap::new();
+        if body[pos..].starts_with(b"\r\n") {
+            pos += 2;
+        } else {
+            let header_end = match byteutils::memmem(&body[pos..], b"\r\n\r\n") {
+                Some(n) => n,
+                None => {
+                    if body.len() - pos > max_header_size {
+                        return Err(MultipartError::HeaderTooLarge);
+                    }
+                    return Err(MultipartError::Malformed);
+                },
+            };
+            if header_end > max_header_size {
+                return Err(MultipartError::HeaderTooLarge);
+            }
+            let header_block = &body[pos .. pos + header_end + 2];
+            for line in byteutils::split_bytes_on_crlf(header_block) {
+                let ret = http_request::parse_header_into(&mut headers, b"",
+                        line);
+                if ret.is_err() {
+                    return Err(MultipartError::Malformed);
+                }
+            }
+            pos += header_end + 4;
+        }
+
+        // Data runs until the next delimiter
+        let data_len = match byteutils::memmem(&body[pos..], &crlf_delimiter) {
+            Some(n) => n,
+            None => return Err(MultipartError::Malformed),
+        };
+        let data = &body[pos .. pos + data_len];
+        pos += data_len + crlf_delimiter.len();
+
+        let mut name = None;
+        let mut filename = None;
+        match headers.get(&b"content-disposition"[..]) {
+            Some(val) => {
+                for (k, v) in parse_header_params(val).into_iter() {
+                    let v = String::from_utf8_lossy(&v).into_owned();
+                    if k == b"name" {
+                        name = Some(v);
+                    } else if k == b"filename" {
+                        filename = Some(v);
+                    }
+                }
+            },
+            None => {},
+        }
+
+        parts.push(MultipartPart {
+            headers: headers,
+            name: name,
+            filename: filename,
+            data: data,
+        });
+    }
+}
+
+
+// Return the boundary from a multipart/form-data Content-Type value
+fn get_boundary(content_type: &[u8]) -> Result<Vec<u8>, MultipartError> {
+    let parts = byteutils::split_bytes_on(content_type, b';', 1);
+    let media_type = byteutils::strip(parts[0]).to_vec().into_ascii_lowercase();
+    if media_type != b"multipart/form-data" {
+        return Err(MultipartError::NotMultipart);
+    }
+    for (k, v) in parse_header_params(content_type).into_iter() {
+        // RFC 2046 limits boundaries to 1-70 characters
+        if k == b"boundary" && !v.is_empty() && v.len() <= 70 {
+            return Ok(v);
+        }
+    }
+    return Err(MultipartError::NotMultipart);
+}
+
+
+// Parse the parameters of a header value like
+// `form-data; name="a;b"; filename=c.txt` into (lowercased name, value)
+// pairs.  The first item (before any ';') is skipped.  Quoted values can
+// contain ';' and backslash escapes.
+fn parse_header_params(value: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut ret = Vec::new();
+    let mut pos = match value.iter().position(|c| *c == b';') {
+        Some(n) => n + 1,
+        None => return ret,
+    };
+
+    while pos < value.len() {
+        // name
+        let mut name = Vec::new();
+        while pos < value.len() && value[pos] != b'=' && value[pos] != b';' {
+            name.push(value[pos]);
+            pos += 1;
+        }
+        let name = byteutils::strip(&name).to_vec().into_ascii_lowercase();
+
+        // value, maybe quoted
+        let mut param_value = Vec::new();
+        if pos < value.len() && value[pos] == b'=' {
+            pos += 1;
+            while pos < value.len() && value[pos] == b' ' {
+                pos += 1;
+            }
+            if pos < value.len() && value[pos] == b'"' {
+                pos += 1;
+                while pos < value.len() && value[pos] != b'"' {
+                    if value[pos] == b'\\' && pos + 1 < value.len() {
+                        pos += 1;
+                    }
+                    param_value.push(value[pos]);
+                    pos += 1;
+                }
+                // Skip the close quote, and anything up to the next ';'
+                while pos < value.len() && value[pos] != b';' {
+                    pos += 1;
+                }
+            } else {
+                while pos < value.len() && value[pos] != b';' {
+                    param_value.push(value[pos]);
+                    pos += 1;
+                }
+                param_value = byteutils::strip(&param_value).to_vec();
+            }
+        }
+
+        // skip the ';'
+        pos += 1;
+        if !name.is_empty() {
+            ret.push((name, param_value));
+        }
+    }
+    return ret;
+}
+
+
+#[test]
+fn test_parse() {
+    let ct = b"multipart/form-data; boundary=XyZ";
+    let body = b"preamble\r\n--XyZ\r\n\
+Content-Disposition: form-data; name=\"field1\"\r\n\
+\r\n\
+value1\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"a;b.txt\"\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+line1\r\nline2\r\n\
+--XyZ--\r\nepilogue";
+    let parts = parse(ct, body, 10, 1000).unwrap();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].get_name(), Some("field1"));
+    assert_eq!(parts[0].get_filename(), None);
+    assert_eq!(parts[0].get_content_type(), None);
+    assert_eq!(parts[0].get_data(), b"value1");
+    assert_eq!(parts[1].get_name(), Some("file"));
+    assert_eq!(parts[1].get_filename(), Some("a;b.txt"));
+    assert_eq!(parts[1].get_content_type(), Some(&b"text/plain"[..]));
+    assert_eq!(parts[1].get_data(), b"line1\r\nline2");
+
+    // Quoted boundary, empty part headers and data, no preamble
+    let ct = b"Multipart/Form-Data; charset=utf-8; boundary=\"a b\"";
+    let body = b"--a b\r\n\r\n\r\n--a b--";
+    let parts = parse(ct, body, 10, 1000).unwrap();
+    assert_eq!(parts.len(), 1);
+    assert_eq!(parts[0].get_name(), None);
+    assert_eq!(parts[0].get_data(), b"");
+}
+
+#[test]
+fn test_parse_bad() {
+    let ct = b"multipart/form-data; boundary=XyZ";
+    let body = b"--XyZ\r\nA: b\r\n\r\ndata\r\n--XyZ\r\nA: b\r\n\r\ndata\r\n--XyZ--";
+    assert!(parse(ct, body, 2, 1000).is_ok());
+    assert_eq!(parse(ct, body, 1, 1000).err(),
+        Some(MultipartError::TooManyParts));
+    assert_eq!(parse(ct, body, 2, 3).err(),
+        Some(MultipartError::HeaderTooLarge));
+
+    assert_eq!(parse(b"text/plain; boundary=XyZ", body, 2, 1000).err(),
+        Some(MultipartError::NotMultipart));
+    assert_eq!(parse(b"multipart/form-data", body, 2, 1000).err(),
+        Some(MultipartError::NotMultipart));
+
+    // Missing close delimiter, missing delimiter, bad header
+    let body = b"--XyZ\r\nA: b\r\n\r\ndata";
+    assert_eq!(parse(ct, body, 2, 1000).err(), Some(MultipartError::Malformed));
+    let body = b"no delimiter";
+    assert_eq!(parse(ct, body, 2, 1000).err(), Some(MultipartError::Malformed));
+    let body = b"--XyZ\r\nA b\r\n\r\ndata\r\n--XyZ--";
+    assert_eq!(parse(ct, body, 2, 1000).err(), Some(MultipartError::Malformed));
+}
+
+#[test]
+fn test_header_params() {
+    let p = parse_header_params(b"form-data; Name=\"a\\\"b\" ; x ; filename=f.txt ");
+    assert_eq!(p, vec![(b"name".to_vec(), b"a\"b".to_vec()),
+        (b"x".to_vec(), b"".to_vec()),
+        (b"filename".to_vec(), b"f.txt".to_vec())]);
+    assert!(parse_header_params(b"form-data").is_empty());
+}
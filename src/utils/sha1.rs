@@ -0,0 +1,84 @@
+//! SHA-1 (RFC 3174).  Only for protocols that require it, like the WebSocket
+//! handshake; SHA-1 is broken for security purposes.
+
+
+/// The SHA-1 digest of `data`
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476,
+        0xc3d2e1f0];
+
+    // Pad with 0x80, zeros, and the length in bits, to a multiple of 64
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    for i in 0..8 {
+        message.push((bit_len >> (56 - i * 8)) as u8);
+    }
+
+    let mut w = [0u32; 80];
+    for block in message.chunks(64) {
+        for i in 0..16 {
+            w[i] = ((block[i * 4] as u32) << 24)
+                | ((block[i * 4 + 1] as u32) << 16)
+                | ((block[i * 4 + 2] as u32) << 8)
+                | (block[i * 4 + 3] as u32);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in 0..80 {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (!b & d), 0x5a827999),
+                20...39 => (b ^ c ^ d, 0x6ed9eba1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e)
+                .wrapping_add(k).wrapping_add(w[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut ret = [0u8; 20];
+    for i in 0..20 {
+        ret[i] = (h[i / 4] >> (24 - (i % 4) * 8)) as u8;
+    }
+    return ret;
+}
+
+
+#[cfg(test)]
+fn hex(digest: &[u8]) -> String {
+    let mut ret = String::new();
+    for b in digest.iter() {
+        ret.push_str(&format!("{:02x}", b));
+    }
+    return ret;
+}
+
+
+#[test]
+fn test_sha1() {
+    assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(hex(&sha1(b"abc")),
+        "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(hex(&sha1(
+        b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+        "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+    assert_eq!(hex(&sha1(&vec![b'a'; 1000000])),
+        "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+}
@@ -0,0 +1,116 @@
+//! Parsing of query strings and application/x-www-form-urlencoded bodies
+
+use super::byteutils;
+
+
+/// Ordered, multi-valued key/value pairs from a query string or form body.
+///
+/// Keys and values are stored as raw (decoded) bytes, since nothing requires
+/// them to be UTF-8.  The `&str` lookups decode values as utf8 (lossy); use
+/// the `_bytes` variants for the exact bytes.
+pub struct FormData {
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl FormData {
+    /// Parse "k=v&k2=v2" style input.  '+' is decoded as a space, then %XX
+    /// escapes are decoded.  Empty pairs ("&&") are skipped, and a pair
+    /// without '=' has an empty value.
+    pub fn parse(input: &[u8]) -> FormData {
+        let mut pairs = Vec::new();
+        for pair in input.split(|c| *c == b'&') {
+            if pair.is_empty() {
+                continue;
+            }
+            let parts = byteutils::split_bytes_on(pair, b'=', 1);
+            let key = decode_component(parts[0]);
+            let value = match parts.len() {
+                2 => decode_component(parts[1]),
+                _ => Vec::new(),
+            };
+            pairs.push((key, value));
+        }
+        return FormData { pairs: pairs };
+    }
+
+    /// First value for key, or None
+    pub fn get(&self, key: &str) -> Option<String> {
+        return self.get_bytes(key.as_bytes()).map(|v| {
+            String::from_utf8_lossy(v).into_owned()
+        });
+    }
+
+    /// All values for key, in order
+    pub fn get_all(&self, key: &str) -> Vec<String> {
+        let mut ret = Vec::new();
+        for v in self.get_all_bytes(key.as_bytes()) {
+            ret.push(String::from_utf8_lossy(v).into_owned());
+        }
+        return ret;
+    }
+
+    /// First value for key, as raw bytes, or None
+    pub fn get_bytes(&self, key: &[u8]) -> Option<&[u8]> {
+        for &(ref k, ref v) in self.pairs.iter() {
+            if &**k == key {
+                return Some(v);
+            }
+        }
+        return None;
+    }
+
+    /// All values for key, as raw bytes, in order
+    pub fn get_all_bytes(&self, key: &[u8]) -> Vec<&[u8]> {
+        let mut ret = Vec::new();
+        for &(ref k, ref v) in self.pairs.iter() {
+            if &**k == key {
+                ret.push(&v[..]);
+            }
+        }
+        return ret;
+    }
+
+    /// All (key, value) pairs, in order
+    pub fn get_pairs(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        return &self.pairs;
+    }
+}
+
+
+// '+' means space in this encoding (only), then percent decode
+fn decode_component(input: &[u8]) -> Vec<u8> {
+    let mut plus_decoded = input.to_vec();
+    for c in plus_decoded.iter_mut() {
+        if *c == b'+' {
+            *c = b' ';
+        }
+    }
+    return byteutils::percent_decode(&plus_decoded);
+}
+
+
+#[test]
+fn test_parse() {
+    let f = FormData::parse(b"a=1&b=hello+world&a=2&&c&d=%2B%26%3d&=e");
+    assert_eq!(f.get_pairs().len(), 6);
+    assert_eq!(f.get("a").unwrap(), "1");
+    assert_eq!(f.get_all("a"), vec!["1".to_string(), "2".to_string()]);
+    assert_eq!(f.get("b").unwrap(), "hello world");
+    assert_eq!(f.get("c").unwrap(), "");
+    assert_eq!(f.get("d").unwrap(), "+&=");
+    assert_eq!(f.get("").unwrap(), "e");
+    assert!(f.get("z").is_none());
+    assert!(f.get_all("z").is_empty());
+
+    let f = FormData::parse(b"");
+    assert_eq!(f.get_pairs().len(), 0);
+}
+
+#[test]
+fn test_parse_bytes() {
+    // Non UTF-8 is preserved exactly in the byte accessors
+    let f = FormData::parse(b"k%ff=%fe%00&k%ff=x=y");
+    assert_eq!(f.get_bytes(b"k\xff").unwrap(), b"\xfe\x00");
+    assert_eq!(f.get_all_bytes(b"k\xff"), vec![&b"\xfe\x00"[..], &b"x=y"[..]]);
+    assert_eq!(f.get_pairs()[0].0, b"k\xff".to_vec());
+}
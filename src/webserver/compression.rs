@@ -0,0 +1,339 @@
+//! Response compression: Content-Encoding gzip or deflate, negotiated with
+//! the request's Accept-Encoding header
+
+use std::ascii::AsciiExt;
+
+use super::{WebRequest, WebResponse, ResponseBody};
+use super::conditional;
+use super::conditional::Precondition;
+use utils::byteutils;
+use utils::deflate;
+use utils::etag::ETag;
+
+// Content types (besides text/*, */*+json and */*+xml) worth compressing.
+// Most other types (images, video, archives) are already compressed.
+static COMPRESSIBLE_TYPES: &'static [&'static str] = &[
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "application/x-javascript",
+    "application/wasm",
+];
+
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    fn name(&self) -> &'static str {
+        return match *self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        };
+    }
+}
+
+
+// Compress the response body, if:
+//
+// * compression is enabled (globally, or for this response)
+// * it's a 2xx response (other than 204 and 206), with an in-memory body of
+//   at least min_size bytes
+// * the Content-Type is compressible, and there's no Content-Encoding yet
+// * the client accepts gzip or deflate
+//
+// Responses that could have been compressed get "Vary: Accept-Encoding",
+// whether or not this client got them compressed.  A compressed response's
+// ETag gets the coding appended, since it's a different representation.
+//
+// A HEAD response is compressed the same as GET, so its headers (including
+// the Content-Length) match; only sending the body is skipped.
+//
+// This runs before the preconditions are applied, but leaves the body alone
+// if it would be replaced by a 304 or 412 anyway.
+pub fn apply_compression(req: &WebRequest, response: &mut WebResponse,
+        enabled: bool, min_size: usize) {
+    let enabled = response.compression.unwrap_or(enabled);
+    if !enabled || response.code < 200 || response.code > 299
+            || response.code == 204 || response.code == 206 {
+        return;
+    }
+    if response.get_header("Content-Encoding").is_some()
+            || response.get_header("Transfer-Encoding").is_some()
+            || response.get_header("Content-Range").is_some() {
+        return;
+    }
+    let compressible = match response.get_header("Content-Type") {
+        Some(val) => is_compressible(val),
+        None => false,
+    };
+    let len = match response.body {
+        ResponseBody::Bytes(ref body) => body.len(),
+        _ => return,
+    };
+    if !compressible || len < min_size {
+        return;
+    }
+
+    add_vary(response);
+    let coding = match req.get_environ_value(b"http_accept-encoding") {
+        Some(val) => choose_coding(&val),
+        None => None,
+    };
+    let coding = match coding {
+        Some(coding) => coding,
+        None => return,
+    };
+
+    // ex: "abc" -> "abc-gzip"
+    let etag = response.get_header("ETag")
+        .and_then(|val| ETag::parse(val.as_bytes()));
+    match etag {
+        Some(etag) => {
+            let weak = if etag.is_weak() { "W/" } else { "" };
+            response.set_header("ETag", &format!("{}\"{}-{}\"", weak,
+                        etag.get_tag(), coding.name()));
+        },
+        None => {},
+    }
+
+    let (etag, last_modified) = conditional::get_validators(response);
+    if conditional::evaluate(req, true, etag.as_ref(), last_modified)
+            != Precondition::Pass {
+        return;
+    }
+    let compressed = match response.body {
+        ResponseBody::Bytes(ref body) => {
+            match coding {
+                Coding::Gzip => deflate::gzip(body),
+                Coding::Deflate => deflate::zlib(body),
+            }
+        },
+        _ => return,
+    };
+    response.set_header("Content-Encoding", coding.name());
+    response.body = ResponseBody::Bytes(compressed);
+}
+
+
+fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap().trim()
+        .to_ascii_lowercase();
+    return mime.starts_with("text/") || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || COMPRESSIBLE_TYPES.iter().any(|t| *t == mime);
+}
+
+
+// Add Accept-Encoding to the Vary header, unless it's already there
+fn add_vary(response: &mut WebResponse) {
+    let vary = response.get_header("Vary").map(|val| val.to_string());
+    match vary {
+        Some(val) => {
+            let present = val.split(',').any(|name| {
+                let name = name.trim();
+                name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+            });
+            if !present {
+                response.set_header("Vary",
+                        &format!("{}, Accept-Encoding", val));
+            }
+        },
+        None => response.set_header("Vary", "Accept-Encoding"),
+    }
+}
+
+
+// The coding the client prefers, by q-value, or None if it accepts neither.
+// gzip wins ties, since it's the one everything supports.
+pub fn choose_coding(accept_encoding: &[u8]) -> Option<Coding> {
+    let mut gzip_q = None;
+    let mut deflate_q = None;
+    let mut any_q = None;
+    for item in accept_encoding.split(|c| *c == b',') {
+        let mut parts = item.split(|c| *c == b';');
+        let name = byteutils::strip(parts.next().unwrap())
+            .to_ascii_lowercase();
+        let mut q = Some(1000);
+        for param in parts {
+            let param = byteutils::strip(param);
+            if param.len() >= 2 && (param[0] == b'q' || param[0] == b'Q')
+                    && param[1] == b'=' {
+                q = parse_qvalue(byteutils::strip(&param[2..]));
+            }
+        }
+        let q = match q {
+            Some(q) => q,
+            None => continue,
+        };
+        if &name[..] == &b"gzip"[..] || &name[..] == &b"x-gzip"[..] {
+            gzip_q = Some(q);
+        } else if &name[..] == &b"deflate"[..] {
+            deflate_q = Some(q);
+        } else if &name[..] == &b"*"[..] {
+            any_q = Some(q);
+        }
+    }
+
+    // A coding that isn't listed gets the q-value of "*", if any
+    let gzip_q = gzip_q.or(any_q).unwrap_or(0);
+    let deflate_q = deflate_q.or(any_q).unwrap_or(0);
+    if gzip_q > 0 && gzip_q >= deflate_q {
+        return Some(Coding::Gzip);
+    }
+    if deflate_q > 0 {
+        return Some(Coding::Deflate);
+    }
+    return None;
+}
+
+
+// Parse a q-value ("0", "0.5", "1.000", ...) into thousandths
+fn parse_qvalue(val: &[u8]) -> Option<u32> {
+    let (int, frac) = match val.iter().position(|c| *c == b'.') {
+        Some(idx) => (&val[..idx], &val[idx + 1..]),
+        None => (val, &b""[..]),
+    };
+    if (int != &b"0"[..] && int != &b"1"[..]) || frac.len() > 3
+            || !frac.iter().all(|c| *c >= b'0' && *c <= b'9') {
+        return None;
+    }
+    let mut ret = if int == &b"1"[..] { 1000 } else { 0 };
+    let mut scale = 100;
+    for c in frac.iter() {
+        ret += (*c - b'0') as u32 * scale;
+        scale /= 10;
+    }
+    if ret > 1000 {
+        return None;
+    }
+    return Some(ret);
+}
+
+
+#[cfg(test)]
+fn json_response() -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_header("Content-Type", "application/json");
+    resp.set_etag(&ETag::strong("v1"));
+    let mut body = String::new();
+    for _ in 0..200 {
+        body.push_str("[1, 2, 3, 4]");
+    }
+    resp.set_body_str(&body);
+    return resp;
+}
+
+
+#[test]
+fn test_choose_coding() {
+    assert_eq!(choose_coding(b"gzip, deflate, br"), Some(Coding::Gzip));
+    assert_eq!(choose_coding(b"deflate"), Some(Coding::Deflate));
+    assert_eq!(choose_coding(b"gzip;q=0.5, deflate"), Some(Coding::Deflate));
+    assert_eq!(choose_coding(b"deflate;q=0.5, gzip;q=0.5"),
+        Some(Coding::Gzip));
+    assert_eq!(choose_coding(b"x-gzip"), Some(Coding::Gzip));
+    assert_eq!(choose_coding(b"*"), Some(Coding::Gzip));
+    assert_eq!(choose_coding(b"*;q=0.2, gzip;q=0"), Some(Coding::Deflate));
+    assert_eq!(choose_coding(b"gzip;q=0, deflate;q=0.000"), None);
+    assert_eq!(choose_coding(b"identity, br"), None);
+    assert_eq!(choose_coding(b""), None);
+    // Invalid q-values are ignored
+    assert_eq!(choose_coding(b"gzip;q=2, deflate;q=0.1"),
+        Some(Coding::Deflate));
+    assert_eq!(parse_qvalue(b"0.125"), Some(125));
+    assert_eq!(parse_qvalue(b"1."), Some(1000));
+    assert_eq!(parse_qvalue(b"1.001"), None);
+    assert_eq!(parse_qvalue(b"0.1234"), None);
+}
+
+#[test]
+fn test_apply_compression() {
+    let req = WebRequest::for_test("get", "/", &[("accept-encoding", "gzip")]);
+    let mut resp = json_response();
+    apply_compression(&req, &mut resp, true, 1024);
+    assert_eq!(resp.get_header("Content-Encoding"), Some("gzip"));
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Encoding"));
+    assert_eq!(resp.get_header("ETag"), Some("\"v1-gzip\""));
+    match resp.body {
+        ResponseBody::Bytes(ref body) => {
+            assert_eq!(&body[..2], &[0x1f, 0x8b]);
+            assert!(body.len() < 100);
+        },
+        _ => panic!("not bytes"),
+    }
+
+    let req = WebRequest::for_test("get", "/",
+            &[("accept-encoding", "deflate")]);
+    let mut resp = json_response();
+    resp.set_header("Vary", "Cookie");
+    apply_compression(&req, &mut resp, true, 1024);
+    assert_eq!(resp.get_header("Content-Encoding"), Some("deflate"));
+    assert_eq!(resp.get_header("Vary"), Some("Cookie, Accept-Encoding"));
+
+    // HEAD gets the same headers as GET, and a body of the same length,
+    // which sets the Content-Length even though it isn't sent
+    let gzip = [("accept-encoding", "gzip")];
+    let mut get_resp = json_response();
+    apply_compression(&WebRequest::for_test("get", "/", &gzip), &mut get_resp,
+            true, 1024);
+    let mut head_resp = json_response();
+    apply_compression(&WebRequest::for_test("head", "/", &gzip),
+            &mut head_resp, true, 1024);
+    assert_eq!(head_resp.get_headers(), get_resp.get_headers());
+    match (&head_resp.body, &get_resp.body) {
+        (&ResponseBody::Bytes(ref head), &ResponseBody::Bytes(ref get)) => {
+            assert_eq!(head.len(), get.len());
+        },
+        _ => panic!("not bytes"),
+    }
+}
+
+#[test]
+fn test_no_compression() {
+    let gzip = [("accept-encoding", "gzip")];
+
+    // Client doesn't accept it: still Vary
+    let req = WebRequest::for_test("get", "/", &[]);
+    let mut resp = json_response();
+    apply_compression(&req, &mut resp, true, 1024);
+    assert!(resp.get_header("Content-Encoding").is_none());
+    assert_eq!(resp.get_header("Vary"), Some("Accept-Encoding"));
+    assert_eq!(resp.get_header("ETag"), Some("\"v1\""));
+
+    // Disabled, too small, or not a compressible type
+    let req = WebRequest::for_test("get", "/", &gzip);
+    let mut resp = json_response();
+    apply_compression(&req, &mut resp, false, 1024);
+    assert!(resp.get_header("Vary").is_none());
+    let mut resp = json_response();
+    resp.set_compression(false);
+    apply_compression(&req, &mut resp, true, 1024);
+    assert!(resp.get_header("Vary").is_none());
+    let mut resp = json_response();
+    apply_compression(&req, &mut resp, true, 100000);
+    assert!(resp.get_header("Vary").is_none());
+    let mut resp = json_response();
+    resp.set_header("Content-Type", "image/png");
+    apply_compression(&req, &mut resp, true, 1024);
+    assert!(resp.get_header("Vary").is_none());
+
+    // Enabled for just this response
+    let mut resp = json_response();
+    resp.set_compression(true);
+    apply_compression(&req, &mut resp, false, 1024);
+    assert_eq!(resp.get_header("Content-Encoding"), Some("gzip"));
+
+    // The body will be replaced by a 304, so isn't compressed
+    let req = WebRequest::for_test("get", "/", &[("accept-encoding", "gzip"),
+        ("if-none-match", "\"v1-gzip\"")]);
+    let mut resp = json_response();
+    apply_compression(&req, &mut resp, true, 1024);
+    assert!(resp.get_header("Content-Encoding").is_none());
+    assert_eq!(resp.get_header("ETag"), Some("\"v1-gzip\""));
+    conditional::apply_preconditions(&req, &mut resp);
+    assert_eq!(resp.code, 304);
+}
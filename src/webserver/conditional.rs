@@ -0,0 +1,327 @@
+//! Conditional requests (RFC 7232): If-Match, If-None-Match,
+//! If-Modified-Since, If-Unmodified-Since, and If-Range
+
+use super::{WebRequest, WebResponse, ResponseBody};
+use utils::byteutils;
+use utils::etag;
+use utils::etag::ETag;
+use utils::httpdate;
+
+
+// The outcome of evaluating the request preconditions
+#[derive(Debug, PartialEq)]
+pub enum Precondition {
+    Pass,
+    NotModified,
+    Failed,
+}
+
+
+// Evaluate the request preconditions against the current state of the
+// resource, in the order RFC 7232 section 6 specifies.
+//
+// exists: whether the resource currently exists (If-Match: * and
+// If-None-Match: * test this)
+pub fn evaluate(req: &WebRequest, exists: bool, etag: Option<&ETag>,
+        last_modified: Option<u64>) -> Precondition {
+    let is_get_or_head = req.method == "get" || req.method == "head";
+
+    match req.get_environ_value(b"http_if-match") {
+        Some(val) => {
+            if !matches_any(&val, exists, etag, true) {
+                return Precondition::Failed;
+            }
+        },
+        None => {
+            let since = req.get_environ_value(b"http_if-unmodified-since")
+                .and_then(|val| httpdate::parse_http_date(&val));
+            match (since, last_modified) {
+                (Some(since), Some(last_modified)) => {
+                    if last_modified > since {
+                        return Precondition::Failed;
+                    }
+                },
+                _ => {},
+            }
+        },
+    }
+
+    match req.get_environ_value(b"http_if-none-match") {
+        Some(val) => {
+            if matches_any(&val, exists, etag, false) {
+                if is_get_or_head {
+                    return Precondition::NotModified;
+                }
+                return Precondition::Failed;
+            }
+        },
+        None => {
+            let since = req.get_environ_value(b"http_if-modified-since")
+                .and_then(|val| httpdate::parse_http_date(&val));
+            match (since, last_modified) {
+                (Some(since), Some(last_modified)) => {
+                    if is_get_or_head && last_modified <= since {
+                        return Precondition::NotModified;
+                    }
+                },
+                _ => {},
+            }
+        },
+    }
+    return Precondition::Pass;
+}
+
+
+// With WebServer::set_auto_etag: add a strong ETag, from a hash of the body,
+// to a 200 response to GET or HEAD with an in-memory body and no ETag.
+pub fn add_auto_etag(req: &WebRequest, response: &mut WebResponse) {
+    if response.code != 200 || (req.method != "get" && req.method != "head")
+            || response.get_header("ETag").is_some() {
+        return;
+    }
+    let tag = match response.body {
+        ResponseBody::Bytes(ref body) => format!("{:016x}", fnv1a_64(body)),
+        _ => return,
+    };
+    response.set_etag(&ETag::strong(&tag));
+}
+
+
+// After the handler runs: turn a 2xx response to GET or HEAD into a 304 or
+// 412 if the request preconditions say so.  Other methods are left alone:
+// by now the handler has already acted, so answering 412 would tell the
+// client nothing happened when it did.  Handlers for unsafe methods must
+// check with evaluate (WebRequest::check_preconditions) before acting.
+pub fn apply_preconditions(req: &WebRequest, response: &mut WebResponse) {
+    if response.code < 200 || response.code > 299 {
+        return;
+    }
+    if req.method != "get" && req.method != "head" {
+        return;
+    }
+    let (etag, last_modified) = get_validators(response);
+    match evaluate(req, true, etag.as_ref(), last_modified) {
+        Precondition::Pass => {},
+        Precondition::NotModified => {
+            response.set_code(304, "Not Modified");
+            response.set_body(b"");
+            response.remove_header("Content-Type");
+        },
+        Precondition::Failed => {
+            response.set_code(412, "Precondition Failed");
+            response.set_body_str("Error 412: Precondition Failed");
+            response.remove_header("Content-Type");
+        },
+    }
+}
+
+
+// Whether a Range request should get ranges, rather than the whole body:
+// true if there's no If-Range, or it matches the response validators.
+pub fn if_range_matches(req: &WebRequest, response: &WebResponse) -> bool {
+    let val = match req.get_environ_value(b"http_if-range") {
+        Some(val) => val,
+        None => return true,
+    };
+    let val = byteutils::strip(&val);
+    let (etag, last_modified) = get_validators(response);
+    if val.starts_with(b"\"") || val.starts_with(b"W/") {
+        return match (ETag::parse(val), etag) {
+            (Some(ref a), Some(ref b)) => a.strong_eq(b),
+            _ => false,
+        };
+    }
+    return match (httpdate::parse_http_date(val), last_modified) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    };
+}
+
+
+// The 304 response for WebRequest::check_preconditions
+pub fn not_modified(etag: Option<&ETag>, last_modified: Option<u64>)
+        -> WebResponse {
+    let mut ret = WebResponse::new();
+    ret.set_code(304, "Not Modified");
+    match etag {
+        Some(etag) => ret.set_etag(etag),
+        None => {},
+    }
+    match last_modified {
+        Some(secs) => ret.set_last_modified(secs),
+        None => {},
+    }
+    return ret;
+}
+
+
+pub fn precondition_failed() -> WebResponse {
+    let mut ret = WebResponse::new();
+    ret.set_code(412, "Precondition Failed");
+    ret.set_body_str("Error 412: Precondition Failed");
+    return ret;
+}
+
+
+// The ETag and Last-Modified headers of the response, if set and valid
+pub fn get_validators(response: &WebResponse) -> (Option<ETag>, Option<u64>) {
+    let etag = response.get_header("ETag")
+        .and_then(|val| ETag::parse(val.as_bytes()));
+    let last_modified = response.get_header("Last-Modified")
+        .and_then(|val| httpdate::parse_http_date(val.as_bytes()));
+    return (etag, last_modified);
+}
+
+
+// Does the If-Match / If-None-Match value match the current etag?
+fn matches_any(header: &[u8], exists: bool, etag: Option<&ETag>,
+        strong: bool) -> bool {
+    if byteutils::strip(header) == b"*" {
+        return exists;
+    }
+    let etag = match etag {
+        Some(etag) => etag,
+        None => return false,
+    };
+    let list = match etag::parse_etag_list(header) {
+        Some(list) => list,
+        None => return false,
+    };
+    for tag in list.iter() {
+        if strong && tag.strong_eq(etag) {
+            return true;
+        }
+        if !strong && tag.weak_eq(etag) {
+            return true;
+        }
+    }
+    return false;
+}
+
+
+// 64 bit FNV-1a hash.  Not cryptographic, but plenty for telling versions of
+// a body apart.
+fn fnv1a_64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for c in data.iter() {
+        hash ^= *c as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+
+#[cfg(test)]
+fn check(method: &str, headers: &[(&str, &str)]) -> Precondition {
+    // Last-Modified: Sun, 06 Nov 1994 08:49:37 GMT
+    let etag = ETag::strong("v1");
+    let req = WebRequest::for_test(method, "/", headers);
+    return evaluate(&req, true, Some(&etag), Some(784111777));
+}
+
+
+#[test]
+fn test_evaluate() {
+    assert_eq!(check("get", &[]), Precondition::Pass);
+
+    assert_eq!(check("put", &[("if-match", "\"v1\"")]), Precondition::Pass);
+    assert_eq!(check("put", &[("if-match", "\"v0\", \"v1\"")]),
+        Precondition::Pass);
+    assert_eq!(check("put", &[("if-match", "*")]), Precondition::Pass);
+    assert_eq!(check("put", &[("if-match", "W/\"v1\"")]),
+        Precondition::Failed);
+    assert_eq!(check("put", &[("if-match", "\"v2\"")]), Precondition::Failed);
+
+    assert_eq!(check("get", &[("if-none-match", "W/\"v1\"")]),
+        Precondition::NotModified);
+    assert_eq!(check("get", &[("if-none-match", "*")]),
+        Precondition::NotModified);
+    assert_eq!(check("put", &[("if-none-match", "\"v1\"")]),
+        Precondition::Failed);
+    assert_eq!(check("get", &[("if-none-match", "\"v2\"")]),
+        Precondition::Pass);
+
+    let old = "Sat, 05 Nov 1994 08:49:37 GMT";
+    let same = "Sun, 06 Nov 1994 08:49:37 GMT";
+    assert_eq!(check("get", &[("if-modified-since", same)]),
+        Precondition::NotModified);
+    assert_eq!(check("get", &[("if-modified-since", old)]),
+        Precondition::Pass);
+    assert_eq!(check("get", &[("if-modified-since", "junk")]),
+        Precondition::Pass);
+    assert_eq!(check("post", &[("if-modified-since", same)]),
+        Precondition::Pass);
+    assert_eq!(check("put", &[("if-unmodified-since", old)]),
+        Precondition::Failed);
+    assert_eq!(check("put", &[("if-unmodified-since", same)]),
+        Precondition::Pass);
+
+    // If-None-Match overrides If-Modified-Since, and If-Match overrides
+    // If-Unmodified-Since
+    assert_eq!(check("get", &[("if-none-match", "\"v2\""),
+        ("if-modified-since", same)]), Precondition::Pass);
+    assert_eq!(check("put", &[("if-match", "\"v1\""),
+        ("if-unmodified-since", old)]), Precondition::Pass);
+
+    // Resource doesn't exist
+    let req = WebRequest::for_test("put", "/", &[("if-none-match", "*")]);
+    assert_eq!(evaluate(&req, false, None, None), Precondition::Pass);
+    let req = WebRequest::for_test("put", "/", &[("if-match", "*")]);
+    assert_eq!(evaluate(&req, false, None, None), Precondition::Failed);
+}
+
+#[test]
+fn test_apply_preconditions() {
+    let mut resp = WebResponse::new();
+    resp.set_body_str("hello");
+    add_auto_etag(&WebRequest::for_test("get", "/", &[]), &mut resp);
+    apply_preconditions(&WebRequest::for_test("get", "/", &[]), &mut resp);
+    let etag = resp.get_header("ETag").unwrap().to_string();
+    assert_eq!(etag, format!("\"{:016x}\"", fnv1a_64(b"hello")));
+    assert_eq!(resp.code, 200);
+
+    let req = WebRequest::for_test("get", "/", &[("if-none-match", &etag)]);
+    let mut resp = WebResponse::new();
+    resp.set_header("Content-Type", "text/plain");
+    resp.set_body_str("hello");
+    add_auto_etag(&req, &mut resp);
+    apply_preconditions(&req, &mut resp);
+    assert_eq!(resp.code, 304);
+    assert!(resp.get_header("Content-Type").is_none());
+    assert_eq!(resp.get_header("ETag"), Some(&etag[..]));
+
+    // No ETag, or not a 2xx response
+    let mut resp = WebResponse::new();
+    apply_preconditions(&req, &mut resp);
+    assert_eq!(resp.code, 200);
+    let mut resp = WebResponse::new();
+    resp.set_code(404, "Not Found");
+    add_auto_etag(&req, &mut resp);
+    apply_preconditions(&req, &mut resp);
+    assert_eq!(resp.code, 404);
+    assert!(resp.get_header("ETag").is_none());
+
+    // A PUT handler has already acted, so its response is left alone
+    let req = WebRequest::for_test("put", "/", &[("if-match", "\"other\"")]);
+    let mut resp = WebResponse::new();
+    resp.set_etag(&ETag::strong("abc"));
+    apply_preconditions(&req, &mut resp);
+    assert_eq!(resp.code, 200);
+}
+
+#[test]
+fn test_if_range() {
+    let mut resp = WebResponse::new();
+    resp.set_etag(&ETag::strong("v1"));
+    resp.set_last_modified(784111777);
+    let check = |val: &str| {
+        let req = WebRequest::for_test("get", "/", &[("if-range", val)]);
+        if_range_matches(&req, &resp)
+    };
+    assert!(check("\"v1\""));
+    assert!(!check("\"v2\""));
+    assert!(!check("W/\"v1\""));
+    assert!(check("Sun, 06 Nov 1994 08:49:37 GMT"));
+    assert!(!check("Sun, 06 Nov 1994 08:49:38 GMT"));
+    assert!(if_range_matches(&WebRequest::for_test("get", "/", &[]), &resp));
+}
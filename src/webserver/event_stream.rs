@@ -0,0 +1,375 @@
+//! Server-Sent Events: text/event-stream responses that stay open, and
+//! deliver events as they are sent from any thread
+
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::Duration;
+
+use super::WebResponse;
+
+static DEFAULT_HEARTBEAT_SECS: u64 = 15;
+// Events queued for a client that isn't reading them.  Past this, the
+// client is disconnected.
+static MAX_BUFFERED: usize = 1_000_000;
+static HEARTBEAT: &'static [u8] = b": heartbeat\n\n";
+
+
+/// One event in an event stream.  Only the data is required.
+///
+/// ex: `Event::new("42")` with `set_event("count")` is received by the
+/// browser's `EventSource` as a "count" event with data "42".
+pub struct Event {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl Event {
+    /// Create an event with this data.  Data with several lines is sent
+    /// as several `data:` lines, which the client joins back together.
+    pub fn new(data: &str) -> Event {
+        return Event {
+            event: None,
+            data: data.to_string(),
+            id: None,
+            retry: None,
+        };
+    }
+
+    /// Set the event type.  Default is "message".
+    pub fn set_event(&mut self, name: &str) {
+        self.event = Some(name.to_string());
+    }
+
+    /// Set the event id.  A client that reconnects sends the last id it
+    /// got as `Last-Event-ID` (see `WebRequest::get_last_event_id`).
+    pub fn set_id(&mut self, id: &str) {
+        self.id = Some(id.to_string());
+    }
+
+    /// Set how long the client should wait before reconnecting, if the
+    /// connection drops, in milliseconds
+    pub fn set_retry(&mut self, millis: u64) {
+        self.retry = Some(millis);
+    }
+
+    /// The event in text/event-stream format, ending with a blank line
+    pub fn encode(&self) -> String {
+        let mut ret = String::new();
+        // Line breaks would start a new field, so aren't allowed in these
+        match self.event {
+            Some(ref event) => {
+                ret.push_str(&format!("event: {}\n", single_line(event)));
+            },
+            None => {},
+        }
+        match self.id {
+            Some(ref id) => {
+                ret.push_str(&format!("id: {}\n", single_line(id)));
+            },
+            None => {},
+        }
+        match self.retry {
+            Some(retry) => ret.push_str(&format!("retry: {}\n", retry)),
+            None => {},
+        }
+        let data = self.data.replace("\r\n", "\n").replace("\r", "\n");
+        for line in data.split('\n') {
+            ret.push_str("data: ");
+            ret.push_str(line);
+            ret.push('\n');
+        }
+        ret.push('\n');
+        return ret;
+    }
+}
+
+
+fn single_line(val: &str) -> String {
+    return val.chars().filter(|c| *c != '\r' && *c != '\n' && *c != '\0')
+        .collect();
+}
+
+
+// Shared by the senders and the response body
+struct EventQueue {
+    state: Mutex<QueueState>,
+    cvar: Condvar,
+}
+
+struct QueueState {
+    // Encoded events not yet read by the response body
+    data: Vec<u8>,
+    nr_senders: usize,
+    // The client disconnected, or fell too far behind
+    closed: bool,
+    heartbeat: Duration,
+}
+
+
+/// Sends events to one event stream response.  See
+/// `WebResponse::new_event_stream`.
+///
+/// Senders can be cloned and moved to other threads.  The stream ends when
+/// the last one is dropped.
+pub struct EventSender {
+    queue: Arc<EventQueue>,
+}
+
+impl EventSender {
+    /// Queue an event for the client.  Returns false if the client has
+    /// disconnected, in which case the event is dropped.
+    ///
+    /// This doesn't wait for the client.  If a client falls more than
+    /// 1,000,000 bytes behind, it is disconnected.
+    pub fn send(&self, event: &Event) -> bool {
+        return self.send_bytes(event.encode().as_bytes());
+    }
+
+    fn send_bytes(&self, bytes: &[u8]) -> bool {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        if state.data.len() + bytes.len() > MAX_BUFFERED {
+            state.closed = true;
+        } else {
+            state.data.push_all(bytes);
+        }
+        self.queue.cvar.notify_one();
+        return !state.closed;
+    }
+
+    /// Whether the client is still connected, as far as the server knows.
+    /// A disconnect is noticed when a write to the client fails, so at the
+    /// latest by the next heartbeat.
+    pub fn is_connected(&self) -> bool {
+        return !self.queue.state.lock().unwrap().closed;
+    }
+
+    /// Set how often a heartbeat comment is sent while there are no events.
+    /// This keeps proxies from timing out the connection, and detects
+    /// clients that went away.  Default is 15 seconds.
+    pub fn set_heartbeat_interval(&self, interval: Duration) {
+        assert!(interval != Duration::new(0, 0));
+        self.queue.state.lock().unwrap().heartbeat = interval;
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> EventSender {
+        self.queue.state.lock().unwrap().nr_senders += 1;
+        return EventSender { queue: self.queue.clone() };
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.nr_senders -= 1;
+        if state.nr_senders == 0 {
+            self.queue.cvar.notify_one();
+        }
+    }
+}
+
+
+// The response body: blocks until there are events (or a heartbeat is due),
+// and ends when all senders are gone
+struct EventReader {
+    queue: Arc<EventQueue>,
+    // Read from the queue, not yet returned
+    pending: Vec<u8>,
+}
+
+impl io::Read for EventReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.pending.is_empty() {
+            self.pending = try!(self.wait());
+        }
+        let size = if buf.len() < self.pending.len() {
+            buf.len()
+        } else {
+            self.pending.len()
+        };
+        for i in 0..size {
+            buf[i] = self.pending[i];
+        }
+        let rest = self.pending[size..].to_vec();
+        self.pending = rest;
+        return Ok(size);
+    }
+}
+
+impl EventReader {
+    // The next bytes to send, or empty at the end of the stream
+    fn wait(&self) -> Result<Vec<u8>, io::Error> {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.closed {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                        "event stream closed"));
+        }
+        if state.data.is_empty() && state.nr_senders > 0 {
+            let heartbeat = state.heartbeat;
+            state = self.queue.cvar.wait_timeout(state, heartbeat).unwrap().0;
+            if state.closed {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                            "event stream closed"));
+            }
+            // Nothing came, so it's time for a heartbeat.  (A spurious
+            // wakeup just sends an extra one.)
+            if state.data.is_empty() && state.nr_senders > 0 {
+                return Ok(HEARTBEAT.to_vec());
+            }
+        }
+        return Ok(mem::replace(&mut state.data, Vec::new()));
+    }
+}
+
+impl Drop for EventReader {
+    // The response is done (or failed), so the client is gone
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+    }
+}
+
+
+// A text/event-stream response, and the sender for its events
+pub fn new_event_stream() -> (WebResponse, EventSender) {
+    let queue = Arc::new(EventQueue {
+        state: Mutex::new(QueueState {
+            data: Vec::new(),
+            nr_senders: 1,
+            closed: false,
+            heartbeat: Duration::from_secs(DEFAULT_HEARTBEAT_SECS),
+        }),
+        cvar: Condvar::new(),
+    });
+    let reader = EventReader { queue: queue.clone(), pending: Vec::new() };
+    let mut resp = WebResponse::new();
+    resp.set_header("Content-Type", "text/event-stream");
+    resp.set_header("Cache-Control", "no-cache");
+    resp.set_body_reader(reader);
+    return (resp, EventSender { queue: queue });
+}
+
+
+/// Broadcasts events to any number of event streams, e.g. one per browser
+/// watching a dashboard.  Share it between handlers with
+/// `WebServer::set_state`, or an `Arc`.
+///
+/// Disconnected streams are removed the next time an event is published.
+pub struct EventHub {
+    senders: Mutex<Vec<EventSender>>,
+}
+
+impl EventHub {
+    pub fn new() -> EventHub {
+        return EventHub { senders: Mutex::new(Vec::new()) };
+    }
+
+    /// Create an event stream response that gets every event published
+    /// from now on.  Return it from the handler.
+    pub fn subscribe(&self) -> WebResponse {
+        let (resp, sender) = new_event_stream();
+        self.add(sender);
+        return resp;
+    }
+
+    /// Add an existing stream, e.g. after sending it the events it missed
+    /// (see `WebRequest::get_last_event_id`)
+    pub fn add(&self, sender: EventSender) {
+        self.senders.lock().unwrap().push(sender);
+    }
+
+    /// Send an event to every connected stream
+    pub fn publish(&self, event: &Event) {
+        let encoded = event.encode();
+        self.senders.lock().unwrap()
+            .retain(|sender| sender.send_bytes(encoded.as_bytes()));
+    }
+
+    /// The number of streams, including any that disconnected since the
+    /// last publish
+    pub fn nr_streams(&self) -> usize {
+        return self.senders.lock().unwrap().len();
+    }
+}
+
+
+#[test]
+fn test_event_encode() {
+    let mut event = Event::new("line 1\nline 2\r\n");
+    event.set_event("up\ndate");
+    event.set_id("7");
+    event.set_retry(5000);
+    assert_eq!(event.encode(), "event: update\nid: 7\nretry: 5000\n\
+data: line 1\ndata: line 2\ndata: \n\n");
+    assert_eq!(Event::new("").encode(), "data: \n\n");
+}
+
+#[test]
+fn test_event_stream() {
+    use std::io::Read;
+    use std::thread;
+
+    let (resp, sender) = new_event_stream();
+    assert_eq!(resp.get_header("Content-Type"), Some("text/event-stream"));
+    let mut reader = match resp.body {
+        super::ResponseBody::Stream(reader, None) => reader,
+        _ => panic!("not a stream"),
+    };
+    let hub = EventHub::new();
+    hub.add(sender.clone());
+    sender.set_heartbeat_interval(Duration::from_millis(10));
+
+    hub.publish(&Event::new("a"));
+    let t = thread::spawn(move || {
+        sender.send(&Event::new("b"));
+    });
+    t.join().unwrap();
+    let mut buf = [0u8; 100];
+    let mut out = Vec::new();
+    while out.len() < 18 {
+        let size = reader.read(&mut buf).unwrap();
+        out.push_all(&buf[..size]);
+    }
+    assert_eq!(&out[..], &b"data: a\n\ndata: b\n\n"[..]);
+
+    // Nothing to send, so a heartbeat
+    let size = reader.read(&mut buf).unwrap();
+    assert_eq!(&buf[..size], HEARTBEAT);
+
+    // The client went away
+    drop(reader);
+    hub.publish(&Event::new("c"));
+    assert_eq!(hub.nr_streams(), 0);
+}
+
+#[test]
+fn test_event_stream_end() {
+    use std::io::Read;
+
+    let (resp, sender) = new_event_stream();
+    let mut reader = match resp.body {
+        super::ResponseBody::Stream(reader, None) => reader,
+        _ => panic!("not a stream"),
+    };
+    assert!(sender.send(&Event::new(&vec!["x"; 600_000].concat())));
+    assert!(!sender.send(&Event::new(&vec!["x"; 600_000].concat())));
+    assert!(!sender.is_connected());
+    assert!(reader.read(&mut [0u8; 10]).is_err());
+
+    let (resp, sender) = new_event_stream();
+    let mut reader = match resp.body {
+        super::ResponseBody::Stream(reader, None) => reader,
+        _ => panic!("not a stream"),
+    };
+    sender.send(&Event::new("last"));
+    drop(sender);
+    let mut out = String::new();
+    reader.read_to_string(&mut out).unwrap();
+    assert_eq!(out, "data: last\n\n");
+}
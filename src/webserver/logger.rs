@@ -17,12 +17,38 @@ impl Logger {
         }
     }
 
+    pub fn log_tls_error(&self, e: io::Error) {
+        if self.logging_enabled {
+            println!("Error in TLS handshake: {}", e);
+        }
+    }
+
     pub fn log_read_request_error(&self, e: io::Error) {
         if self.logging_enabled {
             println!("Error when reading request: {}", e);
         }
     }
 
+    pub fn log_write_response_error(&self, e: io::Error) {
+        if self.logging_enabled {
+            println!("Error when writing response: {}", e);
+        }
+    }
+
+    // Timeouts are expected from slow or stalled clients, so are logged
+    // apart from other I/O errors
+    pub fn log_timeout(&self, what: &str) {
+        if self.logging_enabled {
+            println!("Timeout when {}", what);
+        }
+    }
+
+    pub fn log_invalid_response(&self, reason: &str) {
+        if self.logging_enabled {
+            println!("Invalid response from handler: {}", reason);
+        }
+    }
+
     // TODO: Better machine parsable output
     pub fn log_request_response(&self, method: &str, path: &str,
             code: i32, response_body_len: usize) {
@@ -1,28 +1,99 @@
 use std::env;
+use std::io;
+use std::mem;
+use std::any::{Any, TypeId};
+use std::borrow::Cow;
+use std::cell::{Cell, Ref, RefCell, RefMut};
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::thread;
 use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::time::Duration;
+use std::ascii::{AsciiExt, OwnedAsciiExt};
 
+use utils;
 use utils::threadpool::ThreadPool;
+use utils::genericsocket;
 use utils::genericsocket::GenericSocket;
+use utils::http_request::{RequestLayout, RequestView};
+use utils::urlencoded::FormData;
+use utils::multipart;
+use utils::multipart::{MultipartPart, MultipartError};
+use utils::cookie::{Cookie, CookieJar};
+use utils::etag::ETag;
+use utils::httpdate;
 use self::write_response::write_response;
 use self::router::{Router, RoutingResult};
 pub use self::logger::Logger;
+pub use self::static_files::StaticFiles;
+pub use self::websocket::{WebSocket, WebSocketMessage, WebSocketError};
+pub use self::event_stream::{Event, EventSender, EventHub};
+pub use self::tls::{TlsAcceptor, TlsInfo};
+#[cfg(feature = "openssl")]
+pub use self::openssl_tls::OpenSslAcceptor;
 
 mod read_request;
 mod write_response;
 mod router;
 mod logger;
+mod static_files;
+mod ranges;
+mod conditional;
+mod compression;
+mod websocket;
+mod event_stream;
+mod tls;
+#[cfg(feature = "openssl")]
+mod openssl_tls;
 
 static DEFAULT_MAX_REQUEST_BODY_SIZE: usize = 1_000_000;
+static DEFAULT_MAX_HEADER_SIZE: usize = 32_768;
+static DEFAULT_MAX_HEADERS: usize = 100;
+static DEFAULT_MAX_HEADER_LINE: usize = 8192;
+static DEFAULT_MAX_URI_LENGTH: usize = 8192;
+static DEFAULT_KEEP_ALIVE_TIMEOUT_SECS: u64 = 5;
+static DEFAULT_HEADER_TIMEOUT_SECS: u64 = 10;
+static DEFAULT_BODY_TIMEOUT_SECS: u64 = 20;
+static DEFAULT_BODY_MIN_RATE: u64 = 500;
+static DEFAULT_WRITE_TIMEOUT_SECS: u64 = 30;
+static DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 100;
+static DEFAULT_COMPRESSION_MIN_SIZE: usize = 1024;
 
 
 /// A response that will be sent to the client (code, headers, body)
 pub struct WebResponse {
     code: i32,
     status: String, 
-    body: Vec<u8>,
-    headers: HashMap<String, String>,
+    body: ResponseBody,
+    headers: Vec<(String, String)>,
+    // Overrides WebServer::set_compression, if set
+    compression: Option<bool>,
+    // Takes over the connection after a 101 response
+    upgrade: Option<WebSocketFunction>,
+}
+
+// The response body is either fully in memory, or produced while sending
+// (with a length, if known in advance), or read from a seekable source
+enum ResponseBody {
+    Bytes(Vec<u8>),
+    Stream(Box<io::Read>, Option<u64>),
+    Seekable(Box<ReadSeek>, u64),
+}
+
+// Read + Seek, for seekable bodies.  Trait objects can't be upcast, so
+// this also gives access to the plain reader.
+trait ReadSeek: io::Read + io::Seek {
+    fn as_read(&mut self) -> &mut io::Read;
+}
+
+impl<T: io::Read + io::Seek> ReadSeek for T {
+    fn as_read(&mut self) -> &mut io::Read {
+        return self;
+    }
 }
 
 impl WebResponse {
@@ -35,8 +106,10 @@ impl WebResponse {
         return WebResponse {
                 code: 200,
                 status: "OK".to_string(),
-                body: Vec::new(),
-                headers: HashMap::new(),
+                body: ResponseBody::Bytes(Vec::new()),
+                headers: Vec::new(),
+                compression: None,
+                upgrade: None,
             };
     }
 
@@ -55,8 +128,50 @@ impl WebResponse {
         return ret;
     }
 
+    /// Accept a WebSocket handshake, with a 101 response that hands the
+    /// connection over to `handler` once it has been sent.  If the request
+    /// isn't a valid handshake, this returns a 400 or 426 error response
+    /// instead.  See `WebServer::add_websocket`, which is simpler unless the
+    /// handshake needs checking first (e.g. the Origin header, or auth).
+    ///
+    /// Headers can be added to the 101 response, e.g. to select a
+    /// `Sec-WebSocket-Protocol`.
+    pub fn new_websocket(req: &WebRequest, handler: WebSocketFunction)
+            -> WebResponse {
+        return match websocket::handshake(req) {
+            Ok(mut resp) => {
+                resp.upgrade = Some(handler);
+                resp
+            },
+            Err(resp) => resp,
+        };
+    }
+
+    /// Create a Server-Sent Events (`text/event-stream`) response, and the
+    /// sender for its events.  Return the response from the handler, and
+    /// send events from any thread; each is written to the client as soon
+    /// as it is sent.  The response ends when the last sender is dropped.
+    /// See `EventHub` for sending the same events to many clients.
+    ///
+    /// While the stream is open, it occupies a worker thread, which sends a
+    /// heartbeat comment when there haven't been any events for a while
+    /// (see `EventSender::set_heartbeat_interval`).  That is also how a
+    /// client that went away is noticed; `EventSender::send` then returns
+    /// false.
+    pub fn new_event_stream() -> (WebResponse, EventSender) {
+        return event_stream::new_event_stream();
+    }
+
+    /// The HTTP status code
+    pub fn get_code(&self) -> i32 {
+        return self.code;
+    }
+
     /// Set the HTTP status code and message.  The message should contain ASCII
     /// characters only.
+    ///
+    /// If the code isn't 3 digits, or the message contains CR, LF or NUL, a
+    /// 500 error is sent instead (and logged).
     pub fn set_code(&mut self, code: i32, status: &str) {
         self.code = code;
         self.status = status.to_string();
@@ -64,7 +179,39 @@ impl WebResponse {
 
     /// Set the response body
     pub fn set_body(&mut self, body: &[u8]) {
-        self.body = body.to_vec();
+        self.body = ResponseBody::Bytes(body.to_vec());
+    }
+
+    /// Set a streaming response body, which is read from `reader` (until
+    /// EOF) while it is being sent, so it never has to fit in memory.
+    ///
+    /// HTTP/1.1 clients get the body with `Transfer-Encoding: chunked`.
+    /// HTTP/1.0 clients get it delimited by closing the connection.
+    ///
+    /// If `reader` returns an error, the response is cut short and the
+    /// connection is closed, since the headers have already been sent.
+    pub fn set_body_reader<R: io::Read + 'static>(&mut self, reader: R) {
+        self.body = ResponseBody::Stream(Box::new(reader), None);
+    }
+
+    /// Set a streaming response body of exactly `len` bytes, e.g. from a
+    /// file.  Unlike `set_body_reader`, this is sent with a Content-Length,
+    /// and works with persistent connections for all clients.
+    ///
+    /// Only `len` bytes are read.  If `reader` ends early or returns an
+    /// error, the response is cut short and the connection is closed.
+    pub fn set_body_reader_with_length<R: io::Read + 'static>(&mut self,
+            reader: R, len: u64) {
+        self.body = ResponseBody::Stream(Box::new(reader), Some(len));
+    }
+
+    /// Set a response body of `len` bytes that can be read from any
+    /// position, e.g. a `File`.  This is sent like
+    /// `set_body_reader_with_length`, except a 200 response to a GET request
+    /// with a Range header will just send the requested range(s).
+    pub fn set_body_seekable<R: io::Read + io::Seek + 'static>(&mut self,
+            reader: R, len: u64) {
+        self.body = ResponseBody::Seekable(Box::new(reader), len);
     }
 
     /// Set the response body as the UTF-8 encoded bytes from `body`.
@@ -73,21 +220,124 @@ impl WebResponse {
         self.set_body(body.as_bytes());
     }
 
-    /// Set a response header.  If it already exists, it will be overwritten.
-    /// Header names and values should use ASCII/Latin1 characters only.
+    /// Set a response header, replacing any existing values for the name.
+    /// Header names are case-insensitive.  Names and values should use
+    /// ASCII/Latin1 characters only.
+    ///
+    /// Names must be valid HTTP tokens, and values can't contain CR, LF or
+    /// NUL; otherwise a 500 error is sent instead of this response (and
+    /// logged).  This prevents response splitting when a header value comes
+    /// from user input.
+    ///
+    /// If you set a `Transfer-Encoding` header, you are responsible for
+    /// encoding the body; `Content-Length` will not be sent, and the
+    /// connection is closed after the response.
+    ///
+    /// The server sends its own `Content-Length` and `Connection` headers,
+    /// so those set here are dropped, except that `Connection: close`
+    /// closes the connection after the response.
     pub fn set_header(&mut self, name: &str, value: &str) {
-        self.headers.insert(name.to_string(), value.to_string());
+        // Keep the position of the first existing value, if any
+        let existing = self.headers.iter().position(|h| {
+            header_name_eq(&h.0, name)
+        });
+        match existing {
+            Some(idx) => {
+                self.headers[idx].1 = value.to_string();
+                let mut i = idx + 1;
+                while i < self.headers.len() {
+                    if header_name_eq(&self.headers[i].0, name) {
+                        self.headers.remove(i);
+                    } else {
+                        i += 1;
+                    }
+                }
+            },
+            None => self.append_header(name, value),
+        }
+    }
+
+    /// Add a response header, keeping any existing values for the name.  Use
+    /// this for headers that can be repeated, like `Link`.
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Remove all values for a response header (case-insensitive)
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|h| !header_name_eq(&h.0, name));
+    }
+
+    /// The first value for a response header (case-insensitive), or None
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        for &(ref k, ref v) in self.headers.iter() {
+            if header_name_eq(k, name) {
+                return Some(v);
+            }
+        }
+        return None;
+    }
+
+    /// All response headers, as (name, value), in the order they were added.
+    /// This is also the order they are sent in.
+    pub fn get_headers(&self) -> &[(String, String)] {
+        return &self.headers;
+    }
+
+    /// Set the ETag header, which identifies this version of the resource.
+    /// Conditional requests (If-None-Match, If-Match) are evaluated against
+    /// it after the handler returns.
+    pub fn set_etag(&mut self, etag: &ETag) {
+        self.set_header("ETag", &etag.to_header_value());
+    }
+
+    /// Set the Last-Modified header, in seconds since the Unix epoch.
+    /// Conditional requests (If-Modified-Since, If-Unmodified-Since) are
+    /// evaluated against it after the handler returns.
+    pub fn set_last_modified(&mut self, unix_secs: u64) {
+        let date = httpdate::format_http_date(unix_secs);
+        self.set_header("Last-Modified", &date);
+    }
+
+    /// Turn compression on or off for this response, overriding
+    /// `WebServer::set_compression`.  The other conditions for compressing
+    /// (content type, size, what the client accepts) still apply.
+    ///
+    /// To control it for a whole route, call this from the handler, or from
+    /// the `after` hook of middleware added with `add_middleware_prefix`.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = Some(enabled);
+    }
+
+    /// Add a cookie.  Each one is sent as its own Set-Cookie header.
+    pub fn add_cookie(&mut self, cookie: Cookie) {
+        self.append_header("Set-Cookie", &cookie.to_header_value());
     }
 }
 
 
+// HTTP header names are case-insensitive
+fn header_name_eq(a: &str, b: &str) -> bool {
+    return a.eq_ignore_ascii_case(b);
+}
+
+
 /// A request from a client
 ///
 pub struct WebRequest { 
-    environ: HashMap<Vec<u8>, Vec<u8>>,
+    // The request line and headers as received, and where their parts are
+    head: Vec<u8>,
+    layout: RequestLayout,
+    // Built from the head the first time it's asked for.  Until then, it
+    // only holds the keys that don't come from the head (e.g.
+    // remote_address), and header lookups read the head directly.
+    environ: RefCell<HashMap<Vec<u8>, Vec<u8>>>,
+    environ_built: Cell<bool>,
     path: String,
     method: String,
     body: Vec<u8>,
+    params: Vec<(String, String)>,
+    app_state: Option<Arc<AppState>>,
 }
 
 impl WebRequest {
@@ -100,16 +350,30 @@ impl WebRequest {
     /// * path = "/full/path"
     /// * query_string = "k=v&k2=v2" or "" (empty)
     /// * http_xxx = "Header Value".  ex: http_user-agent = "Mozilla Firefox"
+    /// * trailer_xxx = "Trailer Value", for trailer headers sent after a
+    ///   chunked request body.  ex: trailer_content-md5 = "..."
     ///
     /// * remote_address = remote/client IP and port, ex: "1.1.1.1:1234"
+    /// * url_scheme = "http", or "https" for a server started with `run_tls`
+    ///
+    /// For TLS connections, facts about the session (see `TlsInfo`):
+    ///
+    /// * tls_protocol = ex: "TLSv1.2"
+    /// * tls_cipher = ex: "ECDHE-RSA-AES128-GCM-SHA256"
+    /// * tls_peer_subject = ex: "CN=alice", if the client sent a verified
+    ///   certificate
     ///
     /// Note: protocol, method, and header names are lowercased,
     /// since they are defined to be case-insensitive.
     /// 
     /// If the same header name was repeated in the request, the values will be
     /// concatenated, in order received, separated by a comma.
-    pub fn get_environ(&self) -> &HashMap<Vec<u8>, Vec<u8>> {
-        return &self.environ;
+    ///
+    /// The environ is built the first time it's asked for, so requests whose
+    /// handlers don't need it don't pay for copying every header.
+    pub fn get_environ(&self) -> Ref<HashMap<Vec<u8>, Vec<u8>>> {
+        self.build_environ();
+        return self.environ.borrow();
     }
 
     /// The percent decoded and utf8 (lossy) decoded path.
@@ -120,6 +384,14 @@ impl WebRequest {
         return &self.path;
     }
 
+    /// Mutable access to the environment dictionary, e.g. for middleware to
+    /// pass data to the handler.  Note that changing the path or method here
+    /// doesn't affect routing.
+    pub fn get_environ_mut(&mut self) -> RefMut<HashMap<Vec<u8>, Vec<u8>>> {
+        self.build_environ();
+        return self.environ.borrow_mut();
+    }
+
     /// The utf8 (lossy) decoded method, in lowercase.
     ///
     /// For the raw method, see environ[method].  
@@ -132,18 +404,301 @@ impl WebRequest {
     pub fn get_body(&self) -> &[u8] {
         return &self.body;
     }
+
+    /// The parsed query string (environ[query_string]).  Parsed on each call.
+    pub fn get_query(&self) -> FormData {
+        let query_string = self.get_environ_value(b"query_string").unwrap();
+        return FormData::parse(&query_string);
+    }
+
+    /// The parsed body, if the request Content-Type is
+    /// application/x-www-form-urlencoded (e.g. a POSTed HTML form).
+    /// Otherwise, this is empty.  Parsed on each call.
+    pub fn get_form(&self) -> FormData {
+        let content_type = match self.get_environ_value(b"http_content-type") {
+            Some(val) => val.into_owned().into_ascii_lowercase(),
+            None => Vec::new(),
+        };
+        if content_type.starts_with(b"application/x-www-form-urlencoded") {
+            return FormData::parse(&self.body);
+        }
+        return FormData::parse(b"");
+    }
+
+    /// The parts of a multipart/form-data body (e.g. a POSTed HTML form with
+    /// file uploads).  The part data are slices of get_body().  Parsed on
+    /// each call.
+    ///
+    /// max_parts: error if the body has more parts than this
+    ///
+    /// max_header_size: error if a part's headers are larger than this
+    pub fn get_multipart(&self, max_parts: usize, max_header_size: usize)
+            -> Result<Vec<MultipartPart>, MultipartError> {
+        let content_type = match self.get_environ_value(b"http_content-type") {
+            Some(val) => val,
+            None => Cow::Borrowed(&b""[..]),
+        };
+        return multipart::parse(&content_type, &self.body, max_parts,
+                max_header_size);
+    }
+
+    /// The cookies sent by the client (the Cookie header).  Parsed on each
+    /// call.
+    pub fn get_cookies(&self) -> CookieJar {
+        return match self.get_environ_value(b"http_cookie") {
+            Some(val) => CookieJar::parse(&val),
+            None => CookieJar::parse(b""),
+        };
+    }
+
+    /// Evaluate the conditional request headers (If-Match, If-None-Match,
+    /// If-Modified-Since, If-Unmodified-Since) against the current state of
+    /// the resource.  Returns a 304 or 412 response to send instead, or None
+    /// if the request should go ahead.
+    ///
+    /// The server evaluates these for 2xx responses to GET and HEAD after
+    /// the handler runs, using the response's ETag and Last-Modified
+    /// headers.  Handlers for other methods (e.g. PUT, DELETE) must call
+    /// this before changing anything; the server won't check for them.
+    ///
+    /// exists: whether the resource exists now
+    pub fn check_preconditions(&self, exists: bool, etag: Option<&ETag>,
+            last_modified: Option<u64>) -> Option<WebResponse> {
+        return match conditional::evaluate(self, exists, etag, last_modified) {
+            conditional::Precondition::Pass => None,
+            conditional::Precondition::NotModified => {
+                Some(conditional::not_modified(etag, last_modified))
+            },
+            conditional::Precondition::Failed => {
+                Some(conditional::precondition_failed())
+            },
+        };
+    }
+
+    /// The `Last-Event-ID` header, sent by an `EventSource` that is
+    /// reconnecting to an event stream: the id of the last event it got.
+    /// Use it to send the events it missed.  None if not sent (or not
+    /// UTF-8).
+    pub fn get_last_event_id(&self) -> Option<String> {
+        return match self.get_environ_value(b"http_last-event-id") {
+            Some(val) => String::from_utf8(val.into_owned()).ok(),
+            None => None,
+        };
+    }
+
+    /// The value captured by a `:name` or `*name` segment of the matched
+    /// route pattern, or None.
+    ///
+    /// ex: if the route is "/users/:id", a request for "/users/42" has
+    /// get_param("id") == Some("42").
+    pub fn get_param(&self, name: &str) -> Option<&str> {
+        for &(ref k, ref v) in self.params.iter() {
+            if k == name {
+                return Some(v);
+            }
+        }
+        return None;
+    }
+
+    /// Like `get_param`, but parsed into any `FromStr` type.  Returns None
+    /// if the param is missing or fails to parse.
+    ///
+    /// ex: `let id = req.get_param_as::<u64>("id");`
+    pub fn get_param_as<T: FromStr>(&self, name: &str) -> Option<T> {
+        match self.get_param(name) {
+            Some(val) => val.parse::<T>().ok(),
+            None => None,
+        }
+    }
+
+    /// All values captured by the matched route pattern, in pattern order.
+    pub fn get_params(&self) -> &[(String, String)] {
+        return &self.params;
+    }
+
+    /// The application state of type `T` registered with
+    /// `WebServer::set_state`, or None if there isn't one.
+    pub fn get_state<T: Any + Send + Sync>(&self) -> Option<&T> {
+        match self.app_state {
+            Some(ref app_state) => app_state.get::<T>(),
+            None => None,
+        }
+    }
+
+    // A request read from a client: its head, where the parts of the head
+    // are, and the body
+    fn new(head: Vec<u8>, layout: RequestLayout, body: Vec<u8>) -> WebRequest {
+        let (path, method) = {
+            let view = RequestView::new(&head, &layout);
+            (view.decoded_path(), view.lowercase_method())
+        };
+        return WebRequest {
+            head: head,
+            layout: layout,
+            environ: RefCell::new(HashMap::new()),
+            environ_built: Cell::new(false),
+            path: path,
+            method: method,
+            body: body,
+            params: Vec::new(),
+            app_state: None,
+        };
+    }
+
+    // What get_environ().get(key) would return, but without building the
+    // environ if it isn't built yet.  Values from a built environ are
+    // copied, since it can't be borrowed past this call.
+    fn get_environ_value(&self, key: &[u8]) -> Option<Cow<[u8]>> {
+        match self.environ.borrow().get(key) {
+            Some(val) => return Some(Cow::Owned(val.clone())),
+            None => {},
+        }
+        if self.environ_built.get() {
+            return None;
+        }
+
+        let view = RequestView::new(&self.head, &self.layout);
+        if key.starts_with(b"http_") {
+            return view.get_header(&key[5..]);
+        } else if key == b"method" {
+            return Some(Cow::Owned(view.method().to_ascii_lowercase()));
+        } else if key == b"protocol" {
+            return Some(Cow::Owned(view.protocol().to_ascii_lowercase()));
+        } else if key == b"path" {
+            return Some(Cow::Borrowed(view.path()));
+        } else if key == b"query_string" {
+            return Some(Cow::Borrowed(view.query_string()));
+        }
+        return None;
+    }
+
+    // Add environ keys that don't come from the head, e.g. remote_address
+    fn extend_environ(&mut self, keys: HashMap<Vec<u8>, Vec<u8>>) {
+        self.environ.borrow_mut().extend(keys.into_iter());
+    }
+
+    // Build the environ from the head, if it isn't built yet.  Keys added
+    // with extend_environ are kept.
+    fn build_environ(&self) {
+        if self.environ_built.get() {
+            return;
+        }
+        let view = RequestView::new(&self.head, &self.layout);
+        let mut environ = self.environ.borrow_mut();
+        let extra = mem::replace(&mut *environ, view.to_environ());
+        environ.extend(extra.into_iter());
+        self.environ_built.set(true);
+    }
 }
 
 
-/// The page handler function type 
-pub type PageFunction = fn(&WebRequest) -> WebResponse;
+#[cfg(test)]
+impl WebRequest {
+    // A request as if received over HTTP/1.1 with these headers, for tests
+    pub fn for_test(method: &str, path: &str, headers: &[(&str, &str)])
+            -> WebRequest {
+        let mut head = format!("{} {} HTTP/1.1\r\n", method, path);
+        for &(name, value) in headers.iter() {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let head = head.into_bytes();
+        let layout = utils::http_request::parse_view(&head).ok().unwrap()
+            .into_layout();
+        return WebRequest::new(head, layout, Vec::new());
+    }
+}
+
+
+/// Code that runs around requests, for cross-cutting concerns like auth,
+/// CORS, request ids, timing, or adding headers.  See
+/// `WebServer::add_middleware`.
+///
+/// Middleware runs in the order it was added.  The `before` hooks are called
+/// first, before routing; if one returns a response, the rest of the chain
+/// and the handler are skipped.  Then the `after` hooks are called in reverse
+/// order, for each middleware whose `before` hook was called.  The `after`
+/// hooks also see 404 and 405 responses.
+///
+/// A panic in a hook is handled like a panic in a handler: the client gets
+/// a 500 error.
+pub trait Middleware: Send + Sync {
+    /// Called before routing.  Return a response to skip the handler.
+    fn before(&self, _req: &mut WebRequest) -> Option<WebResponse> {
+        return None;
+    }
+
+    /// Called with the response, before it is sent.
+    fn after(&self, _req: &WebRequest, _resp: &mut WebResponse) {
+    }
+}
+
+
+/// The page handler type.  `add_path` accepts plain functions as well as
+/// closures, which can capture whatever they need (config, pools, caches).
+pub type PageFunction = Arc<Fn(&WebRequest) -> WebResponse + Send + Sync>;
+
+
+/// The WebSocket handler type.  It is called with the handshake request and
+/// the upgraded connection, which is closed when it returns.
+pub type WebSocketFunction = Arc<Fn(&WebRequest, &mut WebSocket) + Send + Sync>;
+
+
+// Application state registered with WebServer::set_state, one value per type
+struct AppState {
+    values: HashMap<TypeId, Box<StateValue>>,
+}
+
+// Any + Send + Sync can't be downcast directly, so go through as_any()
+trait StateValue: Any + Send + Sync {
+    fn as_any(&self) -> &Any;
+}
+
+impl<T: Any + Send + Sync> StateValue for T {
+    fn as_any(&self) -> &Any {
+        return self;
+    }
+}
+
+impl AppState {
+    fn new() -> AppState {
+        return AppState { values: HashMap::new() };
+    }
+
+    fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        match self.values.get(&TypeId::of::<T>()) {
+            // Deref the Box first; the Box itself is a StateValue too
+            Some(value) => (**value).as_any().downcast_ref::<T>(),
+            None => None,
+        }
+    }
+}
 
 
 // All worker threads have read only access 
 struct WorkerSharedContext {
     router: Router,
+    app_state: Arc<AppState>,
+    // (path prefix, middleware), in the order added
+    middleware: Vec<(String, Box<Middleware>)>,
     logger: Logger,
     max_request_body_size: usize,
+    header_limits: read_request::HeaderLimits,
+    keep_alive_timeout: Duration,
+    header_timeout: Duration,
+    body_timeout: Duration,
+    body_min_rate: u64,
+    write_timeout: Duration,
+    max_requests_per_connection: usize,
+    auto_etag: bool,
+    compression: bool,
+    compression_min_size: usize,
+    request_decompression: bool,
+    tls_acceptor: Option<Box<TlsAcceptor>>,
     listen_sock: TcpListener,
 }
 
@@ -161,9 +716,22 @@ pub struct WebServer {
     nr_threads: i32,
     logging_enabled: bool,
     router: Option<Router>,
+    app_state: Option<AppState>,
+    middleware: Option<Vec<(String, Box<Middleware>)>>,
     thread_pool: ThreadPool,
     worker_shared_context: Option<Arc<WorkerSharedContext>>,
     max_request_body_size: usize,
+    header_limits: read_request::HeaderLimits,
+    keep_alive_timeout: Duration,
+    header_timeout: Duration,
+    body_timeout: Duration,
+    body_min_rate: u64,
+    write_timeout: Duration,
+    max_requests_per_connection: usize,
+    auto_etag: bool,
+    compression: bool,
+    compression_min_size: usize,
+    request_decompression: bool,
 }
 
 impl WebServer {
@@ -188,9 +756,31 @@ impl WebServer {
                 nr_threads: nr_threads,
                 logging_enabled: logging_enabled,
                 router: Some(Router::new()),
+                app_state: Some(AppState::new()),
+                middleware: Some(Vec::new()),
                 thread_pool: ThreadPool::new(),
                 worker_shared_context: None,
                 max_request_body_size: DEFAULT_MAX_REQUEST_BODY_SIZE,
+                header_limits: read_request::HeaderLimits {
+                    max_size: DEFAULT_MAX_HEADER_SIZE,
+                    max_headers: DEFAULT_MAX_HEADERS,
+                    max_line: DEFAULT_MAX_HEADER_LINE,
+                    max_uri: DEFAULT_MAX_URI_LENGTH,
+                },
+                keep_alive_timeout: Duration::from_secs(
+                    DEFAULT_KEEP_ALIVE_TIMEOUT_SECS),
+                header_timeout: Duration::from_secs(
+                    DEFAULT_HEADER_TIMEOUT_SECS),
+                body_timeout: Duration::from_secs(DEFAULT_BODY_TIMEOUT_SECS),
+                body_min_rate: DEFAULT_BODY_MIN_RATE,
+                write_timeout: Duration::from_secs(
+                    DEFAULT_WRITE_TIMEOUT_SECS),
+                max_requests_per_connection: 
+                    DEFAULT_MAX_REQUESTS_PER_CONNECTION,
+                auto_etag: false,
+                compression: false,
+                compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+                request_decompression: false,
             };
         return ret;
     }
@@ -212,29 +802,226 @@ impl WebServer {
         self.max_request_body_size = size;
     }
 
+    /// Set the maximum size of the request line and headers together, in
+    /// bytes.  Larger requests will generate a 431 error.  Default is 32768.
+    pub fn set_max_header_size(&mut self, size: usize) {
+        self.header_limits.max_size = size;
+    }
+
+    /// Set the maximum number of request headers.  More will generate a 431
+    /// error.  Default is 100.
+    pub fn set_max_headers(&mut self, n: usize) {
+        self.header_limits.max_headers = n;
+    }
+
+    /// Set the maximum length of one request header line, in bytes.  Longer
+    /// lines will generate a 431 error.  Default is 8192.
+    pub fn set_max_header_line_length(&mut self, size: usize) {
+        self.header_limits.max_line = size;
+    }
+
+    /// Set the maximum length of the request target (path and query
+    /// string), in bytes.  Longer ones will generate a 414 error.  Default
+    /// is 8192.
+    pub fn set_max_uri_length(&mut self, size: usize) {
+        self.header_limits.max_uri = size;
+    }
+
+    /// Set how long a persistent (keep-alive) connection may sit idle,
+    /// waiting for the client to send the next request, before it is closed.
+    /// Default is 5 seconds.  Must be > 0.
+    pub fn set_keep_alive_timeout(&mut self, timeout: Duration) {
+        assert!(timeout != Duration::new(0, 0));
+        self.keep_alive_timeout = timeout;
+    }
+
+    /// Set how long a client may take to send the request line and headers,
+    /// from the first byte.  A client that is too slow gets a 408 and the
+    /// connection is closed, so clients trickling in headers can't hold a
+    /// worker thread forever.  Default is 10 seconds.  Must be > 0.
+    pub fn set_header_timeout(&mut self, timeout: Duration) {
+        assert!(timeout != Duration::new(0, 0));
+        self.header_timeout = timeout;
+    }
+
+    /// Set how long a client may take to send the request body.  For each
+    /// `min_rate` bytes received, another second is allowed, so large
+    /// uploads on a working connection don't time out.  A `min_rate` of 0
+    /// makes the timeout fixed.  A client that is too slow gets a 408 and
+    /// the connection is closed.  Default is 20 seconds and 500 bytes per
+    /// second.  The timeout must be > 0.
+    pub fn set_body_timeout(&mut self, timeout: Duration, min_rate: u64) {
+        assert!(timeout != Duration::new(0, 0));
+        self.body_timeout = timeout;
+        self.body_min_rate = min_rate;
+    }
+
+    /// Set how long one write to the client may block, e.g. when a client
+    /// stops reading the response.  The connection is then closed.  Default
+    /// is 30 seconds.  Must be > 0.
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        assert!(timeout != Duration::new(0, 0));
+        self.write_timeout = timeout;
+    }
+
+    /// Set the maximum number of requests served over one connection before
+    /// the server closes it.  Default is 100.  Must be > 0; a value of 1
+    /// disables persistent connections.
+    pub fn set_max_requests_per_connection(&mut self, n: usize) {
+        assert!(n > 0);
+        self.max_requests_per_connection = n;
+    }
+
+    /// Automatically add a strong ETag, from a hash of the body, to 200
+    /// responses to GET and HEAD requests that have an in-memory body and no
+    /// ETag of their own.  Clients that revalidate with If-None-Match then
+    /// get a 304 (without the body) when nothing changed.  Default is off.
+    ///
+    /// Note that the handler still runs and builds the whole body; this only
+    /// saves bandwidth.
+    pub fn set_auto_etag(&mut self, enabled: bool) {
+        self.auto_etag = enabled;
+    }
+
+    /// Compress response bodies with gzip or deflate, for clients that
+    /// accept it (by `Accept-Encoding` q-values).  Default is off.  See
+    /// `WebResponse::set_compression` to override this per response.
+    ///
+    /// Only 2xx responses with an in-memory body, of a text-like type
+    /// (`text/*`, JSON, JavaScript, XML, SVG), and at least
+    /// `set_compression_min_size` bytes are compressed.  Responses to HEAD
+    /// requests are compressed like GET, so they get the same headers
+    /// (including Content-Length), though the body isn't sent.
+    /// Compressible responses get `Vary: Accept-Encoding`, and compressed
+    /// ones get the coding appended to their ETag, e.g. `"abc-gzip"`.
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.compression = enabled;
+    }
+
+    /// Set the smallest response body that is compressed.  Default is 1024
+    /// bytes; below that, compression rarely saves a packet.
+    pub fn set_compression_min_size(&mut self, size: usize) {
+        self.compression_min_size = size;
+    }
+
+    /// Decode request bodies sent with `Content-Encoding: gzip` or
+    /// `deflate`, before the handler sees them.  Default is off, and bodies
+    /// are passed through as sent.
+    ///
+    /// When on, the maximum request body size (`set_max_request_body_size`)
+    /// also applies to the decoded body, and a larger one gets a 413 error.
+    /// A body that fails to decode gets a 400 error, and any other coding
+    /// gets a 415 error.  The handler sees the request without its
+    /// `Content-Encoding` header, and with `Content-Length` updated to the
+    /// decoded size.
+    pub fn set_request_decompression(&mut self, enabled: bool) {
+        self.request_decompression = enabled;
+    }
+
     /// Add an exact path match rule
     /// 
     /// methods: comma separated list of HTTP methods (GET, HEAD, PUT, etc.)
     ///
     /// path: The path component of a URL.  Must start with a '/', except for
     /// OPTIONS requests which can use '*'.
-    pub fn add_path(&mut self, methods: &str, path: &str, 
-            page_fn: PageFunction) {
+    ///
+    /// The path can also be a pattern, where a `:name` segment matches any
+    /// one non-empty segment, and a final `*name` segment matches the rest of
+    /// the path.  ex: "/users/:id/posts/:post_id" or "/files/*rest".  See
+    /// `WebRequest::get_param` for the captured values.
+    ///
+    /// If more than one rule matches a request, the most specific one wins:
+    /// literal segments beat `:name`, which beats `*name`, and prefix rules
+    /// are tried last.
+    ///
+    /// # Panics
+    /// If the pattern is invalid, or if a rule matching the same paths was
+    /// already added for one of the methods.
+    pub fn add_path<F>(&mut self, methods: &str, path: &str, page_fn: F)
+            where F: Fn(&WebRequest) -> WebResponse + Send + Sync + 'static {
         self.router.as_mut().unwrap().add_path(
-                methods, path, page_fn, false);
+                methods, path, Arc::new(page_fn), false);
     }
 
     /// Add a prefix path match rule.  Like `add_path`, but matches anything
-    /// beginning with `path`.
-    pub fn add_path_prefix(&mut self, methods: &str, path: &str, 
-            page_fn: PageFunction) {
+    /// beginning with `path`.  Patterns are not supported here; if prefix
+    /// rules overlap, the longest one wins.
+    pub fn add_path_prefix<F>(&mut self, methods: &str, path: &str,
+            page_fn: F)
+            where F: Fn(&WebRequest) -> WebResponse + Send + Sync + 'static {
         self.router.as_mut().unwrap().add_path(
-                methods, path, page_fn, true);
+                methods, path, Arc::new(page_fn), true);
+    }
+
+    /// Serve the files in a directory, by adding a GET and HEAD prefix rule
+    /// for `files.get_url_prefix()`.  See `StaticFiles`.
+    pub fn add_static_files(&mut self, files: StaticFiles) {
+        let prefix = files.get_url_prefix().to_string();
+        self.add_path_prefix("get, head", &prefix,
+                move |req: &WebRequest| files.serve(req));
+    }
+
+    /// Accept WebSocket connections on an exact path (which can be a
+    /// pattern, as with `add_path`).  GET requests with a valid handshake
+    /// are upgraded, and `handler` is called with the connection; other GET
+    /// requests get a 426 or 400 error.  Middleware runs for the handshake
+    /// like any other request.
+    ///
+    /// The handler runs on the worker thread that read the handshake, and
+    /// keeps it busy until the connection closes, so set the number of
+    /// threads with the expected number of open websockets in mind.
+    pub fn add_websocket<F>(&mut self, path: &str, handler: F)
+            where F: Fn(&WebRequest, &mut WebSocket) + Send + Sync + 'static {
+        let handler: WebSocketFunction = Arc::new(handler);
+        self.add_path("get", path, move |req: &WebRequest| {
+            WebResponse::new_websocket(req, handler.clone())
+        });
+    }
+
+    /// Add middleware that runs around every request.  See `Middleware` for
+    /// how the chain is called.
+    pub fn add_middleware<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.add_middleware_prefix("", middleware);
+    }
+
+    /// Add middleware that only runs for requests whose path starts with
+    /// `prefix`.  It is ordered with the other middleware by when it was
+    /// added, the same as global middleware.
+    pub fn add_middleware_prefix<M: Middleware + 'static>(&mut self,
+            prefix: &str, middleware: M) {
+        self.middleware.as_mut().unwrap().push(
+                (prefix.to_string(), Box::new(middleware)));
+    }
+
+    /// Register application state, shared by all handlers, which they can
+    /// get with `WebRequest::get_state::<T>()`.  One value is kept per type;
+    /// setting a second value of the same type replaces the first.
+    ///
+    /// Handlers only get shared (`&T`) access, so use `Mutex`, atomics, etc.
+    /// inside `T` for anything that changes.
+    pub fn set_state<T: Any + Send + Sync>(&mut self, state: T) {
+        self.app_state.as_mut().unwrap().insert(state);
     }
 
     /// Starts worker threads and enters supervisor loop.  If any worker
     /// threads fail, they will be respawned.  This function does not return.
     pub fn run(&mut self, address: &str, port: i32) {
+        self.start(address, port, None);
+    }
+
+    /// Like `run`, but serves HTTPS: each connection is handed to
+    /// `acceptor` for the TLS handshake before any requests are read.
+    /// Connections that fail the handshake are closed.
+    ///
+    /// Build with the "openssl" cargo feature for `OpenSslAcceptor`, or
+    /// implement `TlsAcceptor` with any other TLS library.
+    pub fn run_tls<A: TlsAcceptor + 'static>(&mut self, address: &str,
+            port: i32, acceptor: A) {
+        self.start(address, port, Some(Box::new(acceptor)));
+    }
+
+    fn start(&mut self, address: &str, port: i32,
+            tls_acceptor: Option<Box<TlsAcceptor>>) {
         let addr = format!("{}:{}", address, port);
 
         // Should probably return an error instead of panicing here.
@@ -243,16 +1030,7 @@ impl WebServer {
             Ok(s) => s,
             Err(err) => panic!("listen on socket failed: {}", err)
         };
-        
-        let router_moved = self.router.take().unwrap();
-
-        // Create a read-only context all worker threads can use
-        let ctx = WorkerSharedContext {
-            router: router_moved,
-            logger: Logger::new(self.logging_enabled),
-            max_request_body_size: self.max_request_body_size,
-            listen_sock: listener,
-        };
+        let ctx = self.make_shared_context(listener, tls_acceptor);
 
         // We hold a reference too, in case threads die and need restart
         self.worker_shared_context = Some(Arc::new(ctx));
@@ -272,6 +1050,36 @@ impl WebServer {
         }
     }
 
+    // Create a read-only context all worker threads can use.  The router,
+    // state and middleware are moved into it.
+    fn make_shared_context(&mut self, listener: TcpListener,
+            tls_acceptor: Option<Box<TlsAcceptor>>) -> WorkerSharedContext {
+        let router_moved = self.router.take().unwrap();
+        let app_state_moved = self.app_state.take().unwrap();
+        let middleware_moved = self.middleware.take().unwrap();
+
+        return WorkerSharedContext {
+            router: router_moved,
+            app_state: Arc::new(app_state_moved),
+            middleware: middleware_moved,
+            logger: Logger::new(self.logging_enabled),
+            max_request_body_size: self.max_request_body_size,
+            header_limits: self.header_limits,
+            keep_alive_timeout: self.keep_alive_timeout,
+            header_timeout: self.header_timeout,
+            body_timeout: self.body_timeout,
+            body_min_rate: self.body_min_rate,
+            write_timeout: self.write_timeout,
+            max_requests_per_connection: self.max_requests_per_connection,
+            auto_etag: self.auto_etag,
+            compression: self.compression,
+            compression_min_size: self.compression_min_size,
+            request_decompression: self.request_decompression,
+            tls_acceptor: tls_acceptor,
+            listen_sock: listener,
+        };
+    }
+
     fn start_new_worker(&mut self) {
         let priv_ctx = WorkerPrivateContext {
             shared_ctx: self.worker_shared_context.as_ref().unwrap().clone(),
@@ -295,7 +1103,8 @@ fn worker_thread_main(ctx: WorkerPrivateContext) {
 }
 
 
-// HTTP specific socket processing
+// HTTP specific socket processing.  Serves requests until either side
+// decides to close the connection.
 fn process_http_connection(ctx: &WorkerPrivateContext, 
         raw_stream: TcpStream, peer_addr: SocketAddr) {
 
@@ -303,99 +1112,320 @@ fn process_http_connection(ctx: &WorkerPrivateContext,
     // and don't want to stall.
     raw_stream.set_nodelay(true).unwrap();
 
-    let log: &Logger = &ctx.shared_ctx.logger;
+    // Bounds how long we wait for the client to send (the next) request.
+    // read_request adjusts it as the request arrives.
+    raw_stream.set_read_timeout(
+            Some(ctx.shared_ctx.keep_alive_timeout)).unwrap();
+
+    // Bounds each write, for clients that stop reading
+    raw_stream.set_write_timeout(
+            Some(ctx.shared_ctx.write_timeout)).unwrap();
 
+    // The TLS handshake is bounded by the read timeout too
+    let (stream, tls_info): (Box<GenericSocket>, Option<TlsInfo>) =
+            match ctx.shared_ctx.tls_acceptor {
+        Some(ref acceptor) => match acceptor.accept(raw_stream) {
+            Ok((stream, info)) => (stream, Some(info)),
+            Err(ref e) if genericsocket::is_timeout(e) => {
+                ctx.shared_ctx.logger.log_timeout("in TLS handshake");
+                return;
+            },
+            Err(e) => {
+                ctx.shared_ctx.logger.log_tls_error(e);
+                return;
+            },
+        },
+        None => (Box::new(raw_stream), None),
+    };
+    serve_connection(ctx, stream, &peer_addr, tls_info.as_ref());
+}
 
-    // Now is where we could also wrap it with SSL.
-    let mut stream: Box<GenericSocket> = Box::new(raw_stream);
 
+// Serve requests on a connection, until either side decides to close it
+fn serve_connection(ctx: &WorkerPrivateContext,
+        mut stream: Box<GenericSocket>, peer_addr: &SocketAddr,
+        tls_info: Option<&TlsInfo>) {
+    // Received bytes not consumed by the previous request (pipelining)
+    let mut recv_buffer = Vec::<u8>::with_capacity(4096);
+
+    let max_requests = ctx.shared_ctx.max_requests_per_connection;
+    let mut nr_requests = 0;
+    loop {
+        nr_requests += 1;
+        let allow_keep_alive = nr_requests < max_requests;
+        stream = match process_http_request(ctx, stream, &mut recv_buffer,
+                peer_addr, tls_info, allow_keep_alive) {
+            Some(stream) => stream,
+            None => return,
+        };
+    }
+}
+
+
+// Read one request, run its handler, and send the response.
+// Returns the stream if the connection should stay open for another request.
+//
+// Requests are handled one at a time, so pipelined requests are answered in
+// the order received.
+fn process_http_request(ctx: &WorkerPrivateContext,
+        mut stream: Box<GenericSocket>, recv_buffer: &mut Vec<u8>,
+        peer_addr: &SocketAddr, tls_info: Option<&TlsInfo>,
+        allow_keep_alive: bool) -> Option<Box<GenericSocket>> {
+
+    let log: &Logger = &ctx.shared_ctx.logger;
+
+    let timeouts = read_request::Timeouts {
+        idle: ctx.shared_ctx.keep_alive_timeout,
+        header: ctx.shared_ctx.header_timeout,
+        body: ctx.shared_ctx.body_timeout,
+        body_min_rate: ctx.shared_ctx.body_min_rate,
+    };
 
     // Read full request (headers and body)
     let mut req = match read_request::read_request(&mut *stream,
-            ctx.shared_ctx.max_request_body_size) {
+            recv_buffer, ctx.shared_ctx.max_request_body_size,
+            ctx.shared_ctx.request_decompression, &timeouts,
+            &ctx.shared_ctx.header_limits) {
+        Err(read_request::Error::NoRequest) => {
+            // Client is done with the connection (or went idle)
+            return None;
+        },
         Err(read_request::Error::InvalidRequest) => {
             let mut resp = WebResponse::new();
             resp.set_code(400, "Bad Request");
             resp.set_body_str("Error 400: Bad Request");
-            write_response(&mut *stream, None, &resp, log);
-            return;
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
         },
         Err(read_request::Error::LengthRequired) => {
             let mut resp = WebResponse::new();
             resp.set_code(411, "Length Required");
             resp.set_body_str("Error 411: Length Required");
-            write_response(&mut *stream, None, &resp, log);
-            return;
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
+        },
+        Err(read_request::Error::NotImplemented) => {
+            let mut resp = WebResponse::new();
+            resp.set_code(501, "Not Implemented");
+            resp.set_body_str("Error 501: Not Implemented");
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
         },
         Err(read_request::Error::InvalidVersion) => {
             let mut resp = WebResponse::new();
             resp.set_code(505, "Version not Supported");
             resp.set_body_str("Error 505: Version not Supported");
-            write_response(&mut *stream, None, &resp, log);
-            return;
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
         },
         Err(read_request::Error::TooLarge) => {
             let mut resp = WebResponse::new();
             resp.set_code(413, "Request Entity Too Large");
             resp.set_body_str("Error 413: Request Entity Too Large");
-            write_response(&mut *stream, None, &resp, log);
-            return;
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
+        },
+        Err(read_request::Error::UnsupportedMediaType) => {
+            let mut resp = WebResponse::new();
+            resp.set_code(415, "Unsupported Media Type");
+            resp.set_header("Accept-Encoding", "gzip, deflate");
+            resp.set_body_str("Error 415: Unsupported Media Type");
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
+        },
+        Err(read_request::Error::UriTooLong) => {
+            let mut resp = WebResponse::new();
+            resp.set_code(414, "URI Too Long");
+            resp.set_body_str("Error 414: URI Too Long");
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
+        },
+        Err(read_request::Error::HeadersTooLarge) => {
+            let mut resp = WebResponse::new();
+            resp.set_code(431, "Request Header Fields Too Large");
+            resp.set_body_str("Error 431: Request Header Fields Too Large");
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
+        },
+        Err(read_request::Error::Timeout) => {
+            log.log_timeout("reading request");
+            let mut resp = WebResponse::new();
+            resp.set_code(408, "Request Timeout");
+            resp.set_body_str("Error 408: Request Timeout");
+            write_response(&mut *stream, None, &mut resp, false, log);
+            return None;
         },
         Err(read_request::Error::IoError(e)) => {
             log.log_read_request_error(e);
-            return;
+            return None;
         },
         Ok(req) => req,
     };
 
     // Add socket specific attributes 
+    let mut socket_environ = HashMap::new();
     let val = format!("{}", peer_addr);
-    req.environ.insert(b"remote_address".to_vec(), val.as_bytes().to_vec());
+    socket_environ.insert(b"remote_address".to_vec(), val.into_bytes());
+    tls::add_environ(&mut socket_environ, tls_info);
+    req.extend_environ(socket_environ);
+    req.app_state = Some(ctx.shared_ctx.app_state.clone());
+
+    let keep_alive = allow_keep_alive && wants_keep_alive(&req);
+
+    // Run middleware and the handler.  If either panics, the sentinel will
+    // send a 500.
+    // We clone the shared_ctx Arc just so we have access to the logging object
+    // during the drop.  
+    // TODO: when unsafe_destructor is no longer needed
+    // just to store a reference, use that for the logger/context.
+    let mut sentinel = HTTPConnectionSentinel { 
+        stream: Some(stream), 
+        shared_ctx: ctx.shared_ctx.clone(),
+        request: req,
+        armed: true 
+    };
+    let mut response = dispatch(&ctx.shared_ctx, &mut sentinel.request);
+    sentinel.armed = false;
+
+    // A websocket handshake hands the connection over to its handler, which
+    // can use anything the client sent after the handshake
+    let upgrade = response.upgrade.take();
+    if response.code == 101 {
+        let mut stream = sentinel.stream.take().unwrap();
+        let handler = match upgrade {
+            Some(handler) => handler,
+            None => {
+                log.log_invalid_response("101 response without an upgrade");
+                let mut resp = WebResponse::new();
+                resp.set_code(500, "Internal Server Error");
+                resp.set_body_str("Error 500: Invalid response from handler");
+                write_response(&mut *stream, Some(&sentinel.request),
+                        &mut resp, false, log);
+                return None;
+            },
+        };
+        if write_response(&mut *stream, Some(&sentinel.request),
+                &mut response, false, log) {
+            let buffer = mem::replace(recv_buffer, Vec::new());
+            websocket::run(stream, buffer, &sentinel.request, &handler);
+        }
+        return None;
+    }
+
+    // Compress before evaluating preconditions and ranges, since both apply
+    // to the body as sent (and its ETag)
+    if ctx.shared_ctx.auto_etag {
+        conditional::add_auto_etag(&sentinel.request, &mut response);
+    }
+    compression::apply_compression(&sentinel.request, &mut response,
+            ctx.shared_ctx.compression, ctx.shared_ctx.compression_min_size);
+    conditional::apply_preconditions(&sentinel.request, &mut response);
+    ranges::apply_ranges(&sentinel.request, &mut response);
+
+    // Take the stream back, so it can outlive this request
+    let mut stream = sentinel.stream.take().unwrap();
+    let keep_alive = write_response(&mut *stream, Some(&sentinel.request),
+            &mut response, keep_alive, log);
+    return if keep_alive { Some(stream) } else { None };
+}
+
 
-    // Do routing
-    let ret = ctx.shared_ctx.router.route(&req);
-    let page_fn = match ret {
-        RoutingResult::FoundRule(page_fn) => page_fn,
+// Run the middleware before hooks, route the request to its handler (or an
+// error page), then run the after hooks.
+fn dispatch(shared_ctx: &WorkerSharedContext, req: &mut WebRequest)
+        -> WebResponse {
+    let mut chain = Vec::new();
+    for &(ref prefix, ref middleware) in shared_ctx.middleware.iter() {
+        if req.path.starts_with(&**prefix) {
+            chain.push(middleware);
+        }
+    }
+
+    // A before hook can answer the request itself
+    let mut nr_called = 0;
+    let mut early_response = None;
+    for middleware in chain.iter() {
+        nr_called += 1;
+        early_response = middleware.before(req);
+        if early_response.is_some() {
+            break;
+        }
+    }
+
+    let mut response = match early_response {
+        Some(response) => response,
+        None => route_request(shared_ctx, req),
+    };
+
+    for middleware in chain[..nr_called].iter().rev() {
+        middleware.after(req, &mut response);
+    }
+    return response;
+}
+
+
+// Call the handler for the request, or make a 404 / 405 response
+fn route_request(shared_ctx: &WorkerSharedContext, req: &mut WebRequest)
+        -> WebResponse {
+    let ret = shared_ctx.router.route(req);
+    match ret {
+        RoutingResult::FoundRule(page_fn, params) => {
+            req.params = params;
+            return (*page_fn)(req);
+        },
         RoutingResult::NoPathMatch => {
             let mut resp = WebResponse::new();
             resp.set_code(404, "Not Found");
             resp.set_body_str("Error 404: Resource not found");
-            write_response(&mut *stream, Some(&req), &resp, log);
-            return;
-        }
+            return resp;
+        },
         RoutingResult::NoMethodMatch(methods) => {
             let mut resp = WebResponse::new();
             resp.set_code(405, "Method not allowed");
             resp.set_body_str("Error 405: Method not allowed");
             let methods_joined = methods.join(", ");
             resp.set_header("Allow", &methods_joined);
-            write_response(&mut *stream, Some(&req), &resp, log);
-            return;
-        }
-    };
+            return resp;
+        },
+    }
+}
 
 
-    // Run the handler.  If it panics, the sentinel will send a 500.
-    // We clone the shared_ctx Arc just so we have access to the logging object
-    // during the drop.  
-    // TODO: when unsafe_destructor is no longer needed
-    // just to store a reference, use that for the logger/context.
-    let mut sentinel = HTTPConnectionSentinel { 
-        stream: stream, 
-        shared_ctx: ctx.shared_ctx.clone(),
-        request: req,
-        armed: true 
+// Does the client want the connection kept open after this request?
+// HTTP/1.1 is persistent unless "Connection: close" is sent, while
+// HTTP/1.0 needs an explicit "Connection: keep-alive".
+fn wants_keep_alive(req: &WebRequest) -> bool {
+    let is_http10 = &*req.get_environ_value(b"protocol").unwrap() 
+            == b"http/1.0";
+    let val = match req.get_environ_value(b"http_connection") {
+        Some(val) => val.into_owned().into_ascii_lowercase(),
+        None => Vec::new(),
     };
-    let response = (page_fn)(&sentinel.request);
-    sentinel.armed = false;
-    write_response(&mut *sentinel.stream, Some(&sentinel.request),
-            &response, log);
+
+    let mut close = false;
+    let mut keep_alive = false;
+    for token in val.split(|c| *c == b',') {
+        let token = utils::byteutils::strip(token);
+        if token == b"close" {
+            close = true;
+        } else if token == b"keep-alive" {
+            keep_alive = true;
+        }
+    }
+
+    if close {
+        return false;
+    }
+    if is_http10 {
+        return keep_alive;
+    }
+    return true;
 }
 
 
 // A sentinel that sends a 500 error unless armed=false
 struct HTTPConnectionSentinel {
-    stream: Box<GenericSocket>,
+    stream: Option<Box<GenericSocket>>,
     shared_ctx: Arc<WorkerSharedContext>,
     armed: bool,
     request: WebRequest,
@@ -408,10 +1438,209 @@ impl Drop for HTTPConnectionSentinel {
             let mut resp = WebResponse::new();
             resp.set_code(500, "Uh oh :-(");
             resp.set_body_str("Error 500: Internal error in handler function");
-            write_response(&mut *self.stream, 
+            let stream = self.stream.as_mut().unwrap();
+            write_response(&mut **stream, 
                 Some(&self.request), 
-                &resp,
+                &mut resp,
+                false,
                 &self.shared_ctx.logger);
         }
     }
 }
+
+
+// Returns one piece of its input per read, then acts as if the client
+// closed the connection.  The output is shared, so it can be checked after
+// the socket is dropped.
+#[cfg(test)]
+struct TestSocket {
+    pieces: Vec<Vec<u8>>,
+    output: Arc<Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl GenericSocket for TestSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.pieces.is_empty() {
+            return Ok(0);
+        }
+        let piece = self.pieces.remove(0);
+        assert!(piece.len() <= buf.len());
+        for (i, c) in piece.iter().enumerate() {
+            buf[i] = *c;
+        }
+        return Ok(piece.len());
+    }
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.output.lock().unwrap().push_all(buf);
+        return Ok(());
+    }
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>)
+            -> Result<(), io::Error> {
+        return Ok(());
+    }
+}
+
+// A worker context for the server's rules and settings
+#[cfg(test)]
+fn test_context(svr: &mut WebServer) -> WorkerPrivateContext {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    return WorkerPrivateContext {
+        shared_ctx: Arc::new(svr.make_shared_context(listener, None)),
+    };
+}
+
+// Logs its hooks, and answers the request itself if told to
+#[cfg(test)]
+struct Recorder {
+    name: &'static str,
+    log: Arc<Mutex<Vec<String>>>,
+    answer: bool,
+}
+
+#[cfg(test)]
+impl Middleware for Recorder {
+    fn before(&self, _req: &mut WebRequest) -> Option<WebResponse> {
+        self.log.lock().unwrap().push(format!("before {}", self.name));
+        if self.answer {
+            let mut resp = WebResponse::new();
+            resp.set_code(403, "Forbidden");
+            return Some(resp);
+        }
+        return None;
+    }
+
+    fn after(&self, _req: &WebRequest, _resp: &mut WebResponse) {
+        self.log.lock().unwrap().push(format!("after {}", self.name));
+    }
+}
+
+#[cfg(test)]
+struct Panicker;
+
+#[cfg(test)]
+impl Middleware for Panicker {
+    fn before(&self, _req: &mut WebRequest) -> Option<WebResponse> {
+        panic!("before hook failed");
+    }
+}
+
+
+#[test]
+fn test_wants_keep_alive() {
+    let http10 = |headers: &[(&str, &str)]| {
+        let mut req = WebRequest::for_test("get", "/", headers);
+        req.get_environ_mut().insert(b"protocol".to_vec(),
+                b"http/1.0".to_vec());
+        req
+    };
+
+    // HTTP/1.1 stays open unless told otherwise
+    assert!(wants_keep_alive(&WebRequest::for_test("get", "/", &[])));
+    assert!(!wants_keep_alive(&WebRequest::for_test("get", "/",
+                    &[("connection", "Close")])));
+
+    // HTTP/1.0 closes unless told otherwise
+    assert!(!wants_keep_alive(&http10(&[])));
+    assert!(wants_keep_alive(&http10(&[("connection", "Keep-Alive")])));
+
+    // Connection is a token list
+    assert!(wants_keep_alive(&http10(&[("connection",
+                    "keep-alive, Upgrade")])));
+    assert!(!wants_keep_alive(&WebRequest::for_test("get", "/",
+                    &[("connection", "Upgrade,close")])));
+}
+
+#[test]
+fn test_max_requests_per_connection() {
+    fn page(_req: &WebRequest) -> WebResponse {
+        return WebResponse::new();
+    }
+    let mut svr = WebServer::new();
+    svr.set_logging(false);
+    svr.set_max_requests_per_connection(2);
+    svr.add_path("get", "/", page);
+    let ctx = test_context(&mut svr);
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let request = b"GET / HTTP/1.1\r\n\r\n".to_vec();
+    let sock = TestSocket {
+        pieces: vec![request.clone(), request.clone(), request],
+        output: output.clone(),
+    };
+    let peer_addr = "127.0.0.1:1234".parse::<SocketAddr>().unwrap();
+    serve_connection(&ctx, Box::new(sock), &peer_addr, None);
+
+    // The last allowed request is told the connection closes, and the one
+    // after it isn't answered
+    let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+    let responses: Vec<&str> = output.split("HTTP/1.1 200 OK\r\n").collect();
+    assert_eq!(responses.len(), 3);
+    assert!(responses[1].starts_with("Connection: keep-alive\r\n"));
+    assert!(responses[2].starts_with("Connection: close\r\n"));
+}
+
+#[test]
+fn test_dispatch_order() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let recorder = |name: &'static str, answer: bool| {
+        Recorder { name: name, log: log.clone(), answer: answer }
+    };
+    let mut svr = WebServer::new();
+    svr.set_logging(false);
+    svr.add_middleware(recorder("a", false));
+    svr.add_middleware_prefix("/api/", recorder("b", false));
+    svr.add_middleware(recorder("c", false));
+    svr.add_middleware_prefix("/api/private/", recorder("d", true));
+    svr.add_middleware(recorder("e", false));
+    let handler_log = log.clone();
+    svr.add_path_prefix("get", "/", move |_req: &WebRequest| {
+        handler_log.lock().unwrap().push("handler".to_string());
+        WebResponse::new()
+    });
+    let ctx = test_context(&mut svr);
+    let run = |path: &str| {
+        log.lock().unwrap().clear();
+        let mut req = WebRequest::for_test("get", path, &[]);
+        let resp = dispatch(&ctx.shared_ctx, &mut req);
+        (resp.code, log.lock().unwrap().join(", "))
+    };
+
+    // Before hooks in the order added, after hooks in reverse, and prefix
+    // middleware only for its paths
+    assert_eq!(run("/x"), (200, "before a, before c, before e, handler, \
+after e, after c, after a".to_string()));
+    assert_eq!(run("/api/x"), (200, "before a, before b, before c, before e, \
+handler, after e, after c, after b, after a".to_string()));
+
+    // An answer from d skips e and the handler, and only the middleware
+    // that was entered gets its after hook
+    assert_eq!(run("/api/private/x"), (403, "before a, before b, before c, \
+before d, after d, after c, after b, after a".to_string()));
+}
+
+#[test]
+fn test_middleware_panic() {
+    let mut svr = WebServer::new();
+    svr.set_logging(false);
+    svr.add_middleware(Panicker);
+    svr.add_path("get", "/", |_req: &WebRequest| WebResponse::new());
+    let ctx = test_context(&mut svr);
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let sock = TestSocket {
+        pieces: vec![b"GET / HTTP/1.1\r\n\r\n".to_vec()],
+        output: output.clone(),
+    };
+    let peer_addr = "127.0.0.1:1234".parse::<SocketAddr>().unwrap();
+    let ret = thread::spawn(move || {
+        process_http_request(&ctx, Box::new(sock), &mut Vec::new(),
+                &peer_addr, None, true);
+    }).join();
+
+    // The sentinel answers for the panicking hook
+    assert!(ret.is_err());
+    let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+    assert!(output.starts_with("HTTP/1.1 500 Uh oh :-(\r\n\
+Connection: close\r\n"));
+}
@@ -0,0 +1,100 @@
+//! A TlsAcceptor using OpenSSL, built with the "openssl" cargo feature
+
+use std::io;
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::Duration;
+
+use openssl::nid::Nid;
+use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_VERIFY_PEER};
+use openssl::ssl::error::SslError;
+use openssl::x509::X509FileType;
+
+use super::tls::{TlsAcceptor, TlsInfo};
+use utils::genericsocket::GenericSocket;
+
+
+/// Serves TLS with a certificate and private key from PEM files.
+///
+/// ex: for testing on localhost with a self-signed certificate:
+///
+/// ```ignore
+/// // openssl req -x509 -newkey rsa:2048 -nodes -days 30 \
+/// //     -subj /CN=localhost -keyout key.pem -out cert.pem
+/// let acceptor = OpenSslAcceptor::new("cert.pem", "key.pem").unwrap();
+/// svr.run_tls("127.0.0.1", 8443, acceptor);
+/// ```
+pub struct OpenSslAcceptor {
+    ctx: SslContext,
+}
+
+impl OpenSslAcceptor {
+    /// cert_file can hold the whole chain: the server's certificate first,
+    /// then any intermediate certificates.
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(cert_file: P, key_file: Q)
+            -> Result<OpenSslAcceptor, io::Error> {
+        let mut ctx = try!(SslContext::new(SslMethod::Sslv23)
+                .map_err(to_io_error));
+        try!(ctx.set_certificate_chain_file(cert_file, X509FileType::PEM)
+                .map_err(to_io_error));
+        try!(ctx.set_private_key_file(key_file, X509FileType::PEM)
+                .map_err(to_io_error));
+        try!(ctx.check_private_key().map_err(to_io_error));
+        return Ok(OpenSslAcceptor { ctx: ctx });
+    }
+
+    /// Ask clients for a certificate, signed by one of the CAs in this PEM
+    /// file.  Clients without one can still connect; a client that sends
+    /// a certificate that doesn't verify fails the handshake.  The subject
+    /// of a verified certificate is in environ[tls_peer_subject].
+    pub fn set_client_ca_file<P: AsRef<Path>>(&mut self, ca_file: P)
+            -> Result<(), io::Error> {
+        try!(self.ctx.set_CA_file(ca_file).map_err(to_io_error));
+        self.ctx.set_verify(SSL_VERIFY_PEER, None);
+        return Ok(());
+    }
+}
+
+impl TlsAcceptor for OpenSslAcceptor {
+    fn accept(&self, stream: TcpStream)
+            -> Result<(Box<GenericSocket>, TlsInfo), io::Error> {
+        let stream = try!(SslStream::accept(&self.ctx, stream)
+                .map_err(to_io_error));
+        let info = {
+            let ssl = stream.ssl();
+            let cipher = match ssl.get_current_cipher() {
+                Some(cipher) => cipher.name().to_string(),
+                None => String::new(),
+            };
+            let peer_subject = ssl.peer_certificate().and_then(|cert| {
+                cert.subject_name().text_by_nid(Nid::CN)
+                    .map(|cn| format!("CN={}", &*cn))
+            });
+            TlsInfo {
+                protocol: ssl.version().to_string(),
+                cipher: cipher,
+                peer_subject: peer_subject,
+            }
+        };
+        return Ok((Box::new(stream), info));
+    }
+}
+
+
+impl GenericSocket for SslStream<TcpStream> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        return io::Read::read(self, buf);
+    }
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        return io::Write::write_all(self, buf);
+    }
+    fn set_read_timeout(&mut self, timeout: Option<Duration>)
+            -> Result<(), io::Error> {
+        return self.get_ref().set_read_timeout(timeout);
+    }
+}
+
+
+fn to_io_error(err: SslError) -> io::Error {
+    return io::Error::new(io::ErrorKind::Other, err);
+}
@@ -0,0 +1,335 @@
+//! Range requests: answering with part(s) of a response body
+
+use std::io;
+use std::io::{Seek, SeekFrom};
+use std::ascii::AsciiExt;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use super::{WebRequest, WebResponse, ResponseBody, ReadSeek};
+use super::conditional;
+use utils::byteranges;
+use utils::byteranges::Ranges;
+
+// More ranges than this are ignored (the whole body is sent), since each
+// range costs a seek, and they are a known DoS vector.
+static MAX_RANGES: usize = 32;
+
+static BOUNDARY_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+
+// If the response body supports ranges, advertise it with Accept-Ranges.  If
+// the request is also a GET with a satisfiable Range header, change the
+// response to a 206 with the requested range(s); if the Range header isn't
+// satisfiable, change it to a 416.
+//
+// Only 200 responses with an in-memory or seekable body are changed.  A
+// handler can opt out by setting "Accept-Ranges: none".
+pub fn apply_ranges(req: &WebRequest, response: &mut WebResponse) {
+    if response.code != 200
+            || response.get_header("Transfer-Encoding").is_some() {
+        return;
+    }
+    let len = match response.body {
+        ResponseBody::Bytes(ref body) => body.len() as u64,
+        ResponseBody::Seekable(_, len) => len,
+        ResponseBody::Stream(..) => return,
+    };
+    let accepts_bytes = response.get_header("Accept-Ranges")
+        .map(|val| val.eq_ignore_ascii_case("bytes"));
+    match accepts_bytes {
+        Some(true) => {},
+        Some(false) => return,
+        None => response.set_header("Accept-Ranges", "bytes"),
+    }
+
+    if req.get_method() != "get" {
+        return;
+    }
+    let ranges = match req.get_environ_value(b"http_range") {
+        Some(val) => byteranges::parse_range_header(&val, len, MAX_RANGES),
+        None => return,
+    };
+    // If the client's copy is stale, it needs the whole body
+    if !conditional::if_range_matches(req, response) {
+        return;
+    }
+    match ranges {
+        Ranges::Ignore => {},
+        Ranges::Unsatisfiable => {
+            response.set_code(416, "Range Not Satisfiable");
+            response.set_header("Content-Range", &format!("bytes */{}", len));
+            response.remove_header("Content-Type");
+            response.set_body_str("Error 416: Range Not Satisfiable");
+        },
+        Ranges::Satisfiable(ranges) => {
+            let pieces = make_pieces(response, &ranges, len);
+            response.set_code(206, "Partial Content");
+            set_pieces_body(response, pieces);
+        },
+    }
+}
+
+
+// A part of the new body: literal bytes, or (start, length) of the old body
+enum Piece {
+    Data(Vec<u8>),
+    Range(u64, u64),
+}
+
+
+// Describe the new body, and set the Content-Range or Content-Type headers
+fn make_pieces(response: &mut WebResponse, ranges: &[(u64, u64)], len: u64)
+        -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    if ranges.len() == 1 {
+        let (first, last) = ranges[0];
+        response.set_header("Content-Range",
+                &format!("bytes {}-{}/{}", first, last, len));
+        pieces.push(Piece::Range(first, last - first + 1));
+        return pieces;
+    }
+
+    // Multiple ranges are sent as multipart/byteranges.  Each part has the
+    // original Content-Type.
+    let boundary = format!("mudpie_byteranges_{:016x}",
+            BOUNDARY_COUNTER.fetch_add(1, Ordering::Relaxed));
+    let content_type = response.get_header("Content-Type")
+        .map(|s| s.to_string());
+    response.set_header("Content-Type",
+            &format!("multipart/byteranges; boundary={}", boundary));
+    for &(first, last) in ranges.iter() {
+        let mut part_head = format!("--{}\r\n", boundary);
+        match content_type {
+            Some(ref val) => {
+                part_head.push_str(&format!("Content-Type: {}\r\n", val));
+            },
+            None => {},
+        }
+        part_head.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n\r\n",
+                    first, last, len));
+        pieces.push(Piece::Data(part_head.into_bytes()));
+        pieces.push(Piece::Range(first, last - first + 1));
+        pieces.push(Piece::Data(b"\r\n".to_vec()));
+    }
+    pieces.push(Piece::Data(format!("--{}--\r\n", boundary).into_bytes()));
+    return pieces;
+}
+
+
+// Replace the response body with the pieces
+fn set_pieces_body(response: &mut WebResponse, pieces: Vec<Piece>) {
+    let mut new_len = 0;
+    for piece in pieces.iter() {
+        new_len += match *piece {
+            Piece::Data(ref data) => data.len() as u64,
+            Piece::Range(_, len) => len,
+        };
+    }
+
+    let old_body = ::std::mem::replace(&mut response.body,
+            ResponseBody::Bytes(Vec::new()));
+    match old_body {
+        ResponseBody::Bytes(body) => {
+            let mut new_body = Vec::with_capacity(new_len as usize);
+            for piece in pieces.iter() {
+                match *piece {
+                    Piece::Data(ref data) => new_body.push_all(data),
+                    Piece::Range(start, len) => {
+                        let start = start as usize;
+                        new_body.push_all(&body[start .. start + len as usize]);
+                    },
+                }
+            }
+            response.body = ResponseBody::Bytes(new_body);
+        },
+        ResponseBody::Seekable(source, _) => {
+            let reader = PiecesReader {
+                source: source,
+                pieces: pieces,
+                index: 0,
+                offset: 0,
+            };
+            response.body = ResponseBody::Stream(Box::new(reader),
+                    Some(new_len));
+        },
+        ResponseBody::Stream(..) => unreachable!(),
+    }
+}
+
+
+// Reads the pieces in order, seeking the source for each range
+struct PiecesReader {
+    source: Box<ReadSeek>,
+    pieces: Vec<Piece>,
+    index: usize,
+    offset: u64,  // within the current piece
+}
+
+impl io::Read for PiecesReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.index < self.pieces.len() {
+            let (size, piece_len) = match self.pieces[self.index] {
+                Piece::Data(ref data) => {
+                    let remaining = &data[self.offset as usize ..];
+                    let size = ::std::cmp::min(remaining.len(), buf.len());
+                    for i in 0..size {
+                        buf[i] = remaining[i];
+                    }
+                    (size, data.len() as u64)
+                },
+                Piece::Range(start, len) => {
+                    if self.offset == 0 {
+                        try!(self.source.seek(SeekFrom::Start(start)));
+                    }
+                    let remaining = len - self.offset;
+                    let want = ::std::cmp::min(remaining, buf.len() as u64);
+                    let size = try!(self.source.as_read().read(
+                                &mut buf[.. want as usize]));
+                    if size == 0 {
+                        // The source is shorter than it claimed to be
+                        return Ok(0);
+                    }
+                    (size, len)
+                },
+            };
+            self.offset += size as u64;
+            if self.offset == piece_len {
+                self.index += 1;
+                self.offset = 0;
+            }
+            if size > 0 {
+                return Ok(size);
+            }
+        }
+        return Ok(0);
+    }
+}
+
+
+#[cfg(test)]
+fn read_body(response: &mut WebResponse) -> String {
+    use std::io::Read;
+    let mut ret = Vec::new();
+    match response.body {
+        ResponseBody::Bytes(ref body) => ret.push_all(body),
+        ResponseBody::Stream(ref mut reader, len) => {
+            reader.read_to_end(&mut ret).unwrap();
+            assert_eq!(Some(ret.len() as u64), len);
+        },
+        ResponseBody::Seekable(..) => panic!("not ranged"),
+    }
+    return String::from_utf8(ret).unwrap();
+}
+
+#[cfg(test)]
+fn ranged_response(method: &str, range: Option<&str>, seekable: bool)
+        -> WebResponse {
+    let mut resp = WebResponse::new();
+    resp.set_header("Content-Type", "text/plain");
+    if seekable {
+        resp.set_body_seekable(io::Cursor::new(b"0123456789".to_vec()), 10);
+    } else {
+        resp.set_body_str("0123456789");
+    }
+    let req = match range {
+        Some(range) => WebRequest::for_test(method, "/", &[("range", range)]),
+        None => WebRequest::for_test(method, "/", &[]),
+    };
+    apply_ranges(&req, &mut resp);
+    return resp;
+}
+
+
+#[test]
+fn test_single_range() {
+    for seekable in [false, true].iter() {
+        let mut resp = ranged_response("get", Some("bytes=2-4"), *seekable);
+        assert_eq!(resp.code, 206);
+        assert_eq!(resp.get_header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(resp.get_header("Accept-Ranges"), Some("bytes"));
+        assert_eq!(read_body(&mut resp), "234");
+
+        let mut resp = ranged_response("get", Some("bytes=-3"), *seekable);
+        assert_eq!(read_body(&mut resp), "789");
+    }
+}
+
+#[test]
+fn test_multiple_ranges() {
+    for seekable in [false, true].iter() {
+        let mut resp = ranged_response("get", Some("bytes=0-1,-2"), *seekable);
+        assert_eq!(resp.code, 206);
+        assert!(resp.get_header("Content-Range").is_none());
+        let content_type = resp.get_header("Content-Type").unwrap()
+            .to_string();
+        let prefix = "multipart/byteranges; boundary=";
+        assert!(content_type.starts_with(prefix));
+        let boundary = &content_type[prefix.len()..];
+        let expected = format!("--{}\r\nContent-Type: text/plain\r\n\
+Content-Range: bytes 0-1/10\r\n\r\n01\r\n\
+--{}\r\nContent-Type: text/plain\r\n\
+Content-Range: bytes 8-9/10\r\n\r\n89\r\n\
+--{}--\r\n", boundary, boundary, boundary);
+        assert_eq!(read_body(&mut resp), expected);
+    }
+}
+
+#[test]
+fn test_overlapping_ranges() {
+    // Merged into one range, so it's a plain 206 with each byte sent once
+    for seekable in [false, true].iter() {
+        let mut resp = ranged_response("get", Some("bytes=4-6,0-3,2-5,0-"),
+                *seekable);
+        assert_eq!(resp.code, 206);
+        assert_eq!(resp.get_header("Content-Range"), Some("bytes 0-9/10"));
+        assert_eq!(read_body(&mut resp), "0123456789");
+
+        let mut resp = ranged_response("get", Some("bytes=6-7,0-1,1-2"),
+                *seekable);
+        let content_type = resp.get_header("Content-Type").unwrap()
+            .to_string();
+        let boundary = &content_type["multipart/byteranges; boundary="
+            .len()..];
+        let expected = format!("--{}\r\nContent-Type: text/plain\r\n\
+Content-Range: bytes 0-2/10\r\n\r\n012\r\n\
+--{}\r\nContent-Type: text/plain\r\n\
+Content-Range: bytes 6-7/10\r\n\r\n67\r\n\
+--{}--\r\n", boundary, boundary, boundary);
+        assert_eq!(read_body(&mut resp), expected);
+    }
+}
+
+#[test]
+fn test_no_range() {
+    // Unsatisfiable
+    let resp = ranged_response("get", Some("bytes=10-"), false);
+    assert_eq!(resp.code, 416);
+    assert_eq!(resp.get_header("Content-Range"), Some("bytes */10"));
+
+    // Ignored: invalid, not GET, no header
+    let mut resp = ranged_response("get", Some("bytes=5-1"), false);
+    assert_eq!(resp.code, 200);
+    assert_eq!(read_body(&mut resp), "0123456789");
+    let resp = ranged_response("head", Some("bytes=0-1"), false);
+    assert_eq!(resp.code, 200);
+    assert_eq!(resp.get_header("Accept-Ranges"), Some("bytes"));
+    let resp = ranged_response("get", None, true);
+    assert_eq!(resp.code, 200);
+
+    // Opted out, or not a 200
+    let mut resp = WebResponse::new();
+    resp.set_header("Accept-Ranges", "none");
+    resp.set_body_str("0123456789");
+    let req = WebRequest::for_test("get", "/", &[("range", "bytes=0-1")]);
+    apply_ranges(&req, &mut resp);
+    assert_eq!(resp.code, 200);
+    let mut resp = WebResponse::new();
+    resp.set_code(404, "Not Found");
+    let req = WebRequest::for_test("get", "/", &[("range", "bytes=0-1")]);
+    apply_ranges(&req, &mut resp);
+    assert_eq!(resp.code, 404);
+    assert!(resp.get_header("Accept-Ranges").is_none());
+}
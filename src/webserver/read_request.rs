@@ -2,22 +2,176 @@
 
 use std;
 use std::io;
-use std::ascii::OwnedAsciiExt; 
+use std::mem;
+use std::collections::HashMap;
+use std::time::Duration;
+use std::ascii::{AsciiExt, OwnedAsciiExt};
 
 use super::WebRequest;
+use super::compression::Coding;
+use utils::genericsocket;
 use utils::genericsocket::GenericSocket;
 use utils;
+use utils::clock;
+use utils::http_request;
+use utils::http_request::RequestView;
+use utils::inflate;
+use utils::inflate::InflateError;
 
 
 // Possible errors from `read_request`
 pub enum Error {
     IoError(io::Error),
+    // Connection closed or timed out before any request bytes arrived
+    NoRequest,
     InvalidRequest,
     InvalidVersion,
     LengthRequired,
+    NotImplemented,
     TooLarge,
+    // A Content-Encoding we can't decode
+    UnsupportedMediaType,
+    // Part of the request arrived, but not all of it in time
+    Timeout,
+    // Exceeded a HeaderLimits size or count
+    HeadersTooLarge,
+    // The request target exceeded HeaderLimits.max_uri
+    UriTooLong,
 }
 
+
+// Limits on the request line and headers
+#[derive(Clone, Copy)]
+pub struct HeaderLimits {
+    // Total bytes, from the request line to the blank line
+    pub max_size: usize,
+    pub max_headers: usize,
+    // Bytes in one header line, without the \r\n
+    pub max_line: usize,
+    // Bytes in the request target (path and query string)
+    pub max_uri: usize,
+}
+
+
+// Checks the request line and headers against HeaderLimits as they arrive,
+// so an oversized line is rejected without waiting for the rest
+struct HeaderChecker {
+    // Where the line not yet complete starts
+    line_start: usize,
+    nr_lines: usize,
+}
+
+impl HeaderChecker {
+    fn new() -> HeaderChecker {
+        return HeaderChecker { line_start: 0, nr_lines: 0 };
+    }
+
+    // Check the lines in head that weren't already checked.  head is all
+    // the request received so far, and may end part way through a line.
+    fn check(&mut self, head: &[u8], limits: &HeaderLimits)
+            -> Result<(), Error> {
+        loop {
+            let rest = &head[self.line_start..];
+            let end = rest.iter().position(|c| *c == b'\n');
+            let mut line = match end {
+                Some(end) => &rest[..end],
+                None => rest,
+            };
+            if line.ends_with(b"\r") {
+                line = &line[..line.len() - 1];
+            }
+            if self.nr_lines == 0 {
+                let target = line.split(|c| *c == b' ').nth(1);
+                if target.map_or(0, |t| t.len()) > limits.max_uri {
+                    return Err(Error::UriTooLong);
+                }
+            } else if !line.is_empty() {
+                if line.len() > limits.max_line
+                        || self.nr_lines > limits.max_headers {
+                    return Err(Error::HeadersTooLarge);
+                }
+            }
+            match end {
+                Some(end) => {
+                    self.line_start += end + 1;
+                    self.nr_lines += 1;
+                },
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+
+// How long reading a request may take
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+    // Waiting for the first byte of a request
+    pub idle: Duration,
+    // From the first byte to the end of the headers
+    pub header: Duration,
+    // For the body, plus a second for every body_min_rate bytes received
+    // (if not 0)
+    pub body: Duration,
+    pub body_min_rate: u64,
+}
+
+// Generous timeouts, for tests that aren't about them
+#[cfg(test)]
+fn test_timeouts() -> Timeouts {
+    return Timeouts {
+        idle: Duration::from_secs(5),
+        header: Duration::from_secs(10),
+        body: Duration::from_secs(10),
+        body_min_rate: 0,
+    };
+}
+
+
+// A limit on the total time spent reading part of a request.  Socket
+// timeouts only bound each read, so every read gets the time remaining.
+struct Deadline {
+    start_ms: u64,
+    base_ms: u64,
+    min_rate: u64,
+    received: u64,
+}
+
+impl Deadline {
+    fn new(timeout: Duration, min_rate: u64) -> Deadline {
+        return Deadline {
+            start_ms: clock::now_ms(),
+            base_ms: timeout.as_secs() * 1000
+                + (timeout.subsec_nanos() / 1_000_000) as u64,
+            min_rate: min_rate,
+            received: 0,
+        };
+    }
+
+    // The time left at now_ms, or None if it's up
+    fn remaining(&self, now_ms: u64) -> Option<Duration> {
+        let mut allowed = self.base_ms;
+        if self.min_rate > 0 {
+            allowed += self.received * 1000 / self.min_rate;
+        }
+        let elapsed = now_ms - self.start_ms;
+        if elapsed >= allowed {
+            return None;
+        }
+        return Some(Duration::from_millis(allowed - elapsed));
+    }
+}
+
+// How the request body is delimited
+enum BodyFraming {
+    NoBody,
+    ContentLength(usize),
+    Chunked,
+}
+
+// Max length of a chunk-size line, including any chunk extensions
+static MAX_CHUNK_LINE_SIZE: usize = 4096;
+
 // Auto convert io::IOError into our module specific error
 impl std::convert::From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
@@ -30,33 +184,136 @@ impl std::convert::From<io::Error> for Error {
 // Read a full request from the client (headers and body)
 // max_size: max body size
 //
+// timeouts: how long the client may take.  Idle connections get NoRequest,
+// and requests that stall part way get Timeout.
+//
+// limits: for the request line and headers.  The target is checked first, so
+// a long URL gets UriTooLong, not HeadersTooLarge.
+//
+// decompress: decode a gzip or deflate Content-Encoding of the body.  The
+// max size then also applies to the decoded body.
+//
+// recv_buffer: bytes received on this connection but not yet consumed.  It
+// may already hold (part of) the request, and on success it holds whatever
+// followed the request, e.g. the next pipelined request.
+//
 // We need a Reader+Writer, due to stupid HTTP 100-continue.
 // We transparently send the 100-Continue if expected of us.  However, the more
 // educated thing to do, for apps that actually care about this, would be to
 // call the app code first and let it validate the headers.
-pub fn read_request(stream: &mut GenericSocket, max_size: usize) 
-        -> Result<WebRequest, Error> {
-    let mut req_buffer = Vec::<u8>::with_capacity(4096);
-    let req_size = try!(read_until_headers_end(&mut req_buffer, stream));
-
-    // Try to parse it
-    let req = match utils::http_request::parse(&req_buffer[..req_size]) {
-        Err(utils::http_request::ParseError::BadVersion) => 
-            return Err(Error::InvalidVersion),
-        Err(..) => return Err(Error::InvalidRequest),
-        Ok(parsed_req) => parsed_req,
+pub fn read_request(stream: &mut GenericSocket, recv_buffer: &mut Vec<u8>,
+        max_size: usize, decompress: bool, timeouts: &Timeouts,
+        limits: &HeaderLimits) -> Result<WebRequest, Error> {
+    let req_size = try!(read_until_headers_end(recv_buffer, stream,
+                timeouts, limits));
+
+    // Try to parse it.  The headers are checked before anything is copied
+    // out of the buffer, so rejected requests cost little.
+    let (layout, framing, continue_needed) = {
+        let view = match http_request::parse_view(&recv_buffer[..req_size]) {
+            Err(http_request::ParseError::BadVersion) => 
+                return Err(Error::InvalidVersion),
+            Err(..) => return Err(Error::InvalidRequest),
+            Ok(view) => view,
+        };
+
+        // See if there's a body to read too.  
+        let framing = try!(get_body_framing(&view, max_size));
+        let continue_needed = needs_100_continue(&view);
+        (view.into_layout(), framing, continue_needed)
     };
 
-    // See if there's a body to read too.  
-    let mut body = Vec::new();
+    // The request keeps the receive buffer as its head, and the environ is
+    // only built from it if asked for.  Anything after the head is the body
+    // or the next request.
+    let mut body_buffer = recv_buffer[req_size..].to_vec();
+    let mut head = mem::replace(recv_buffer, Vec::new());
+    head.truncate(req_size);
+
+    let mut trailers = HashMap::new();
+    let body = match framing {
+        BodyFraming::NoBody => {
+            recv_buffer.push_all(&body_buffer);
+            Vec::new()
+        },
+        BodyFraming::ContentLength(clen) => {
+            if continue_needed {
+                try!(send_100_continue(stream));
+            }
+
+            // Read the body
+            let mut deadline = Deadline::new(timeouts.body,
+                    timeouts.body_min_rate);
+            try!(read_until_size(&mut body_buffer, stream, clen,
+                        &mut deadline));
+            assert!(body_buffer.len() >= clen);
+
+            // Anything past the body is the start of a pipelined request
+            recv_buffer.push_all(&body_buffer[clen..]);
+            body_buffer.truncate(clen);
+            assert!(body_buffer.len() == clen);
+            body_buffer
+        },
+        BodyFraming::Chunked => {
+            if continue_needed {
+                try!(send_100_continue(stream));
+            }
+
+            let mut deadline = Deadline::new(timeouts.body,
+                    timeouts.body_min_rate);
+            let body = try!(read_chunked_body(stream, &mut body_buffer,
+                    max_size, limits, &mut trailers, &mut deadline));
+            recv_buffer.push_all(&body_buffer);
+            body
+        },
+    };
+
+    // All done
+    let mut req = WebRequest::new(head, layout, Vec::new());
+    req.extend_environ(trailers);
+    req.body = if decompress
+            && req.get_environ_value(b"http_content-encoding").is_some() {
+        try!(decode_body(&mut req.get_environ_mut(), body, max_size))
+    } else {
+        body
+    };
+    return Ok(req);
+}
+
+
+// Figure out how the body is delimited, from the Transfer-Encoding and
+// Content-Length headers.
+fn get_body_framing(req: &RequestView, max_size: usize)
+        -> Result<BodyFraming, Error> {
+    let te = req.get_header(b"transfer-encoding");
+    let clen = req.get_header(b"content-length");
+
+    if te.is_some() {
+        // Transfer-Encoding overrides Content-Length, but sending both is a
+        // classic request smuggling trick, so refuse it.
+        if clen.is_some() {
+            return Err(Error::InvalidRequest);
+        }
 
-    // We don't currently support chunked
-    if req.environ.contains_key(&b"http_transfer-encoding"[..]) {
-        return Err(Error::LengthRequired);
+        // We only know chunked, and it must be the final (only) coding
+        let te = te.unwrap().into_owned().into_ascii_lowercase();
+        let codings = utils::byteutils::split_bytes_on(&te, b',', te.len());
+        for coding in codings.iter() {
+            if utils::byteutils::strip(coding) != b"chunked" {
+                return Err(Error::NotImplemented);
+            }
+        }
+        if codings.len() != 1 {
+            return Err(Error::InvalidRequest);
+        }
+
+        // Chunked isn't defined for HTTP/1.0, so we can't know the length
+        if req.protocol().eq_ignore_ascii_case(b"http/1.0") {
+            return Err(Error::LengthRequired);
+        }
+        return Ok(BodyFraming::Chunked);
     }
 
-    { // borrow scope for req.environ
-    let clen = req.environ.get(&b"http_content-length"[..]);
     if clen.is_some() {
         let clen = match utils::byteutils::parse_u64(&clen.unwrap()) {
             // unparseable content-length
@@ -69,49 +326,82 @@ pub fn read_request(stream: &mut GenericSocket, max_size: usize)
         }
 
         // Cast it down, as we read in memory
-        let clen = clen as usize;
+        return Ok(BodyFraming::ContentLength(clen as usize));
+    }
 
-        // Send 100-continue if needed
-        if needs_100_continue(&req) {
-            let cont = b"HTTP/1.1 100 Continue\r\n\r\n";
-            try!(stream.write_all(cont));
-        }
+    return Ok(BodyFraming::NoBody);
+}
 
-        // Start one new buffer, so we don't copy when done
-        let mut body_buffer = req_buffer[req_size..].to_vec();
-        // Can free some memory
-        drop(req_buffer);
 
-        // Read the body
-        try!(read_until_size(&mut body_buffer, stream, clen));
-        assert!(body_buffer.len() >= clen);
+// Undo the Content-Encoding of the body, if any.  The Content-Encoding
+// header is removed, and Content-Length updated, so they describe the body
+// the handler gets.
+fn decode_body(environ: &mut HashMap<Vec<u8>, Vec<u8>>, body: Vec<u8>,
+        max_size: usize) -> Result<Vec<u8>, Error> {
+    let codings = match environ.remove(&b"http_content-encoding"[..]) {
+        Some(val) => val.into_ascii_lowercase(),
+        None => return Ok(body),
+    };
 
-        // Make sure not to include an extra pipelined request
-        body_buffer.truncate(clen);
-        assert!(body_buffer.len() == clen);
+    // Check every coding first, so one we don't know is an error even if
+    // there's nothing to decode.  Codings are listed in the order they were
+    // applied.
+    let mut to_undo = Vec::new();
+    for coding in codings.split(|c| *c == b',').rev() {
+        let coding = utils::byteutils::strip(coding);
+        if coding == b"gzip" || coding == b"x-gzip" {
+            to_undo.push(Coding::Gzip);
+        } else if coding == b"deflate" {
+            to_undo.push(Coding::Deflate);
+        } else if coding != b"identity" {
+            return Err(Error::UnsupportedMediaType);
+        }
+    }
 
-        body = body_buffer;
+    // An empty body has nothing to inflate
+    let mut body = body;
+    if !body.is_empty() {
+        for coding in to_undo.iter() {
+            let decoded = match *coding {
+                Coding::Gzip => inflate::inflate_gzip(&body, max_size),
+                Coding::Deflate => {
+                    // Should be zlib format, but some clients send raw
+                    // DEFLATE
+                    if inflate::has_zlib_header(&body) {
+                        inflate::inflate_zlib(&body, max_size)
+                    } else {
+                        inflate::inflate(&body, max_size)
+                    }
+                },
+            };
+            body = match decoded {
+                Ok(decoded) => decoded,
+                Err(InflateError::Invalid) => return Err(Error::InvalidRequest),
+                Err(InflateError::TooLarge) => return Err(Error::TooLarge),
+            };
+        }
     }
+
+    if environ.contains_key(&b"http_content-length"[..]) {
+        environ.insert(b"http_content-length".to_vec(),
+                body.len().to_string().into_bytes());
     }
+    return Ok(body);
+}
 
-    // All done
-    let ret = WebRequest {
-        environ: req.environ,
-        path: req.path,
-        method: req.method,
-        body: body,
-    };
-    return Ok(ret);
+
+fn send_100_continue(stream: &mut GenericSocket) -> Result<(), io::Error> {
+    let cont = b"HTTP/1.1 100 Continue\r\n\r\n";
+    return stream.write_all(cont);
 }
 
 
-fn needs_100_continue(req: &utils::http_request::Request) -> bool {
-    let val = req.environ.get(&b"http_expect"[..]);
+fn needs_100_continue(req: &RequestView) -> bool {
+    let val = req.get_header(b"expect");
     if val.is_none() {
         return false;
     }
-    let val = val.unwrap().clone().into_ascii_lowercase();
-    if val == b"100-continue" {
+    if val.unwrap().eq_ignore_ascii_case(b"100-continue") {
         return true;
     } else {
         // Note: the RFC only defines 100-continue, and says we MAY
@@ -122,9 +412,14 @@ fn needs_100_continue(req: &utils::http_request::Request) -> bool {
 
 
 // Read until \r\n\r\n, which terminates the request headers
-// Note: extra data may be in the buffer.
+// Note: extra data may be in the buffer, both before and after the call.
+//
+// If the client goes away (or stays idle) before sending anything, that's
+// not an error (it's normal for persistent connections), and NoRequest is
+// returned.  Once the request starts, the header timeout applies.
 fn read_until_headers_end(buffer: &mut Vec<u8>,
-        stream: &mut GenericSocket) -> Result<usize, io::Error> 
+        stream: &mut GenericSocket, timeouts: &Timeouts,
+        limits: &HeaderLimits) -> Result<usize, Error> 
 {
     // Craptastic new io copying; with_extra isn't supported yet
     // and is unsafe.
@@ -132,42 +427,611 @@ fn read_until_headers_end(buffer: &mut Vec<u8>,
     let mut chunk_buff = Vec::with_capacity(chunk_size);
     chunk_buff.resize(chunk_size, 0);
 
+    let mut deadline = None;
+    let mut checker = HeaderChecker::new();
+    // Where to resume the search for the end of the headers.  It backs up 3
+    // bytes from the end of what was searched, in case the \r\n\r\n is split
+    // between reads.
+    let mut search_start = 0;
     loop { 
-        // Try to read some more data
-        let size = try!(stream.read(&mut chunk_buff));
+        // A pipelined request may already be fully buffered
+        let split_pos = utils::byteutils::memmem(&buffer[search_start..],
+                b"\r\n\r\n");
+        if split_pos.is_some() {
+            let size = search_start + split_pos.unwrap() + 4;
+            try!(checker.check(&buffer[..size], limits));
+            if size > limits.max_size {
+                return Err(Error::HeadersTooLarge);
+            }
+            return Ok(size);
+        }
+        try!(checker.check(&buffer, limits));
+        if buffer.len() >= limits.max_size {
+            return Err(Error::HeadersTooLarge);
+        }
+        search_start = buffer.len().saturating_sub(3);
+
+        if buffer.is_empty() {
+            try!(stream.set_read_timeout(Some(timeouts.idle)));
+            let size = match stream.read(&mut chunk_buff) {
+                Ok(size) => size,
+                Err(ref e) if genericsocket::is_timeout(e) => {
+                    return Err(Error::NoRequest);
+                },
+                Err(e) => return Err(Error::IoError(e)),
+            };
+            if size == 0 {
+                return Err(Error::NoRequest);
+            }
+            buffer.push_all(&chunk_buff[0..size]);
+            continue;
+        }
+
+        if deadline.is_none() {
+            deadline = Some(Deadline::new(timeouts.header, 0));
+        }
+        let size = try!(read_some(stream, &mut chunk_buff,
+                    deadline.as_mut().unwrap()));
         if size == 0 {
-            return Err(io::Error::new(
+            return Err(Error::IoError(io::Error::new(
                     io::ErrorKind::BrokenPipe,
-                    "connection closed while reading request headers"));
+                    "connection closed while reading request headers")));
         }
         buffer.push_all(&chunk_buff[0..size]);
+    }
+}
 
-        let split_pos = utils::byteutils::memmem(&buffer, b"\r\n\r\n");
-        if split_pos.is_none() {
-            continue;
+
+// Decode a chunked request body (RFC 7230 section 4.1).
+//
+// buffer: data already received after the headers.  On return, it holds
+// whatever followed the body (e.g. the next pipelined request).
+//
+// Chunk extensions are ignored.  Trailer headers are added to trailers as
+// "trailer_xxx", and are held to the same limits as the request headers.
+fn read_chunked_body(stream: &mut GenericSocket, buffer: &mut Vec<u8>,
+        max_size: usize, limits: &HeaderLimits,
+        trailers: &mut HashMap<Vec<u8>, Vec<u8>>,
+        deadline: &mut Deadline) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+
+    // Start of the unconsumed data in buffer
+    let mut pos = 0;
+
+    loop {
+        // chunk-size [ chunk-ext ] CRLF
+        let line = try!(read_line(stream, buffer, &mut pos,
+                    MAX_CHUNK_LINE_SIZE, deadline));
+        let parts = utils::byteutils::split_bytes_on(&line, b';', 1);
+        let size_str = utils::byteutils::rstrip(parts[0]);
+        let size = match utils::byteutils::parse_hex_u64(size_str) {
+            None => return Err(Error::InvalidRequest),
+            Some(size) => size,
+        };
+        if size == 0 {
+            break;
+        }
+        if size > (max_size - body.len()) as u64 {
+            return Err(Error::TooLarge);
+        }
+        let size = size as usize;
+
+        // chunk-data CRLF
+        try!(fill_buffer(stream, buffer, &mut pos, size + 2, deadline));
+        if &buffer[pos + size .. pos + size + 2] != b"\r\n" {
+            return Err(Error::InvalidRequest);
+        }
+        body.push_all(&buffer[pos .. pos + size]);
+        pos += size + 2;
+    }
+
+    // Trailer headers, terminated by an empty line
+    let mut trailer_size = 0;
+    let mut nr_trailers = 0;
+    loop {
+        // read_line's only error of its own is a line that's too long.  It
+        // only checks lines still arriving, so check whole ones here.
+        let line = match read_line(stream, buffer, &mut pos, limits.max_line,
+                deadline) {
+            Err(Error::InvalidRequest) => return Err(Error::HeadersTooLarge),
+            ret => try!(ret),
+        };
+        if line.is_empty() {
+            break;
+        }
+        trailer_size += line.len() + 2;
+        nr_trailers += 1;
+        if line.len() > limits.max_line || trailer_size > limits.max_size
+                || nr_trailers > limits.max_headers {
+            return Err(Error::HeadersTooLarge);
+        }
+        let ret = utils::http_request::parse_header_into(trailers,
+                b"trailer_", &line);
+        if ret.is_err() {
+            return Err(Error::InvalidRequest);
+        }
+    }
+
+    // Keep only what follows the body
+    let rest = buffer[pos..].to_vec();
+    *buffer = rest;
+    return Ok(body);
+}
+
+
+// Return the next line (without the \r\n) starting at pos, reading more
+// data if needed.  Lines longer than max_size are an error.
+fn read_line(stream: &mut GenericSocket, buffer: &mut Vec<u8>,
+        pos: &mut usize, max_size: usize, deadline: &mut Deadline)
+        -> Result<Vec<u8>, Error> {
+    loop {
+        let found = utils::byteutils::memmem(&buffer[*pos..], b"\r\n");
+        if found.is_some() {
+            let end = *pos + found.unwrap();
+            let line = buffer[*pos..end].to_vec();
+            *pos = end + 2;
+            return Ok(line);
+        }
+        let avail = buffer.len() - *pos;
+        if avail > max_size {
+            return Err(Error::InvalidRequest);
         }
-        return Ok(split_pos.unwrap() + 4);
+        try!(fill_buffer(stream, buffer, pos, avail + 1, deadline));
     }
 }
 
 
+// Make sure at least `size` bytes starting at pos are in the buffer.
+// Consumed data before pos is discarded first, so pos may change.
+fn fill_buffer(stream: &mut GenericSocket, buffer: &mut Vec<u8>,
+        pos: &mut usize, size: usize, deadline: &mut Deadline)
+        -> Result<(), Error> {
+    if buffer.len() - *pos >= size {
+        return Ok(());
+    }
+    let rest = buffer[*pos..].to_vec();
+    *buffer = rest;
+    *pos = 0;
+    return read_until_size(buffer, stream, size, deadline);
+}
+
+
+// Read into buf before the deadline.  Returns 0 at EOF.
+fn read_some(stream: &mut GenericSocket, buf: &mut [u8],
+        deadline: &mut Deadline) -> Result<usize, Error> {
+    let remaining = match deadline.remaining(clock::now_ms()) {
+        Some(remaining) => remaining,
+        None => return Err(Error::Timeout),
+    };
+    try!(stream.set_read_timeout(Some(remaining)));
+    return match stream.read(buf) {
+        Ok(size) => {
+            deadline.received += size as u64;
+            Ok(size)
+        },
+        Err(ref e) if genericsocket::is_timeout(e) => Err(Error::Timeout),
+        Err(e) => Err(Error::IoError(e)),
+    };
+}
+
+
 // Read until the buffer is at least size bytes long
 // Note: extra data may be in the buffer.
 fn read_until_size(buffer: &mut Vec<u8>,
-        stream: &mut GenericSocket, size: usize) -> Result<(), io::Error>
+        stream: &mut GenericSocket, size: usize, deadline: &mut Deadline)
+        -> Result<(), Error>
 {
     let chunk_size = 4096;
     let mut chunk_buff = Vec::with_capacity(chunk_size);
     chunk_buff.resize(chunk_size, 0);
 
     while buffer.len() < size {
-        let size = try!(stream.read(&mut chunk_buff));
+        let size = try!(read_some(stream, &mut chunk_buff, deadline));
         if size == 0 {
-            return Err(io::Error::new(
+            return Err(Error::IoError(io::Error::new(
                     io::ErrorKind::BrokenPipe,
-                    "connection closed while reading request body"));
+                    "connection closed while reading request body")));
         }
         buffer.push_all(&chunk_buff[0..size]);
     }
     return Ok(());
 }
+
+
+#[test]
+fn test_decode_body() {
+    let mut environ = HashMap::new();
+    let body = b"hello hello hello".to_vec();
+
+    // No Content-Encoding
+    assert_eq!(decode_body(&mut environ, body.clone(), 100).ok(),
+        Some(body.clone()));
+
+    environ.insert(b"http_content-encoding".to_vec(), b"GZIP".to_vec());
+    environ.insert(b"http_content-length".to_vec(), b"10".to_vec());
+    let gz = utils::deflate::gzip(&body);
+    assert_eq!(decode_body(&mut environ, gz, 100).ok(), Some(body.clone()));
+    assert!(environ.get(&b"http_content-encoding"[..]).is_none());
+    assert_eq!(environ.get(&b"http_content-length"[..]),
+        Some(&b"17".to_vec()));
+
+    // Applied in order: zlib, then gzip
+    environ.insert(b"http_content-encoding".to_vec(),
+        b"deflate, identity, gzip".to_vec());
+    let both = utils::deflate::gzip(&utils::deflate::zlib(&body));
+    assert_eq!(decode_body(&mut environ, both, 100).ok(), Some(body.clone()));
+
+    // Raw DEFLATE also works for "deflate"
+    environ.insert(b"http_content-encoding".to_vec(), b"deflate".to_vec());
+    let raw = utils::deflate::deflate(&body);
+    assert_eq!(decode_body(&mut environ, raw, 100).ok(), Some(body.clone()));
+
+    environ.insert(b"http_content-encoding".to_vec(), b"gzip".to_vec());
+    let gz = utils::deflate::gzip(&body);
+    match decode_body(&mut environ, gz, 16) {
+        Err(Error::TooLarge) => {},
+        _ => panic!("expected TooLarge"),
+    }
+    environ.insert(b"http_content-encoding".to_vec(), b"gzip".to_vec());
+    match decode_body(&mut environ, body.clone(), 100) {
+        Err(Error::InvalidRequest) => {},
+        _ => panic!("expected InvalidRequest"),
+    }
+    environ.insert(b"http_content-encoding".to_vec(), b"br".to_vec());
+    match decode_body(&mut environ, body.clone(), 100) {
+        Err(Error::UnsupportedMediaType) => {},
+        _ => panic!("expected UnsupportedMediaType"),
+    }
+
+    // An empty body still has its codings checked, but isn't inflated
+    environ.insert(b"http_content-encoding".to_vec(), b"gzip, br".to_vec());
+    match decode_body(&mut environ, Vec::new(), 100) {
+        Err(Error::UnsupportedMediaType) => {},
+        _ => panic!("expected UnsupportedMediaType"),
+    }
+    environ.insert(b"http_content-encoding".to_vec(), b"gzip".to_vec());
+    environ.insert(b"http_content-length".to_vec(), b"0".to_vec());
+    assert_eq!(decode_body(&mut environ, Vec::new(), 100).ok(), Some(vec![]));
+    assert!(environ.get(&b"http_content-encoding"[..]).is_none());
+    assert_eq!(environ.get(&b"http_content-length"[..]), Some(&b"0".to_vec()));
+}
+
+
+// Sends its data in the given pieces, then times out
+#[cfg(test)]
+struct TricklingSocket {
+    pieces: Vec<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl GenericSocket for TricklingSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.pieces.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "timeout"));
+        }
+        let piece = self.pieces.remove(0);
+        assert!(piece.len() <= buf.len());
+        for (i, c) in piece.iter().enumerate() {
+            buf[i] = *c;
+        }
+        return Ok(piece.len());
+    }
+    fn write_all(&mut self, _buf: &[u8]) -> Result<(), io::Error> {
+        return Ok(());
+    }
+    fn set_read_timeout(&mut self, timeout: Option<Duration>)
+            -> Result<(), io::Error> {
+        assert!(timeout.is_some());
+        return Ok(());
+    }
+}
+
+
+#[cfg(test)]
+static TEST_LIMITS: HeaderLimits = HeaderLimits {
+    max_size: 100,
+    max_headers: 3,
+    max_line: 30,
+    max_uri: 20,
+};
+
+
+#[test]
+fn test_deadline() {
+    let timeout = Duration::from_millis(2500);
+    let mut deadline = Deadline::new(timeout, 0);
+    let start = deadline.start_ms;
+    assert_eq!(deadline.remaining(start), Some(timeout));
+    assert_eq!(deadline.remaining(start + 2000),
+        Some(Duration::from_millis(500)));
+    assert_eq!(deadline.remaining(start + 2500), None);
+
+    // Every 100 bytes buys another second
+    deadline.min_rate = 100;
+    deadline.received = 250;
+    assert_eq!(deadline.remaining(start + 4000),
+        Some(Duration::from_millis(1000)));
+}
+
+#[test]
+fn test_read_timeouts() {
+    let timeouts = test_timeouts();
+    let read = |pieces: &[&[u8]]| {
+        let mut sock = TricklingSocket {
+            pieces: pieces.iter().map(|p| p.to_vec()).collect(),
+        };
+        read_request(&mut sock, &mut Vec::new(), 1000, false, &timeouts,
+                &TEST_LIMITS)
+    };
+
+    // Idle, vs. stalled part way through the headers or body
+    match read(&[]) {
+        Err(Error::NoRequest) => {},
+        _ => panic!("expected NoRequest"),
+    }
+    match read(&[b"GET / HTTP/1.1\r\n"]) {
+        Err(Error::Timeout) => {},
+        _ => panic!("expected Timeout"),
+    }
+    match read(&[b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n", b"abc"]) {
+        Err(Error::Timeout) => {},
+        _ => panic!("expected Timeout"),
+    }
+    match read(&[b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+                b"3\r\nabc\r\n"]) {
+        Err(Error::Timeout) => {},
+        _ => panic!("expected Timeout"),
+    }
+    match read(&[b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\n", b"abc"]) {
+        Ok(req) => assert_eq!(req.get_body(), b"abc"),
+        _ => panic!("expected a request"),
+    }
+}
+
+#[test]
+fn test_lazy_environ() {
+    let mut sock = TricklingSocket { pieces: vec![
+        b"POST /a%20b?q=1 HTTP/1.1\r\nX: 1\r\nTransfer-Encoding: chunked\r\n\
+X: 2\r\n\r\n3\r\nabc\r\n0\r\nT: t\r\n\r\n".to_vec()] };
+    let timeouts = test_timeouts();
+    let mut req = read_request(&mut sock, &mut Vec::new(), 1000, false,
+            &timeouts, &TEST_LIMITS).ok().unwrap();
+
+    // Lookups read the head until the environ is asked for
+    assert!(!req.environ_built.get());
+    assert_eq!(req.get_path(), "/a b");
+    assert_eq!(&*req.get_environ_value(b"method").unwrap(), b"post");
+    assert_eq!(&*req.get_environ_value(b"query_string").unwrap(), b"q=1");
+    assert_eq!(&*req.get_environ_value(b"http_x").unwrap(), b"1,2");
+    assert_eq!(&*req.get_environ_value(b"trailer_t").unwrap(), b"t");
+    assert!(req.get_environ_value(b"http_y").is_none());
+    assert!(!req.environ_built.get());
+
+    // Once built, it has everything, and changes to it are seen
+    assert_eq!(req.get_environ().get(&b"trailer_t"[..]), Some(&b"t".to_vec()));
+    assert_eq!(req.get_environ().get(&b"http_x"[..]), Some(&b"1,2".to_vec()));
+    req.get_environ_mut().insert(b"http_x".to_vec(), b"3".to_vec());
+    assert_eq!(&*req.get_environ_value(b"http_x").unwrap(), b"3");
+}
+
+#[test]
+fn test_header_limits() {
+    let timeouts = test_timeouts();
+    let read = |pieces: &[&[u8]]| {
+        let mut sock = TricklingSocket {
+            pieces: pieces.iter().map(|p| p.to_vec()).collect(),
+        };
+        read_request(&mut sock, &mut Vec::new(), 1000, false, &timeouts,
+                &TEST_LIMITS)
+    };
+    let long = [b'a'; 40];
+
+    // Just under every limit
+    match read(&[b"GET /aaaaaaaaaaaaaaaaaaa HTTP/1.1\r\n\
+a: 1\r\nb: 2\r\nc: 3456789012345678901234567\r\n\r\n"]) {
+        Ok(req) => assert_eq!(req.get_environ().get(&b"http_c"[..]),
+            Some(&b"3456789012345678901234567".to_vec())),
+        _ => panic!("expected a request"),
+    }
+
+    // A long target is noticed before the request line ends
+    match read(&[b"GET /aaaaaaaaaaaaaaaaaaaa"]) {
+        Err(Error::UriTooLong) => {},
+        _ => panic!("expected UriTooLong"),
+    }
+    match read(&[b"GET /", &long, b" HTTP/1.1\r\n\r\n"]) {
+        Err(Error::UriTooLong) => {},
+        _ => panic!("expected UriTooLong"),
+    }
+
+    // Too many headers, a long header, or too much in total
+    match read(&[b"GET / HTTP/1.1\r\na: 1\r\nb: 2\r\nc: 3\r\nd: 4\r\n\r\n"]) {
+        Err(Error::HeadersTooLarge) => {},
+        _ => panic!("expected HeadersTooLarge"),
+    }
+    match read(&[b"GET / HTTP/1.1\r\nlong: ", &long]) {
+        Err(Error::HeadersTooLarge) => {},
+        _ => panic!("expected HeadersTooLarge"),
+    }
+    let line = b"x: 123456789012345678901234567\r\n";
+    match read(&[b"GET / HTTP/1.1\r\n", line, line, line]) {
+        Err(Error::HeadersTooLarge) => {},
+        _ => panic!("expected HeadersTooLarge"),
+    }
+    match read(&[b"GET / HTTP/1.1 ", &long, &long, &long]) {
+        Err(Error::HeadersTooLarge) => {},
+        _ => panic!("expected HeadersTooLarge"),
+    }
+}
+
+#[test]
+fn test_read_split_headers() {
+    let timeouts = test_timeouts();
+    let data = b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b";
+
+    // The end of the headers may be split anywhere between reads
+    for split in 1..data.len() {
+        let mut sock = TricklingSocket {
+            pieces: vec![data[..split].to_vec(), data[split..].to_vec()],
+        };
+        let mut buffer = Vec::new();
+        match read_request(&mut sock, &mut buffer, 1000, false, &timeouts,
+                &TEST_LIMITS) {
+            Ok(req) => assert_eq!(req.get_path(), "/a"),
+            _ => panic!("expected a request"),
+        }
+        // Whatever was read past the end is kept for the next request
+        assert!(b"GET /b".starts_with(&buffer));
+    }
+
+    let mut sock = TricklingSocket {
+        pieces: data.iter().map(|c| vec![*c]).collect(),
+    };
+    let mut buffer = Vec::new();
+    match read_request(&mut sock, &mut buffer, 1000, false, &timeouts,
+            &TEST_LIMITS) {
+        Ok(req) => assert_eq!(req.get_path(), "/a"),
+        _ => panic!("expected a request"),
+    }
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_read_pipelined() {
+    let timeouts = test_timeouts();
+
+    // Both requests, and the start of the second body, arrive in one read
+    let mut sock = TricklingSocket { pieces: vec![
+        b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+POST /b HTTP/1.1\r\nContent-Length: 5\r\n\r\nde".to_vec(),
+        b"fgh".to_vec()] };
+    let mut buffer = Vec::new();
+    match read_request(&mut sock, &mut buffer, 1000, false, &timeouts,
+            &TEST_LIMITS) {
+        Ok(req) => {
+            assert_eq!(req.get_path(), "/a");
+            assert_eq!(req.get_body(), b"abc");
+        },
+        _ => panic!("expected a request"),
+    }
+    assert!(buffer.starts_with(b"POST /b"));
+    assert!(buffer.ends_with(b"\r\n\r\nde"));
+
+    match read_request(&mut sock, &mut buffer, 1000, false, &timeouts,
+            &TEST_LIMITS) {
+        Ok(req) => {
+            assert_eq!(req.get_path(), "/b");
+            assert_eq!(req.get_body(), b"defgh");
+        },
+        _ => panic!("expected a request"),
+    }
+    assert!(buffer.is_empty());
+}
+
+#[test]
+fn test_read_chunked_body() {
+    let timeouts = test_timeouts();
+    let head = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+    let read = |pieces: &[&[u8]], max_size: usize, buffer: &mut Vec<u8>| {
+        let mut sock = TricklingSocket {
+            pieces: pieces.iter().map(|p| p.to_vec()).collect(),
+        };
+        read_request(&mut sock, buffer, max_size, false, &timeouts,
+                &TEST_LIMITS)
+    };
+
+    // Extensions are skipped, trailers get their own keys, and the next
+    // pipelined request stays in the buffer
+    let mut buffer = Vec::new();
+    match read(&[head, b"3;name=\"a;b\"\r\nabc\r\nA\r\n01234",
+            b"56789\r\n0;last\r\nX-Sum: 1\r\nX-Sum: 2\r\n\r\nGET /next"],
+            100, &mut buffer) {
+        Ok(req) => {
+            assert_eq!(req.get_body(), b"abc0123456789");
+            assert_eq!(req.get_environ().get(&b"trailer_x-sum"[..]),
+                Some(&b"1,2".to_vec()));
+            assert!(req.get_environ().get(&b"http_x-sum"[..]).is_none());
+        },
+        _ => panic!("expected a request"),
+    }
+    assert_eq!(buffer, b"GET /next");
+
+    // The chunk sizes add up past the max body size
+    match read(&[head, b"8\r\n01234567\r\n8\r\n01234567\r\n0\r\n\r\n"], 10,
+            &mut Vec::new()) {
+        Err(Error::TooLarge) => {},
+        _ => panic!("expected TooLarge"),
+    }
+
+    // Chunk data not followed by CRLF
+    match read(&[head, b"3\r\nabcd\r\n0\r\n\r\n"], 100, &mut Vec::new()) {
+        Err(Error::InvalidRequest) => {},
+        _ => panic!("expected InvalidRequest"),
+    }
+
+    // A chunk-size line that never ends
+    let ext = [b'a'; 2000];
+    match read(&[head, b"1;", &ext, &ext, &ext], 100, &mut Vec::new()) {
+        Err(Error::InvalidRequest) => {},
+        _ => panic!("expected InvalidRequest"),
+    }
+
+    // Framing headers we refuse: both TE and CL (400), a coding other than
+    // chunked (501), and chunked with HTTP/1.0 (411)
+    match read(&[b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+Content-Length: 5\r\n\r\n0\r\n\r\n"], 100, &mut Vec::new()) {
+        Err(Error::InvalidRequest) => {},
+        _ => panic!("expected InvalidRequest"),
+    }
+    match read(&[b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"], 100,
+            &mut Vec::new()) {
+        Err(Error::NotImplemented) => {},
+        _ => panic!("expected NotImplemented"),
+    }
+    match read(&[b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n\
+0\r\n\r\n"], 100, &mut Vec::new()) {
+        Err(Error::LengthRequired) => {},
+        _ => panic!("expected LengthRequired"),
+    }
+}
+
+#[test]
+fn test_trailer_limits() {
+    let timeouts = test_timeouts();
+    let limits = HeaderLimits {
+        max_size: 50,
+        max_headers: 3,
+        max_line: 30,
+        max_uri: 20,
+    };
+    let read = |trailers: &[u8]| {
+        let mut data = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+0\r\n".to_vec();
+        data.push_all(trailers);
+        let mut sock = TricklingSocket { pieces: vec![data] };
+        read_request(&mut sock, &mut Vec::new(), 1000, false, &timeouts,
+                &limits)
+    };
+
+    // Just under every limit
+    match read(b"a: 1\r\nb: 2\r\nc: 345678901234567890123\r\n\r\n") {
+        Ok(req) => assert_eq!(req.get_environ().get(&b"trailer_c"[..]),
+            Some(&b"345678901234567890123".to_vec())),
+        _ => panic!("expected a request"),
+    }
+
+    // A long trailer, too many, or too much in total
+    match read(b"x: 1234567890123456789012345678\r\n\r\n") {
+        Err(Error::HeadersTooLarge) => {},
+        _ => panic!("expected HeadersTooLarge"),
+    }
+    match read(b"a: 1\r\nb: 2\r\nc: 3\r\nd: 4\r\n\r\n") {
+        Err(Error::HeadersTooLarge) => {},
+        _ => panic!("expected HeadersTooLarge"),
+    }
+    match read(b"a: 12345678901234567890123\r\n\
+b: 12345678901234567890123\r\n\r\n") {
+        Err(Error::HeadersTooLarge) => {},
+        _ => panic!("expected HeadersTooLarge"),
+    }
+}
@@ -1,69 +1,148 @@
+//! Maps request paths to handler functions.
+//!
+//! Rules are compiled into a radix tree (literal edges are compressed byte
+//! strings), so matching costs about the length of the path, not the number
+//! of rules.
+
 use std::collections::HashSet;
 use std::ascii::OwnedAsciiExt;
+use std::mem;
 
 use super::PageFunction;
 use super::WebRequest;
 
 
 pub enum RoutingResult {
-    FoundRule(PageFunction),
+    // The handler, and values captured by the path pattern
+    FoundRule(PageFunction, Vec<(String, String)>),
     NoPathMatch,
     NoMethodMatch(Vec<String>),
 }
 
-struct Rule {
-    path: String,
-    is_prefix: bool,
-    methods: Vec<String>,
+// A pattern like "/users/:id/files/*rest" is compiled into the tokens
+// [Literal("/users/"), Param, Literal("/files/")] ending with a wildcard.
+enum Token {
+    Literal(Vec<u8>),
+    // ":name" matches one non-empty segment
+    Param,
+}
+
+// Where a rule's handler hangs off the final node
+#[derive(Clone, Copy)]
+enum EndKind {
+    Exact,
+    // "*name" matches the rest of the path, and must be last
+    Wildcard,
+    Prefix,
+}
+
+// A handler for one method
+struct Handler {
+    method: String,
     page_fn: PageFunction,
+    // Names for the captured values, in order
+    param_names: Vec<String>,
+    // The rule as added, for error messages
+    path: String,
+}
+
+struct Node {
+    // Literal bytes on the edge into this node
+    label: Vec<u8>,
+    // Literal children, each starting with a different byte
+    children: Vec<Node>,
+    // A ":name" segment starting here.  Only exists if the path up to here
+    // ends with '/'.
+    param: Option<Box<Node>>,
+    // Handlers for paths ending here, "*name" starting here, and prefix
+    // rules ending here
+    exact: Vec<Handler>,
+    wildcard: Vec<Handler>,
+    prefix: Vec<Handler>,
+}
+
+impl Node {
+    fn new(label: Vec<u8>) -> Node {
+        Node {
+            label: label,
+            children: Vec::new(),
+            param: None,
+            exact: Vec::new(),
+            wildcard: Vec::new(),
+            prefix: Vec::new(),
+        }
+    }
 }
 
 
 pub struct Router {
-    rules: Vec<Rule>
+    root: Node,
 }
 
 
 impl Router {
     pub fn new() -> Router {
-        Router { rules: Vec::new() }
+        Router { root: Node::new(Vec::new()) }
     }
 
+    // Panics if the path is an invalid pattern, or if another rule with the
+    // same shape already handles one of the methods.
     pub fn add_path(&mut self, methods: &str, path: &str, 
             page_fn: PageFunction, is_prefix: bool) {
-        let rule = Rule { 
-            path: path.to_string(), 
-            is_prefix: is_prefix,
-            page_fn: page_fn,
-            methods: parse_methods(methods),
+        let (tokens, end_kind, param_names) = {
+            if is_prefix {
+                (vec![Token::Literal(path.as_bytes().to_vec())],
+                 EndKind::Prefix, Vec::new())
+            } else if path.starts_with("/") {
+                parse_pattern(path)
+            } else {
+                // Paths that aren't rooted at '/', i.e. OPTIONS '*'
+                (vec![Token::Literal(path.as_bytes().to_vec())],
+                 EndKind::Exact, Vec::new())
+            }
         };
-        self.rules.push(rule);
+
+        for method in parse_methods(methods) {
+            let handler = Handler {
+                method: method,
+                page_fn: page_fn.clone(),
+                param_names: param_names.clone(),
+                path: path.to_string(),
+            };
+            insert(&mut self.root, &tokens, end_kind, handler);
+        }
     }
 
+    // When several rules match a path, the most specific one that allows the
+    // request method wins, regardless of the order they were added in.
+    // Literal segments beat params, which beat wildcards; prefix rules come
+    // last, longest prefix first.
     pub fn route(&self, req: &WebRequest) -> RoutingResult {
         let mut found_path_match = false;
         let mut found_methods = HashSet::<&str>::new();
 
-        for rule in self.rules.iter() {
-            let matched = {
-                if rule.is_prefix {
-                    req.path.starts_with(&rule.path)
-                } else {
-                    req.path == rule.path
-                }
-            };
-            if matched {
-                found_path_match = true;
-                // Now check methods
-                for method in rule.methods.iter() {
-                    if *method == req.method {
-                        // Found a rule match
-                        return RoutingResult::FoundRule(rule.page_fn);
-                    }
+        // Exact and pattern rules, searched most specific first
+        let mut values = Vec::new();
+        let ret = search(&self.root, &req.path, 0, &req.method,
+                &mut values, &mut found_path_match, &mut found_methods);
+        if ret.is_some() {
+            let (handler, values) = ret.unwrap();
+            let mut params = Vec::new();
+            for (name, value) in handler.param_names.iter().zip(values.iter()) {
+                params.push((name.to_string(), value.to_string()));
+            }
+            return RoutingResult::FoundRule(handler.page_fn.clone(), params);
+        }
 
-                    // Method doesn't match, but save it for possible error
-                    found_methods.insert(&method);
-                }
+        // Prefix rules, longest first
+        let prefix_lists = collect_prefixes(&self.root, &req.path);
+        for handlers in prefix_lists.iter().rev() {
+            found_path_match = true;
+            let ret = check_methods(handlers, &req.method, 
+                    &mut found_methods);
+            if ret.is_some() {
+                return RoutingResult::FoundRule(ret.unwrap().page_fn.clone(),
+                        Vec::new());
             }
         }
 
@@ -92,3 +171,481 @@ fn parse_methods(methods: &str) -> Vec<String> {
     }
     return ret;
 }
+
+
+// Compile a pattern like "/users/:id/files/*rest" into tokens.
+// Return: (tokens, how it ends, param names)
+fn parse_pattern(path: &str) -> (Vec<Token>, EndKind, Vec<String>) {
+    let parts: Vec<&str> = path[1..].split('/').collect();
+    let mut tokens = Vec::new();
+    let mut end_kind = EndKind::Exact;
+    let mut names = Vec::<String>::new();
+
+    // Literal bytes not yet turned into a token
+    let mut literal = b"/".to_vec();
+
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            literal.push(b'/');
+        }
+        if part.starts_with(":") || part.starts_with("*") {
+            let name = part[1..].to_string();
+            if name.is_empty() {
+                panic!("route {}: missing param name", path);
+            }
+            if names.contains(&name) {
+                panic!("route {}: duplicate param name {}", path, name);
+            }
+            names.push(name);
+            tokens.push(Token::Literal(literal));
+            literal = Vec::new();
+            if part.starts_with(":") {
+                tokens.push(Token::Param);
+            } else {
+                if i != parts.len() - 1 {
+                    panic!("route {}: wildcard must be last", path);
+                }
+                end_kind = EndKind::Wildcard;
+            }
+        } else {
+            literal.push_all(part.as_bytes());
+        }
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+    return (tokens, end_kind, names);
+}
+
+
+// Add a handler at the end of the tokens, creating nodes as needed
+fn insert(node: &mut Node, tokens: &[Token], end_kind: EndKind,
+        handler: Handler) {
+    if tokens.is_empty() {
+        let handlers = match end_kind {
+            EndKind::Exact => &mut node.exact,
+            EndKind::Wildcard => &mut node.wildcard,
+            EndKind::Prefix => &mut node.prefix,
+        };
+        // Same node and kind means the rules match exactly the same paths
+        for other in handlers.iter() {
+            if other.method == handler.method {
+                panic!("route {} {} conflicts with {}",
+                    handler.method, handler.path, other.path);
+            }
+        }
+        handlers.push(handler);
+        return;
+    }
+
+    match tokens[0] {
+        Token::Literal(ref lit) => {
+            let child = insert_literal(node, lit);
+            insert(child, &tokens[1..], end_kind, handler);
+        },
+        Token::Param => {
+            if node.param.is_none() {
+                node.param = Some(Box::new(Node::new(Vec::new())));
+            }
+            let child = &mut **node.param.as_mut().unwrap();
+            insert(child, &tokens[1..], end_kind, handler);
+        },
+    }
+}
+
+
+// Follow (or create) literal edges for lit, splitting edges as needed so a
+// node ends exactly at the end of lit.  Returns that node.
+fn insert_literal<'a>(node: &'a mut Node, lit: &[u8]) -> &'a mut Node {
+    if lit.is_empty() {
+        return node;
+    }
+
+    let idx = node.children.iter().position(|c| c.label[0] == lit[0]);
+    match idx {
+        None => {
+            node.children.push(Node::new(lit.to_vec()));
+            let last = node.children.len() - 1;
+            return &mut node.children[last];
+        },
+        Some(i) => {
+            let common = common_prefix_len(&node.children[i].label, lit);
+            if common < node.children[i].label.len() {
+                split_node(&mut node.children[i], common);
+            }
+            return insert_literal(&mut node.children[i], &lit[common..]);
+        },
+    }
+}
+
+
+// Split a node's edge at `at`; the node keeps the first part, and a new
+// child takes the rest of the edge along with everything below it.
+fn split_node(node: &mut Node, at: usize) {
+    let mut rest = Node::new(node.label[at..].to_vec());
+    rest.children = mem::replace(&mut node.children, Vec::new());
+    rest.param = node.param.take();
+    rest.exact = mem::replace(&mut node.exact, Vec::new());
+    rest.wildcard = mem::replace(&mut node.wildcard, Vec::new());
+    rest.prefix = mem::replace(&mut node.prefix, Vec::new());
+    node.label.truncate(at);
+    node.children.push(rest);
+}
+
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    let mut i = 0;
+    while i < a.len() && i < b.len() && a[i] == b[i] {
+        i += 1;
+    }
+    return i;
+}
+
+
+// Return the handler for method, or save the methods for a possible 405
+fn check_methods<'a>(handlers: &'a [Handler], method: &str,
+        found_methods: &mut HashSet<&'a str>) -> Option<&'a Handler> {
+    for handler in handlers.iter() {
+        if handler.method == method {
+            return Some(handler);
+        }
+        found_methods.insert(&handler.method);
+    }
+    return None;
+}
+
+
+// Depth first search of exact and pattern rules matching path[pos..], most
+// specific first: at each node, the path ending here, then literal children,
+// then a param, then a wildcard.  The label of node has already been matched.
+//
+// values: the param values captured so far
+// Returns the handler and all captured values.
+fn search<'a, 'p>(node: &'a Node, path: &'p str, pos: usize, method: &str,
+        values: &mut Vec<&'p str>, found_path_match: &mut bool,
+        found_methods: &mut HashSet<&'a str>) 
+        -> Option<(&'a Handler, Vec<&'p str>)> {
+    let rest = &path.as_bytes()[pos..];
+
+    if rest.is_empty() {
+        if !node.exact.is_empty() {
+            *found_path_match = true;
+            let ret = check_methods(&node.exact, method, found_methods);
+            if ret.is_some() {
+                return Some((ret.unwrap(), values.clone()));
+            }
+        }
+    } else {
+        // At most one literal child can start with the next byte
+        for child in node.children.iter() {
+            if rest.starts_with(&child.label) {
+                let ret = search(child, path, pos + child.label.len(), method,
+                        values, found_path_match, found_methods);
+                if ret.is_some() {
+                    return ret;
+                }
+                break;
+            }
+        }
+
+        if node.param.is_some() {
+            let len = match rest.iter().position(|c| *c == b'/') {
+                Some(len) => len,
+                None => rest.len(),
+            };
+            if len > 0 {
+                values.push(&path[pos .. pos + len]);
+                let ret = search(node.param.as_ref().unwrap(), path, pos + len,
+                        method, values, found_path_match, found_methods);
+                if ret.is_some() {
+                    return ret;
+                }
+                values.pop();
+            }
+        }
+    }
+
+    // A wildcard also matches an empty rest
+    if !node.wildcard.is_empty() {
+        *found_path_match = true;
+        let ret = check_methods(&node.wildcard, method, found_methods);
+        if ret.is_some() {
+            let mut values = values.clone();
+            values.push(&path[pos..]);
+            return Some((ret.unwrap(), values));
+        }
+    }
+    return None;
+}
+
+
+// Return the prefix rule handlers matching path, shortest prefix first
+fn collect_prefixes<'a>(root: &'a Node, path: &str) -> Vec<&'a [Handler]> {
+    let path = path.as_bytes();
+    let mut ret = Vec::new();
+    let mut node = root;
+    let mut pos = 0;
+    loop {
+        if !node.prefix.is_empty() {
+            ret.push(&node.prefix[..]);
+        }
+        let mut next = None;
+        for child in node.children.iter() {
+            if path[pos..].starts_with(&child.label) {
+                next = Some(child);
+                break;
+            }
+        }
+        match next {
+            Some(child) => {
+                pos += child.label.len();
+                node = child;
+            },
+            None => return ret,
+        }
+    }
+}
+
+
+#[cfg(test)]
+use std::sync::Arc;
+
+#[cfg(test)]
+fn route_code(router: &Router, method: &str, path: &str) -> i32 {
+    let req = WebRequest::for_test(method, path, &[]);
+    match router.route(&req) {
+        RoutingResult::FoundRule(page_fn, _) => (*page_fn)(&req).code,
+        RoutingResult::NoPathMatch => 404,
+        RoutingResult::NoMethodMatch(..) => 405,
+    }
+}
+
+#[cfg(test)]
+fn page_1(_req: &WebRequest) -> super::WebResponse {
+    let mut resp = super::WebResponse::new();
+    resp.set_code(1, "");
+    return resp;
+}
+
+#[cfg(test)]
+fn page_2(_req: &WebRequest) -> super::WebResponse {
+    let mut resp = super::WebResponse::new();
+    resp.set_code(2, "");
+    return resp;
+}
+
+#[cfg(test)]
+fn page_3(_req: &WebRequest) -> super::WebResponse {
+    let mut resp = super::WebResponse::new();
+    resp.set_code(3, "");
+    return resp;
+}
+
+
+// A linear scan over the rules, like the router before the tree, to check
+// and benchmark against.  Returns the index of the first matching rule.
+// Only literal and ":name" segments are supported.
+#[cfg(test)]
+fn route_linear(rules: &[String], path: &str) -> Option<usize> {
+    for (i, pattern) in rules.iter().enumerate() {
+        if pattern_matches(pattern, path) {
+            return Some(i);
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let mut parts = pattern.split('/');
+    let mut segments = path.split('/');
+    loop {
+        match (parts.next(), segments.next()) {
+            (None, None) => return true,
+            (Some(part), Some(segment)) => {
+                if part.starts_with(":") {
+                    if segment.is_empty() {
+                        return false;
+                    }
+                } else if part != segment {
+                    return false;
+                }
+            },
+            _ => return false,
+        }
+    }
+}
+
+// A few hundred API style rules, like the demo's /bench/route set, each
+// routed to a handler answering with its index as the code
+#[cfg(test)]
+fn bench_router() -> (Router, Vec<String>) {
+    let mut r = Router::new();
+    let mut rules = Vec::new();
+    for i in 0..300 {
+        let pattern = format!("/api/v1/resource{}/:id/items/:item_id", i);
+        r.add_path("get, put, delete", &pattern,
+                Arc::new(move |_req: &WebRequest| {
+                    let mut resp = super::WebResponse::new();
+                    resp.set_code(i, "");
+                    resp
+                }), false);
+        rules.push(pattern);
+    }
+    return (r, rules);
+}
+
+
+#[test]
+fn test_route_params() {
+    let mut r = Router::new();
+    r.add_path("get", "/users/:id/posts/:post_id", Arc::new(page_1), false);
+    r.add_path("get", "/files/*rest", Arc::new(page_2), false);
+
+    let req = WebRequest::for_test("get", "/users/42/posts/7", &[]);
+    match r.route(&req) {
+        RoutingResult::FoundRule(_, params) => {
+            assert_eq!(params, vec![("id".to_string(), "42".to_string()),
+                ("post_id".to_string(), "7".to_string())]);
+        },
+        _ => panic!("no match"),
+    }
+
+    let req = WebRequest::for_test("get", "/files/a/b/c.txt", &[]);
+    match r.route(&req) {
+        RoutingResult::FoundRule(_, params) => {
+            assert_eq!(params, vec![("rest".to_string(),
+                "a/b/c.txt".to_string())]);
+        },
+        _ => panic!("no match"),
+    }
+
+    assert_eq!(route_code(&r, "get", "/files/"), 2);
+    assert_eq!(route_code(&r, "get", "/files"), 404);
+    assert_eq!(route_code(&r, "get", "/users//posts/7"), 404);
+    assert_eq!(route_code(&r, "get", "/users/42/posts/7/"), 404);
+    assert_eq!(route_code(&r, "post", "/users/42/posts/7"), 405);
+}
+
+#[test]
+fn test_route_priority() {
+    // Most specific wins, regardless of insertion order
+    let mut r = Router::new();
+    r.add_path("get", "/users/", Arc::new(page_3), true);
+    r.add_path("get", "/users/:id", Arc::new(page_2), false);
+    r.add_path("get", "/users/me", Arc::new(page_1), false);
+    assert_eq!(route_code(&r, "get", "/users/me"), 1);
+    assert_eq!(route_code(&r, "get", "/users/42"), 2);
+    assert_eq!(route_code(&r, "get", "/users/42/x"), 3);
+
+    // Falls through to a less specific rule if the method doesn't match
+    r.add_path("post", "/users/you", Arc::new(page_1), false);
+    assert_eq!(route_code(&r, "get", "/users/you"), 2);
+
+    // Longest prefix wins
+    let mut r = Router::new();
+    r.add_path("get", "/a/*rest", Arc::new(page_1), false);
+    r.add_path("get", "/a/", Arc::new(page_2), true);
+    r.add_path("get", "/a/b/", Arc::new(page_3), true);
+    assert_eq!(route_code(&r, "get", "/a/b/c"), 1);
+    assert_eq!(route_code(&r, "get", "/a"), 404);
+
+    let mut r = Router::new();
+    r.add_path("get", "/a/", Arc::new(page_2), true);
+    r.add_path("get", "/a/b/", Arc::new(page_3), true);
+    assert_eq!(route_code(&r, "get", "/a/b/c"), 3);
+    assert_eq!(route_code(&r, "get", "/a/c"), 2);
+}
+
+#[test]
+fn test_route_backtrack() {
+    // Literal edges split mid-segment, and params are tried when a literal
+    // path dead-ends
+    let mut r = Router::new();
+    r.add_path("get", "/ab", Arc::new(page_1), false);
+    r.add_path("get", "/abc/x", Arc::new(page_2), false);
+    r.add_path("get", "/:name", Arc::new(page_3), false);
+    r.add_path("options", "*", Arc::new(page_1), false);
+    assert_eq!(route_code(&r, "get", "/ab"), 1);
+    assert_eq!(route_code(&r, "get", "/abc"), 3);
+    assert_eq!(route_code(&r, "get", "/a"), 3);
+    assert_eq!(route_code(&r, "get", "/abc/x"), 2);
+    assert_eq!(route_code(&r, "get", "/abc/y"), 404);
+    assert_eq!(route_code(&r, "options", "*"), 1);
+
+    // Only OPTIONS may have a '*' target, so fake one past the parser
+    let mut req = WebRequest::for_test("get", "/", &[]);
+    req.path = "*".to_string();
+    match r.route(&req) {
+        RoutingResult::NoMethodMatch(..) => {},
+        _ => panic!("expected 405"),
+    }
+
+    // Allow list collects methods from every matching rule
+    r.add_path("put", "/:other", Arc::new(page_1), false);
+    let req = WebRequest::for_test("delete", "/ab", &[]);
+    match r.route(&req) {
+        RoutingResult::NoMethodMatch(mut methods) => {
+            methods.sort();
+            assert_eq!(methods, vec!["get".to_string(), "put".to_string()]);
+        },
+        _ => panic!("expected 405"),
+    }
+}
+
+#[test]
+#[should_panic]
+fn test_route_conflict() {
+    let mut r = Router::new();
+    r.add_path("get, post", "/users/:id", Arc::new(page_1), false);
+    r.add_path("put, post", "/users/:name", Arc::new(page_2), false);
+}
+
+#[test]
+fn test_route_no_conflict() {
+    let mut r = Router::new();
+    r.add_path("get", "/users/:id", Arc::new(page_1), false);
+    r.add_path("put", "/users/:name", Arc::new(page_2), false);
+    r.add_path("get", "/users/:id/x", Arc::new(page_1), false);
+    r.add_path("get", "/users/*rest", Arc::new(page_1), false);
+    assert_eq!(route_code(&r, "put", "/users/1"), 2);
+}
+
+#[test]
+#[should_panic]
+fn test_route_bad_wildcard() {
+    let mut r = Router::new();
+    r.add_path("get", "/files/*rest/x", Arc::new(page_1), false);
+}
+
+#[test]
+fn test_route_matches_linear() {
+    let (r, rules) = bench_router();
+    for path in ["/api/v1/resource0/1/items/2",
+                 "/api/v1/resource299/abc/items/x",
+                 "/api/v1/resource30/1/items/2",
+                 "/api/v1/resource300/1/items/2",
+                 "/api/v1/resource5/1/items",
+                 "/api/v1/resource5//items/2"].iter() {
+        let expected = match route_linear(&rules, path) {
+            Some(i) => i as i32,
+            None => 404,
+        };
+        assert_eq!(route_code(&r, "get", path), expected);
+    }
+}
+
+#[bench]
+fn bench_route_300(b: &mut ::test::Bencher) {
+    let (r, _) = bench_router();
+    let req = WebRequest::for_test("get", "/api/v1/resource250/42/items/7",
+            &[]);
+    b.iter(|| r.route(::test::black_box(&req)));
+}
+
+#[bench]
+fn bench_route_linear_300(b: &mut ::test::Bencher) {
+    let (_, rules) = bench_router();
+    b.iter(|| route_linear(&rules,
+                ::test::black_box("/api/v1/resource250/42/items/7")));
+}
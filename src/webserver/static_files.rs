@@ -0,0 +1,372 @@
+//! Serving the files in a directory
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::{WebRequest, WebResponse};
+use utils::byteutils;
+use utils::escape::html_element_escape;
+use utils::mime;
+use utils::etag::ETag;
+
+
+/// Serves the files under a directory, for requests under a URL prefix.
+///
+/// ```ignore
+/// let mut files = StaticFiles::new("/static/", "./public");
+/// files.set_directory_listing(true);
+/// server.add_static_files(files);
+/// ```
+///
+/// The request path is percent decoded, and then `.` and `..` segments are
+/// resolved, so a request can't refer to anything above the directory.
+/// Symlinks are followed, but only if they resolve to somewhere inside the
+/// directory; otherwise the client gets a 404, as if the file didn't exist.
+///
+/// The Content-Type is guessed from the file extension, with a built-in
+/// table.  Unknown types are sent as "application/octet-stream".  Files get
+/// Last-Modified and ETag headers (from the modification time and size), so
+/// conditional and Range requests work.
+pub struct StaticFiles {
+    url_prefix: String,
+    root: PathBuf,
+    index_files: Vec<String>,
+    directory_listing: bool,
+}
+
+impl StaticFiles {
+    /// url_prefix: URL path to serve the files under.  Must end with '/'.
+    ///
+    /// root: the directory to serve.
+    ///
+    /// # Panics
+    /// If `url_prefix` doesn't end with '/', or `root` doesn't exist.
+    pub fn new(url_prefix: &str, root: &str) -> StaticFiles {
+        if !url_prefix.starts_with("/") || !url_prefix.ends_with("/") {
+            panic!("static files prefix {}: must start and end with /",
+                    url_prefix);
+        }
+        // Resolve the root now, so it can be compared with resolved paths
+        let root_path = match fs::canonicalize(root) {
+            Ok(path) => path,
+            Err(e) => panic!("static files root {}: {}", root, e),
+        };
+        return StaticFiles {
+            url_prefix: url_prefix.to_string(),
+            root: root_path,
+            index_files: vec!["index.html".to_string()],
+            directory_listing: false,
+        };
+    }
+
+    /// File names to look for (in order) when a directory is requested.
+    /// Default: "index.html"
+    pub fn set_index_files(&mut self, names: &[&str]) {
+        self.index_files = names.iter().map(|s| s.to_string()).collect();
+    }
+
+    /// If a directory without an index file is requested, send an HTML list
+    /// of its contents instead of a 403 error.  Dot files, and symlinks
+    /// that lead outside the root, aren't listed.  Default: false
+    pub fn set_directory_listing(&mut self, enabled: bool) {
+        self.directory_listing = enabled;
+    }
+
+    /// The URL prefix
+    pub fn get_url_prefix(&self) -> &str {
+        return &self.url_prefix;
+    }
+
+    /// Respond to a GET or HEAD request for a path under the URL prefix.
+    /// `WebServer::add_static_files` routes requests here; call it directly
+    /// to wrap it in your own handler.
+    pub fn serve(&self, req: &WebRequest) -> WebResponse {
+        let path = req.get_path();
+        if !path.starts_with(&self.url_prefix) {
+            return error_response(404, "Not Found");
+        }
+        let segments = match normalize_path(&path[self.url_prefix.len()..]) {
+            Some(segments) => segments,
+            None => return error_response(404, "Not Found"),
+        };
+        let mut fs_path = self.root.clone();
+        for segment in segments.iter() {
+            fs_path.push(segment);
+        }
+
+        let fs_path = match self.resolve(&fs_path) {
+            Some(fs_path) => fs_path,
+            None => return error_response(404, "Not Found"),
+        };
+        let metadata = match fs::metadata(&fs_path) {
+            Ok(metadata) => metadata,
+            Err(_) => return error_response(404, "Not Found"),
+        };
+        if !metadata.is_dir() {
+            return serve_file(&fs_path, &metadata);
+        }
+
+        // Relative links in a directory index need the trailing slash
+        if !path.ends_with("/") {
+            return redirect_to_directory(req);
+        }
+        for name in self.index_files.iter() {
+            match self.resolve(&fs_path.join(name)) {
+                Some(index_path) => {
+                    match fs::metadata(&index_path) {
+                        Ok(ref metadata) if metadata.is_file() => {
+                            return serve_file(&index_path, metadata);
+                        },
+                        _ => {},
+                    }
+                },
+                None => {},
+            }
+        }
+        if self.directory_listing {
+            return self.list_directory(&fs_path, path);
+        }
+        return error_response(403, "Forbidden");
+    }
+
+    // Resolve symlinks (and check existence).  Returns None unless the
+    // result is inside the root.
+    fn resolve(&self, fs_path: &Path) -> Option<PathBuf> {
+        let resolved = match fs::canonicalize(fs_path) {
+            Ok(resolved) => resolved,
+            Err(_) => return None,
+        };
+        if !resolved.starts_with(&self.root) {
+            return None;
+        }
+        return Some(resolved);
+    }
+
+    // An HTML page linking to each entry in the directory, sorted by name.
+    // Hidden (dot) files, and symlinks that lead out of the root, are left
+    // out, since they can't be fetched anyway.
+    fn list_directory(&self, fs_path: &Path, url_path: &str) -> WebResponse {
+        let entries = match fs::read_dir(fs_path) {
+            Ok(entries) => entries,
+            Err(_) => return error_response(404, "Not Found"),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(".") {
+                continue;
+            }
+            let resolved = match self.resolve(&entry.path()) {
+                Some(resolved) => resolved,
+                None => continue,
+            };
+            let is_dir = match fs::metadata(&resolved) {
+                Ok(metadata) => metadata.is_dir(),
+                Err(_) => continue,
+            };
+            names.push((name, is_dir));
+        }
+        names.sort();
+
+        let title = html_element_escape(&format!("Index of {}", url_path));
+        let mut page = String::new();
+        page.push_str(&format!("<html><head><title>{}</title></head>\
+                               <body><h1>{}</h1><ul>", title, title));
+        if url_path != self.url_prefix {
+            page.push_str("<li><a href=\"../\">../</a></li>");
+        }
+        for &(ref name, is_dir) in names.iter() {
+            let slash = if is_dir { "/" } else { "" };
+            page.push_str(&format!("<li><a href=\"{}{}\">{}{}</a></li>",
+                        byteutils::percent_encode(name.as_bytes()), slash,
+                        html_element_escape(name), slash));
+        }
+        page.push_str("</ul></body></html>");
+        return WebResponse::new_html(page);
+    }
+}
+
+
+// Split a decoded relative path into segments, resolving "." and "..".
+// ".." at the top is ignored, like browsers do.  Returns None for segments
+// that can't be file names.
+fn normalize_path(path: &str) -> Option<Vec<&str>> {
+    let mut ret = Vec::new();
+    for segment in path.split('/') {
+        if segment.contains('\0') || segment.contains('\\') {
+            return None;
+        }
+        match segment {
+            "" | "." => {},
+            ".." => { ret.pop(); },
+            _ => ret.push(segment),
+        }
+    }
+    return Some(ret);
+}
+
+
+fn serve_file(fs_path: &Path, metadata: &fs::Metadata) -> WebResponse {
+    let file = match fs::File::open(fs_path) {
+        Ok(file) => file,
+        Err(_) => return error_response(404, "Not Found"),
+    };
+    let file_name = fs_path.to_string_lossy();
+    let mut ret = WebResponse::new();
+    ret.set_header("Content-Type", mime::guess_mime_type(&file_name));
+    match modified_time(metadata) {
+        Some(mtime) => {
+            ret.set_last_modified(mtime);
+            let tag = format!("{:x}-{:x}", mtime, metadata.len());
+            ret.set_etag(&ETag::strong(&tag));
+        },
+        None => {},
+    }
+    ret.set_body_seekable(file, metadata.len());
+    return ret;
+}
+
+
+// The file modification time, in seconds since the Unix epoch
+#[cfg(unix)]
+fn modified_time(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    let mtime = metadata.mtime();
+    if mtime < 0 {
+        return None;
+    }
+    return Some(mtime as u64);
+}
+
+#[cfg(not(unix))]
+fn modified_time(_metadata: &fs::Metadata) -> Option<u64> {
+    return None;
+}
+
+
+// Redirect "/dir" to "/dir/", keeping the query string
+fn redirect_to_directory(req: &WebRequest) -> WebResponse {
+    let mut location = req.get_environ_value(b"path").unwrap().into_owned();
+    location.push(b'/');
+    let query_string = req.get_environ_value(b"query_string").unwrap();
+    if !query_string.is_empty() {
+        location.push(b'?');
+        location.push_all(&query_string);
+    }
+    let mut ret = WebResponse::new();
+    ret.set_code(301, "Moved Permanently");
+    ret.set_header("Location", &String::from_utf8_lossy(&location));
+    ret.set_body_str("Error 301: Moved Permanently");
+    return ret;
+}
+
+
+fn error_response(code: i32, status: &str) -> WebResponse {
+    let mut ret = WebResponse::new();
+    ret.set_code(code, status);
+    ret.set_body_str(&format!("Error {}: {}", code, status));
+    return ret;
+}
+
+
+#[test]
+fn test_normalize_path() {
+    assert_eq!(normalize_path("a/b/c.txt"), Some(vec!["a", "b", "c.txt"]));
+    assert_eq!(normalize_path("a//./b/"), Some(vec!["a", "b"]));
+    assert_eq!(normalize_path("a/../../../b"), Some(vec!["b"]));
+    assert_eq!(normalize_path("../.."), Some(vec![]));
+    assert_eq!(normalize_path(""), Some(vec![]));
+    assert_eq!(normalize_path("..."), Some(vec!["..."]));
+    assert_eq!(normalize_path("a\\..\\b"), None);
+    assert_eq!(normalize_path("a\0b"), None);
+}
+
+// The body of a response, whether in memory or a file
+#[cfg(test)]
+fn body_string(resp: &mut WebResponse) -> String {
+    use std::io::Read;
+    use super::ResponseBody;
+
+    let mut body = Vec::new();
+    match resp.body {
+        ResponseBody::Bytes(ref bytes) => body.push_all(bytes),
+        ResponseBody::Seekable(ref mut reader, _) => {
+            reader.as_read().read_to_end(&mut body).unwrap();
+        },
+        ResponseBody::Stream(ref mut reader, _) => {
+            reader.read_to_end(&mut body).unwrap();
+        },
+    }
+    return String::from_utf8(body).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_serve() {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+
+    // tmp/outside.txt, and the served directory tmp/root with:
+    //   escape.txt -> ../outside.txt
+    //   docs/index.html
+    //   list/a<b>&.txt
+    //   list/.hidden
+    //   list/escape.txt -> ../../outside.txt
+    //
+    // tmp is a new directory, so concurrent runs don't share it
+    let tmp_name = |n| format!("mudpie-test-static-files-{}", n);
+    let mut n = 0;
+    let mut tmp = env::temp_dir().join(tmp_name(n));
+    while fs::create_dir(&tmp).is_err() {
+        n += 1;
+        assert!(n < 1000, "can't create a temp directory");
+        tmp = env::temp_dir().join(tmp_name(n));
+    }
+    let root = tmp.join("root");
+    fs::create_dir_all(root.join("docs")).unwrap();
+    fs::create_dir_all(root.join("list")).unwrap();
+    File::create(tmp.join("outside.txt")).unwrap().write_all(b"secret")
+        .unwrap();
+    symlink("../outside.txt", root.join("escape.txt")).unwrap();
+    File::create(root.join("docs/index.html")).unwrap().write_all(b"hi")
+        .unwrap();
+    File::create(root.join("list/a<b>&.txt")).unwrap();
+    File::create(root.join("list/.hidden")).unwrap();
+    symlink("../../outside.txt", root.join("list/escape.txt")).unwrap();
+
+    let mut files = StaticFiles::new("/static/", root.to_str().unwrap());
+    files.set_directory_listing(true);
+    let get = |target: &str| {
+        files.serve(&WebRequest::for_test("get", target, &[]))
+    };
+
+    // A symlink out of the root is as good as missing
+    let mut resp = get("/static/escape.txt");
+    assert_eq!(resp.code, 404);
+    assert!(!body_string(&mut resp).contains("secret"));
+
+    let mut resp = get("/static/docs/");
+    assert_eq!(resp.code, 200);
+    assert_eq!(body_string(&mut resp), "hi");
+
+    // Directories need the trailing slash, for relative links
+    let resp = get("/static/docs?x=1");
+    assert_eq!(resp.code, 301);
+    assert_eq!(resp.get_header("Location"), Some("/static/docs/?x=1"));
+
+    let mut resp = get("/static/list/");
+    assert_eq!(resp.code, 200);
+    let page = body_string(&mut resp);
+    assert!(page.contains(">a&lt;b&gt;&amp;.txt</a>"));
+    assert!(!page.contains("a<b>"));
+    assert!(!page.contains("hidden"));
+    assert!(!page.contains("escape"));
+
+    fs::remove_dir_all(&tmp).unwrap();
+}
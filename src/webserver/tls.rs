@@ -0,0 +1,82 @@
+//! TLS support: the server does the TCP part, and a TlsAcceptor wraps each
+//! accepted connection.  The acceptor is supplied by the application, so
+//! there's no built-in dependency on a TLS library.
+
+use std::io;
+use std::net::TcpStream;
+use std::collections::HashMap;
+
+use utils::genericsocket::GenericSocket;
+
+
+/// Facts about a TLS session, which handlers see in the request environ
+/// (see `WebRequest::get_environ`)
+pub struct TlsInfo {
+    /// The protocol version, ex: "TLSv1.2"
+    pub protocol: String,
+    /// The negotiated cipher suite, ex: "ECDHE-RSA-AES128-GCM-SHA256"
+    pub cipher: String,
+    /// The subject of the client's certificate, ex: "CN=alice", if the
+    /// client sent one and it was verified
+    pub peer_subject: Option<String>,
+}
+
+
+/// Does the server side TLS handshake on new connections.  See
+/// `WebServer::run_tls`.
+///
+/// `accept` is called on the worker thread that will serve the
+/// connection, with the read timeout already set to the keep-alive
+/// timeout, so a client that stalls the handshake doesn't hold the thread
+/// forever.  The returned socket's `set_read_timeout` should apply to the
+/// underlying `TcpStream`.
+pub trait TlsAcceptor: Send + Sync {
+    /// Do the handshake, and return the encrypted socket and facts about
+    /// the session.  On error, the connection is closed (and the error
+    /// logged).
+    fn accept(&self, stream: TcpStream)
+        -> Result<(Box<GenericSocket>, TlsInfo), io::Error>;
+}
+
+
+// Add the environ keys for a connection: url_scheme, and the tls_ keys
+pub fn add_environ(environ: &mut HashMap<Vec<u8>, Vec<u8>>,
+        info: Option<&TlsInfo>) {
+    let info = match info {
+        Some(info) => info,
+        None => {
+            environ.insert(b"url_scheme".to_vec(), b"http".to_vec());
+            return;
+        },
+    };
+    environ.insert(b"url_scheme".to_vec(), b"https".to_vec());
+    environ.insert(b"tls_protocol".to_vec(), info.protocol.as_bytes().to_vec());
+    environ.insert(b"tls_cipher".to_vec(), info.cipher.as_bytes().to_vec());
+    match info.peer_subject {
+        Some(ref subject) => {
+            environ.insert(b"tls_peer_subject".to_vec(),
+                    subject.as_bytes().to_vec());
+        },
+        None => {},
+    }
+}
+
+
+#[test]
+fn test_add_environ() {
+    let mut environ = HashMap::new();
+    add_environ(&mut environ, None);
+    assert_eq!(environ.get(&b"url_scheme"[..]), Some(&b"http".to_vec()));
+    assert_eq!(environ.len(), 1);
+
+    let info = TlsInfo {
+        protocol: "TLSv1.2".to_string(),
+        cipher: "AES128-SHA".to_string(),
+        peer_subject: Some("CN=alice".to_string()),
+    };
+    add_environ(&mut environ, Some(&info));
+    assert_eq!(environ.get(&b"url_scheme"[..]), Some(&b"https".to_vec()));
+    assert_eq!(environ.get(&b"tls_protocol"[..]), Some(&b"TLSv1.2".to_vec()));
+    assert_eq!(environ.get(&b"tls_peer_subject"[..]),
+        Some(&b"CN=alice".to_vec()));
+}
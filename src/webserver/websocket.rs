@@ -0,0 +1,628 @@
+//! WebSocket (RFC 6455): the opening handshake, and a message-oriented
+//! socket over the upgraded connection
+
+use std::io;
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+use std::ascii::AsciiExt;
+
+use super::{WebRequest, WebResponse};
+use utils::base64;
+use utils::byteutils;
+use utils::genericsocket;
+use utils::genericsocket::GenericSocket;
+use utils::sha1::sha1;
+
+static HANDSHAKE_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+static DEFAULT_MAX_MESSAGE_SIZE: usize = 1_000_000;
+// How long close() waits for the client to answer the close frame
+static CLOSE_TIMEOUT_SECS: u64 = 5;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+// Close status codes we send (RFC 6455 section 7.4.1)
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+
+/// A complete message from the client.  Fragmented messages are
+/// reassembled, and ping / pong / close frames are handled internally.
+#[derive(Debug, PartialEq)]
+pub enum WebSocketMessage {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+
+/// Why `WebSocket::recv` didn't return a message
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// The client closed the connection, with an optional status code and
+    /// reason.  The close frame has been answered.
+    Closed(Option<u16>, String),
+    /// The client violated the protocol, or sent a message that is too
+    /// large or isn't valid UTF-8.  The connection was closed with this
+    /// status code.
+    Protocol(u16),
+    /// No frame arrived before the read timeout (see
+    /// `WebSocket::set_read_timeout`).  The socket is still usable.
+    Timeout,
+    /// The connection failed, or was already closed
+    Io(io::Error),
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match *self {
+            WebSocketError::Closed(Some(code), ref reason) =>
+                write!(f, "closed by client: {} {}", code, reason),
+            WebSocketError::Closed(None, _) => write!(f, "closed by client"),
+            WebSocketError::Protocol(code) =>
+                write!(f, "protocol error, closed with {}", code),
+            WebSocketError::Timeout => write!(f, "read timed out"),
+            WebSocketError::Io(ref e) => write!(f, "{}", e),
+        };
+    }
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(err: io::Error) -> WebSocketError {
+        return WebSocketError::Io(err);
+    }
+}
+
+
+/// A connection upgraded to the WebSocket protocol.  See
+/// `WebServer::add_websocket`.
+///
+/// The socket is blocking and owned by the handler's worker thread.  `recv`
+/// waits for the next message, answering pings and handling the close
+/// handshake along the way.  To also send messages that don't answer the
+/// client (e.g. from a channel), set a read timeout and send between
+/// `recv` calls that return `WebSocketError::Timeout`.
+pub struct WebSocket {
+    stream: Box<GenericSocket>,
+    // Received bytes not yet parsed into frames
+    buffer: Vec<u8>,
+    // The opcode and data of a fragmented message being received
+    message_opcode: Option<u8>,
+    message: Vec<u8>,
+    max_message_size: usize,
+    close_sent: bool,
+    closed: bool,
+}
+
+impl WebSocket {
+    // buffer: bytes already received after the handshake request
+    fn new(stream: Box<GenericSocket>, buffer: Vec<u8>) -> WebSocket {
+        return WebSocket {
+            stream: stream,
+            buffer: buffer,
+            message_opcode: None,
+            message: Vec::new(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            close_sent: false,
+            closed: false,
+        };
+    }
+
+    /// Set the largest message the client may send (after reassembling
+    /// fragments).  A larger one closes the connection with status 1009.
+    /// Default is 1,000,000 bytes.
+    pub fn set_max_message_size(&mut self, size: usize) {
+        self.max_message_size = size;
+    }
+
+    /// Set how long `recv` waits for data before returning
+    /// `WebSocketError::Timeout`.  Default is None (wait forever).
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>)
+            -> Result<(), io::Error> {
+        return self.stream.set_read_timeout(timeout);
+    }
+
+    /// Wait for the next message.  Any error other than `Timeout` means the
+    /// connection is closed; the handler should return.
+    pub fn recv(&mut self) -> Result<WebSocketMessage, WebSocketError> {
+        loop {
+            if self.closed {
+                return Err(WebSocketError::Io(io::Error::new(
+                        io::ErrorKind::NotConnected, "websocket is closed")));
+            }
+            let (fin, opcode, payload) = try!(self.read_frame());
+            match opcode {
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => {
+                    // A continuation needs a message to continue, and a new
+                    // message can't start in the middle of another
+                    let started = self.message_opcode.is_some();
+                    if (opcode == OP_CONTINUATION) != started {
+                        return Err(self.fail(CLOSE_PROTOCOL_ERROR));
+                    }
+                    if !started {
+                        self.message_opcode = Some(opcode);
+                    }
+                    self.message.push_all(&payload);
+                    if fin {
+                        return self.finish_message();
+                    }
+                },
+                OP_PING => {
+                    try!(self.send_frame(OP_PONG, &payload));
+                },
+                OP_PONG => {},
+                OP_CLOSE => {
+                    return Err(self.handle_close(&payload));
+                },
+                _ => {
+                    return Err(self.fail(CLOSE_PROTOCOL_ERROR));
+                },
+            }
+        }
+    }
+
+    /// Send a text message
+    pub fn send_text(&mut self, text: &str) -> Result<(), io::Error> {
+        return self.send_frame(OP_TEXT, text.as_bytes());
+    }
+
+    /// Send a binary message
+    pub fn send_binary(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        return self.send_frame(OP_BINARY, data);
+    }
+
+    /// Send a ping, e.g. to check that an idle client is still there.  The
+    /// client's pong is ignored by `recv`.  `data` can be at most 125 bytes.
+    pub fn ping(&mut self, data: &[u8]) -> Result<(), io::Error> {
+        if data.len() > 125 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "ping data over 125 bytes"));
+        }
+        return self.send_frame(OP_PING, data);
+    }
+
+    /// Start the close handshake with a status code (e.g. 1000 for a normal
+    /// closure, or 1001 when the server is going away) and a reason of at
+    /// most 123 bytes, then wait a few seconds for the client's close frame.
+    /// Messages that arrive in the meantime are discarded.
+    ///
+    /// If the handler returns without closing, this is done with status
+    /// 1000.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), io::Error> {
+        if self.closed {
+            return Ok(());
+        }
+        if reason.len() > 123 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                        "close reason over 123 bytes"));
+        }
+        let mut payload = vec![(code >> 8) as u8, code as u8];
+        payload.push_all(reason.as_bytes());
+        try!(self.send_frame(OP_CLOSE, &payload));
+        try!(self.stream.set_read_timeout(
+                Some(Duration::from_secs(CLOSE_TIMEOUT_SECS))));
+        while !self.closed {
+            match self.read_frame() {
+                Ok((_, OP_CLOSE, _)) => self.closed = true,
+                Ok(..) => {},
+                Err(..) => self.closed = true,
+            }
+        }
+        return Ok(());
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8])
+            -> Result<(), io::Error> {
+        if self.close_sent {
+            return Err(io::Error::new(io::ErrorKind::NotConnected,
+                        "websocket is closed"));
+        }
+        if opcode == OP_CLOSE {
+            self.close_sent = true;
+        }
+        let frame = encode_frame(opcode, payload);
+        return self.stream.write_all(&frame);
+    }
+
+    // Read and unmask one whole frame: (fin, opcode, payload).
+    // Only complete frames are removed from the buffer, so a timeout can be
+    // retried.
+    fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>), WebSocketError> {
+        try!(self.fill(2));
+        let fin = self.buffer[0] & 0x80 != 0;
+        let opcode = self.buffer[0] & 0x0f;
+        let is_control = opcode & 0x08 != 0;
+        // No extensions are negotiated, so the RSV bits must be 0, and
+        // clients must mask their frames
+        if self.buffer[0] & 0x70 != 0 || self.buffer[1] & 0x80 == 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR));
+        }
+
+        let (len, header_len) = match self.buffer[1] & 0x7f {
+            126 => {
+                try!(self.fill(4));
+                (read_be(&self.buffer[2..4]), 4)
+            },
+            127 => {
+                try!(self.fill(10));
+                (read_be(&self.buffer[2..10]), 10)
+            },
+            len => (len as u64, 2),
+        };
+        // Control frames can't be fragmented, and are short
+        if is_control && (!fin || len > 125) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR));
+        }
+        // Check the size before reading (and buffering) the payload
+        if !is_control && len > (self.max_message_size
+                - self.message.len()) as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG));
+        }
+
+        let len = len as usize;
+        let start = header_len + 4;
+        try!(self.fill(start + len));
+        let mut payload = self.buffer[start..start + len].to_vec();
+        for (i, c) in payload.iter_mut().enumerate() {
+            *c ^= self.buffer[header_len + i % 4];
+        }
+        let rest = self.buffer[start + len..].to_vec();
+        self.buffer = rest;
+        return Ok((fin, opcode, payload));
+    }
+
+    // Read until the buffer has at least size bytes
+    fn fill(&mut self, size: usize) -> Result<(), WebSocketError> {
+        let mut chunk = [0u8; 4096];
+        while self.buffer.len() < size {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    return Err(WebSocketError::Io(io::Error::new(
+                        io::ErrorKind::BrokenPipe, "client disconnected")));
+                },
+                Ok(n) => self.buffer.push_all(&chunk[..n]),
+                Err(ref e) if genericsocket::is_timeout(e) => {
+                    return Err(WebSocketError::Timeout);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    self.closed = true;
+                    return Err(WebSocketError::Io(e));
+                },
+            }
+        }
+        return Ok(());
+    }
+
+    fn finish_message(&mut self) -> Result<WebSocketMessage, WebSocketError> {
+        let opcode = self.message_opcode.take().unwrap();
+        let data = mem::replace(&mut self.message, Vec::new());
+        if opcode == OP_BINARY {
+            return Ok(WebSocketMessage::Binary(data));
+        }
+        return match String::from_utf8(data) {
+            Ok(text) => Ok(WebSocketMessage::Text(text)),
+            Err(..) => Err(self.fail(CLOSE_INVALID_DATA)),
+        };
+    }
+
+    // Answer the client's close frame (echoing its code), and end
+    fn handle_close(&mut self, payload: &[u8]) -> WebSocketError {
+        if payload.len() == 1 {
+            return self.fail(CLOSE_PROTOCOL_ERROR);
+        }
+        let mut code = None;
+        let mut reason = String::new();
+        if payload.len() >= 2 {
+            let val = ((payload[0] as u16) << 8) | payload[1] as u16;
+            if !is_valid_close_code(val) {
+                return self.fail(CLOSE_PROTOCOL_ERROR);
+            }
+            reason = match String::from_utf8(payload[2..].to_vec()) {
+                Ok(reason) => reason,
+                Err(..) => return self.fail(CLOSE_INVALID_DATA),
+            };
+            code = Some(val);
+        }
+        if !self.close_sent {
+            let reply = match code {
+                Some(code) => vec![(code >> 8) as u8, code as u8],
+                None => Vec::new(),
+            };
+            let _ = self.send_frame(OP_CLOSE, &reply);
+        }
+        self.closed = true;
+        return WebSocketError::Closed(code, reason);
+    }
+
+    // Close the connection because the client misbehaved
+    fn fail(&mut self, code: u16) -> WebSocketError {
+        if !self.close_sent {
+            let _ = self.send_frame(OP_CLOSE,
+                    &[(code >> 8) as u8, code as u8]);
+        }
+        self.closed = true;
+        return WebSocketError::Protocol(code);
+    }
+}
+
+
+// Codes a client may send in a close frame
+fn is_valid_close_code(code: u16) -> bool {
+    return match code {
+        1000...1003 | 1007...1011 | 3000...4999 => true,
+        _ => false,
+    };
+}
+
+
+// A big-endian length from a frame header
+fn read_be(bytes: &[u8]) -> u64 {
+    return bytes.iter().fold(0, |n, c| (n << 8) | *c as u64);
+}
+
+
+// An unfragmented, unmasked (server to client) frame
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    let len = payload.len();
+    if len < 126 {
+        frame.push(len as u8);
+    } else if len < 65536 {
+        frame.push(126);
+        frame.push((len >> 8) as u8);
+        frame.push(len as u8);
+    } else {
+        frame.push(127);
+        for i in 0..8 {
+            frame.push(((len as u64) >> (56 - i * 8)) as u8);
+        }
+    }
+    frame.push_all(payload);
+    return frame;
+}
+
+
+// Check the opening handshake, and return the 101 response accepting it,
+// or an error response.  A request that isn't a websocket upgrade at all
+// gets 426 Upgrade Required.
+pub fn handshake(req: &WebRequest) -> Result<WebResponse, WebResponse> {
+    if !header_has_token(req, b"http_upgrade", b"websocket") {
+        let mut resp = WebResponse::new();
+        resp.set_code(426, "Upgrade Required");
+        resp.set_header("Upgrade", "websocket");
+        resp.set_body_str("Error 426: Upgrade Required");
+        return Err(resp);
+    }
+    let version = req.get_environ_value(b"http_sec-websocket-version");
+    if version.map(|v| byteutils::strip(&v) == b"13") != Some(true) {
+        let mut resp = WebResponse::new();
+        resp.set_code(426, "Upgrade Required");
+        resp.set_header("Upgrade", "websocket");
+        resp.set_header("Sec-WebSocket-Version", "13");
+        resp.set_body_str("Error 426: Unsupported WebSocket version");
+        return Err(resp);
+    }
+    let key = req.get_environ_value(b"http_sec-websocket-key")
+        .map(|v| byteutils::strip(&v).to_vec());
+    let key_ok = match key {
+        Some(ref key) => base64::decode(key).map(|k| k.len()) == Some(16),
+        None => false,
+    };
+    let http11 = &*req.get_environ_value(b"protocol").unwrap() == b"http/1.1";
+    if !key_ok || !http11 || req.get_method() != "get"
+            || !header_has_token(req, b"http_connection", b"upgrade") {
+        let mut resp = WebResponse::new();
+        resp.set_code(400, "Bad Request");
+        resp.set_body_str("Error 400: Invalid WebSocket handshake");
+        return Err(resp);
+    }
+
+    let mut resp = WebResponse::new();
+    resp.set_code(101, "Switching Protocols");
+    resp.set_header("Upgrade", "websocket");
+    resp.set_header("Sec-WebSocket-Accept", &accept_key(&key.unwrap()));
+    return Ok(resp);
+}
+
+
+// The Sec-WebSocket-Accept value for a Sec-WebSocket-Key
+fn accept_key(key: &[u8]) -> String {
+    let mut data = key.to_vec();
+    data.push_all(HANDSHAKE_GUID.as_bytes());
+    return base64::encode(&sha1(&data));
+}
+
+
+// Whether a comma separated request header contains a token
+// (case-insensitive)
+fn header_has_token(req: &WebRequest, name: &[u8], token: &[u8]) -> bool {
+    return match req.get_environ_value(name) {
+        Some(val) => val.split(|c| *c == b',').any(|t| {
+            byteutils::strip(t).eq_ignore_ascii_case(token)
+        }),
+        None => false,
+    };
+}
+
+
+// Run the handler over the upgraded connection, then close it
+pub fn run(stream: Box<GenericSocket>, buffer: Vec<u8>, req: &WebRequest,
+        handler: &super::WebSocketFunction) {
+    let mut ws = WebSocket::new(stream, buffer);
+    // The keep-alive timeout doesn't apply to a websocket
+    if ws.set_read_timeout(None).is_err() {
+        return;
+    }
+    (*handler)(req, &mut ws);
+    let _ = ws.close(CLOSE_NORMAL, "");
+}
+
+
+#[cfg(test)]
+struct TestSocket {
+    input: io::Cursor<Vec<u8>>,
+    output: ::std::sync::Arc<::std::sync::Mutex<Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl GenericSocket for TestSocket {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        return io::Read::read(&mut self.input, buf);
+    }
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        self.output.lock().unwrap().push_all(buf);
+        return Ok(());
+    }
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>)
+            -> Result<(), io::Error> {
+        return Ok(());
+    }
+}
+
+// A websocket reading the given client frames, and the bytes it sent
+#[cfg(test)]
+fn test_websocket(input: Vec<u8>)
+        -> (WebSocket, ::std::sync::Arc<::std::sync::Mutex<Vec<u8>>>) {
+    use std::sync::{Arc, Mutex};
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let sock = TestSocket {
+        input: io::Cursor::new(input),
+        output: output.clone(),
+    };
+    return (WebSocket::new(Box::new(sock), Vec::new()), output);
+}
+
+// A masked client frame
+#[cfg(test)]
+fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = encode_frame(0, payload);
+    frame[0] = first;
+    frame[1] |= 0x80;
+    let header_len = frame.len() - payload.len();
+    for i in 0..payload.len() {
+        frame[header_len + i] ^= mask[i % 4];
+    }
+    for (i, c) in mask.iter().enumerate() {
+        frame.insert(header_len + i, *c);
+    }
+    return frame;
+}
+
+
+#[cfg(test)]
+fn ws_request(headers: &[(&str, &str)]) -> WebRequest {
+    return WebRequest::for_test("GET", "/ws", headers);
+}
+
+
+#[test]
+fn test_handshake() {
+    let mut headers = vec![("upgrade", "websocket"),
+        ("connection", "keep-alive, Upgrade"),
+        ("sec-websocket-version", "13"),
+        ("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")];
+    let resp = handshake(&ws_request(&headers)).ok().unwrap();
+    assert_eq!(resp.get_code(), 101);
+    assert_eq!(resp.get_header("Sec-WebSocket-Accept"),
+        Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    headers[3] = ("sec-websocket-key", "dG9vIHNob3J0");
+    let resp = handshake(&ws_request(&headers)).err().unwrap();
+    assert_eq!(resp.get_code(), 400);
+    headers[2] = ("sec-websocket-version", "8");
+    let resp = handshake(&ws_request(&headers)).err().unwrap();
+    assert_eq!(resp.get_code(), 426);
+    assert_eq!(resp.get_header("Sec-WebSocket-Version"), Some("13"));
+    assert_eq!(handshake(&ws_request(&[])).err().unwrap().get_code(), 426);
+}
+
+#[test]
+fn test_recv() {
+    let mut input = client_frame(0x81, b"hello");
+    // Fragmented binary message, with a ping in the middle
+    input.push_all(&client_frame(0x02, &[1, 2]));
+    input.push_all(&client_frame(0x89, b"p"));
+    input.push_all(&client_frame(0x80, &[3]));
+    input.push_all(&client_frame(0x81, &vec![b'x'; 300]));
+    input.push_all(&client_frame(0x88, &[0x03, 0xe8, b'b', b'y', b'e']));
+    let (mut ws, output) = test_websocket(input);
+    assert_eq!(ws.recv().unwrap(), WebSocketMessage::Text("hello".to_string()));
+    assert_eq!(ws.recv().unwrap(), WebSocketMessage::Binary(vec![1, 2, 3]));
+    match ws.recv().unwrap() {
+        WebSocketMessage::Text(text) => assert_eq!(text.len(), 300),
+        _ => panic!("not text"),
+    }
+    match ws.recv() {
+        Err(WebSocketError::Closed(Some(1000), ref reason)) => {
+            assert_eq!(reason, "bye");
+        },
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(ws.recv().is_err());
+    ws.send_text("late").unwrap_err();
+    // Pong, then the close reply
+    assert_eq!(&*output.lock().unwrap(),
+        &[0x8a, 1, b'p', 0x88, 2, 0x03, 0xe8]);
+}
+
+#[test]
+fn test_recv_errors() {
+    let cases = [
+        // Unmasked
+        (encode_frame(OP_TEXT, b"hi"), CLOSE_PROTOCOL_ERROR),
+        // Unknown opcode, RSV bit set, fragmented ping
+        (client_frame(0x83, b""), CLOSE_PROTOCOL_ERROR),
+        (client_frame(0xc1, b"hi"), CLOSE_PROTOCOL_ERROR),
+        (client_frame(0x09, b""), CLOSE_PROTOCOL_ERROR),
+        // Continuation without a message
+        (client_frame(0x80, b"hi"), CLOSE_PROTOCOL_ERROR),
+        (client_frame(0x81, &[0xff]), CLOSE_INVALID_DATA),
+        (client_frame(0x82, &[0; 200]), CLOSE_TOO_BIG),
+        (client_frame(0x88, &[0x03]), CLOSE_PROTOCOL_ERROR),
+        (client_frame(0x88, &[0x03, 0xed]), CLOSE_PROTOCOL_ERROR),
+    ];
+    for &(ref input, code) in cases.iter() {
+        let (mut ws, output) = test_websocket(input.clone());
+        ws.set_max_message_size(100);
+        match ws.recv() {
+            Err(WebSocketError::Protocol(c)) => assert_eq!(c, code),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(&*output.lock().unwrap(),
+            &[0x88, 2, (code >> 8) as u8, code as u8]);
+    }
+
+    // The size limit applies to the whole message
+    let mut input = client_frame(0x02, &[0; 60]);
+    input.push_all(&client_frame(0x80, &[0; 60]));
+    let (mut ws, _) = test_websocket(input);
+    ws.set_max_message_size(100);
+    match ws.recv() {
+        Err(WebSocketError::Protocol(CLOSE_TOO_BIG)) => {},
+        other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn test_send() {
+    let (mut ws, output) = test_websocket(client_frame(0x88, b""));
+    ws.send_text("hi").unwrap();
+    ws.send_binary(&vec![7; 200]).unwrap();
+    ws.close(1001, "").unwrap();
+    let out = output.lock().unwrap();
+    assert_eq!(&out[..4], &[0x81, 2, b'h', b'i']);
+    assert_eq!(&out[4..8], &[0x82, 126, 0, 200]);
+    assert_eq!(&out[208..], &[0x88, 2, 0x03, 0xe9]);
+    assert_eq!(encode_frame(OP_BINARY, &vec![0; 70000])[..10],
+        [0x82, 127, 0, 0, 0, 0, 0, 1, 0x11, 0x70]);
+}
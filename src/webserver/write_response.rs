@@ -1,43 +1,113 @@
 //use std;
+use std::io;
+use std::ascii::AsciiExt;
 
-use super::{WebRequest, WebResponse, Logger};
+use super::{WebRequest, WebResponse, ResponseBody, Logger};
+use super::header_name_eq;
+use utils::genericsocket;
 use utils::genericsocket::GenericSocket;
+use utils::byteutils;
 
 
 // Send response headers and body.
 // Body will not be sent if the request was a HEAD request.
 // Headers will be sent as UTF-8 bytes, but you need to stay in ASCII/Latin-1
 // range to be safe.
-pub fn write_response(stream: &mut GenericSocket, 
-        request: Option<&WebRequest>, 
-        response: &WebResponse, 
-        log: &Logger) {
+// If the status line or headers are invalid (e.g. a CR/LF in a header value,
+// which would allow response splitting), a 500 is sent instead.
+//
+// keep_alive: whether we'd like to keep the connection open afterwards.
+// Returns true if the connection can actually be used for another request,
+// or for a 101, if it was sent and the connection can be upgraded.
+pub fn write_response(stream: &mut GenericSocket,
+        request: Option<&WebRequest>,
+        response: &mut WebResponse,
+        keep_alive: bool,
+        log: &Logger) -> bool {
+
+    match check_response_head(response) {
+        Ok(()) => {},
+        Err(reason) => {
+            log.log_invalid_response(&reason);
+            *response = WebResponse::new();
+            response.set_code(500, "Internal Server Error");
+            response.set_body_str("Error 500: Invalid response from handler");
+        },
+    }
+
+    // We send our own Content-Length and Connection headers, so the
+    // handler's would contradict or repeat them.  It can still ask for the
+    // connection to be closed.
+    let keep_alive = keep_alive && !asks_to_close(response);
+    response.remove_header("Content-Length");
+    response.remove_header("Connection");
 
     // Respond with the max version the client requested
     let mut protocol = "HTTP/1.1";
+    let mut method = "";
+    let mut path = "";
     if request.is_some() {
         let req = request.unwrap();
-        if &**req.environ.get(&b"protocol"[..]).unwrap() 
-                == b"http/1.0" {
+        if &*req.get_environ_value(b"protocol").unwrap() == b"http/1.0" {
             protocol = "HTTP/1.0";
         }
+        method = req.get_method();
+        path = req.get_path();
+    }
 
-        log.log_request_response(req.get_method(), req.get_path(),
-                response.code, response.body.len());
-    } else {
-        // Didn't get a valid request
-        log.log_request_response("", "", 
-                response.code, response.body.len());
+    // These responses can't have a body, or a Content-Length
+    let no_body = response.code == 204 || response.code == 304
+        || (response.code >= 100 && response.code < 200);
+    if no_body {
+        response.body = ResponseBody::Bytes(Vec::new());
+    }
+
+    // A handler that sets its own Transfer-Encoding frames the body itself,
+    // and we can't tell where it ends.
+    let custom_framing = response.get_header("Transfer-Encoding").is_some();
+
+    // Streamed bodies of unknown length are chunked, except HTTP/1.0 doesn't
+    // know chunked, so there the end of the body is signaled by closing the
+    // connection.
+    let mut chunked = false;
+    let mut keep_alive = keep_alive && !custom_framing;
+    let content_length = match response.body {
+        ResponseBody::Bytes(ref body) => Some(body.len() as u64),
+        ResponseBody::Stream(_, len) => len,
+        ResponseBody::Seekable(_, len) => Some(len),
+    };
+    if content_length.is_none() && !custom_framing {
+        if protocol == "HTTP/1.1" {
+            chunked = true;
+        } else {
+            keep_alive = false;
+        }
     }
 
+    let upgrade = response.code == 101;
     let mut resp = String::new();
-    resp.push_str(&format!("{} {} {}\r\n", 
+    resp.push_str(&format!("{} {} {}\r\n",
                 protocol, response.code, response.status));
-    resp.push_str("Connection: close\r\n");
-    resp.push_str(&format!("Content-Length: {}\r\n", 
-                response.body.len()));
+    if upgrade {
+        resp.push_str("Connection: Upgrade\r\n");
+    } else if keep_alive {
+        resp.push_str("Connection: keep-alive\r\n");
+    } else {
+        resp.push_str("Connection: close\r\n");
+    }
+    if chunked {
+        resp.push_str("Transfer-Encoding: chunked\r\n");
+    }
+    if !custom_framing && !no_body {
+        match content_length {
+            Some(len) => {
+                resp.push_str(&format!("Content-Length: {}\r\n", len));
+            },
+            None => {},
+        }
+    }
 
-    for (k, v) in response.headers.iter() {
+    for &(ref k, ref v) in response.headers.iter() {
         resp.push_str(&k);
         resp.push_str(": ");
         resp.push_str(&v);
@@ -48,19 +118,315 @@ pub fn write_response(stream: &mut GenericSocket,
     // Note that success still doesn't guarantee the client got the data.
     let ioret = stream.write_all(resp.as_bytes());
     if ioret.is_err() {
-        return;
+        log.log_request_response(method, path, response.code, 0);
+        return false;
     }
 
     // Send the body unless it was a HEAD request.
     // HTTP HEAD is so retarded because you can't see error bodies.
-    let mut send_body = true;
-    if request.is_some() && request.unwrap().method == "head" {
-        send_body = false;
+    let send_body = method != "head";
+    let ioret = match response.body {
+        ResponseBody::Bytes(ref body) => {
+            if send_body {
+                stream.write_all(body).map(|_| body.len())
+            } else {
+                Ok(body.len())
+            }
+        },
+        ResponseBody::Stream(ref mut reader, len) => {
+            if send_body {
+                write_stream_body(stream, &mut **reader, chunked, len)
+            } else {
+                Ok(0)
+            }
+        },
+        ResponseBody::Seekable(ref mut reader, len) => {
+            if send_body {
+                write_stream_body(stream, reader.as_read(), chunked, Some(len))
+            } else {
+                Ok(0)
+            }
+        },
+    };
+
+    match ioret {
+        Ok(body_len) => {
+            log.log_request_response(method, path, response.code, body_len);
+            return keep_alive || upgrade;
+        },
+        Err(e) => {
+            log.log_request_response(method, path, response.code, 0);
+            if genericsocket::is_timeout(&e) {
+                log.log_timeout("writing response");
+            } else {
+                log.log_write_response_error(e);
+            }
+            return false;
+        },
+    }
+}
+
+
+// Check that the status code and message and the header names and values
+// can be sent without corrupting the response.
+// Returns a description of the first problem found.
+fn check_response_head(response: &WebResponse) -> Result<(), String> {
+    if response.code < 100 || response.code > 999 {
+        return Err(format!("invalid status code: {}", response.code));
+    }
+    if !response.status.bytes().all(is_field_char) {
+        return Err(format!("invalid status message: {:?}", response.status));
     }
-    if send_body {
-        let ioret = stream.write_all(&response.body);
-        if ioret.is_err() {
-            return;
-        } 
+    for &(ref name, ref value) in response.headers.iter() {
+        if name.is_empty() || !name.bytes().all(byteutils::is_token_char) {
+            return Err(format!("invalid header name: {:?}", name));
+        }
+        if !value.bytes().all(is_field_char) {
+            return Err(format!("invalid value for header {}: {:?}",
+                        name, value));
+        }
     }
+    return Ok(());
+}
+
+
+// Whether the handler set "Connection: close"
+fn asks_to_close(response: &WebResponse) -> bool {
+    return response.headers.iter().any(|&(ref name, ref value)| {
+        header_name_eq(name, "Connection") && value.split(',').any(|token| {
+            token.trim().eq_ignore_ascii_case("close")
+        })
+    });
+}
+
+
+// CR and LF would end the line early, and NUL is rejected by many clients
+fn is_field_char(c: u8) -> bool {
+    return c != b'\r' && c != b'\n' && c != 0;
+}
+
+
+// Copy the body from reader to the client, optionally chunk encoding it.
+// If len is given, copy exactly that many bytes, or fail.
+// Returns the number of body bytes (before chunk encoding).
+fn write_stream_body(stream: &mut GenericSocket, reader: &mut io::Read,
+        chunked: bool, len: Option<u64>) -> Result<usize, io::Error> {
+    let chunk_size = 16384;
+    let mut chunk_buff = Vec::with_capacity(chunk_size);
+    chunk_buff.resize(chunk_size, 0);
+
+    let mut total = 0;
+    loop {
+        let want = match len {
+            Some(len) => {
+                let remaining = len - total as u64;
+                if remaining < chunk_size as u64 {
+                    remaining as usize
+                } else {
+                    chunk_size
+                }
+            },
+            None => chunk_size,
+        };
+        if want == 0 {
+            break;
+        }
+        let size = try!(reader.read(&mut chunk_buff[..want]));
+        if size == 0 {
+            if len.is_some() {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                            "body reader ended before its length"));
+            }
+            break;
+        }
+        total += size;
+        if chunked {
+            // Send size line, data, and CRLF in one write
+            let mut out = format!("{:x}\r\n", size).into_bytes();
+            out.push_all(&chunk_buff[..size]);
+            out.push_all(b"\r\n");
+            try!(stream.write_all(&out));
+        } else {
+            try!(stream.write_all(&chunk_buff[..size]));
+        }
+    }
+
+    if chunked {
+        // Last chunk, no trailers
+        try!(stream.write_all(b"0\r\n\r\n"));
+    }
+    return Ok(total);
+}
+
+
+#[cfg(test)]
+fn write_to_bytes(response: &mut WebResponse) -> Vec<u8> {
+    return write_for_request(None, response).1;
+}
+
+// Whether the connection can be kept open, and what was sent
+#[cfg(test)]
+fn write_for_request(request: Option<&WebRequest>, response: &mut WebResponse)
+        -> (bool, Vec<u8>) {
+    let mut stream = io::Cursor::new(Vec::new());
+    let keep_alive = write_response(&mut stream, request, response, true,
+            &Logger::new(false));
+    return (keep_alive, stream.into_inner());
+}
+
+
+#[test]
+fn test_header_order() {
+    let mut resp = WebResponse::new();
+    resp.set_header("Content-Type", "text/plain");
+    resp.append_header("Link", "</a>; rel=preload");
+    resp.append_header("X-B", "1");
+    resp.append_header("link", "</b>; rel=preload");
+    resp.set_header("x-b", "2");
+    resp.append_header("Vary", "Cookie");
+    resp.set_body_str("hi");
+    assert_eq!(resp.get_header("LINK"), Some("</a>; rel=preload"));
+
+    let out = write_to_bytes(&mut resp);
+    assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\n\
+Connection: keep-alive\r\n\
+Content-Length: 2\r\n\
+Content-Type: text/plain\r\n\
+Link: </a>; rel=preload\r\n\
+X-B: 2\r\n\
+link: </b>; rel=preload\r\n\
+Vary: Cookie\r\n\
+\r\n\
+hi");
+
+    resp.remove_header("Link");
+    assert_eq!(resp.get_header("link"), None);
+    assert_eq!(resp.get_headers().len(), 3);
+}
+
+#[test]
+fn test_invalid_head() {
+    let mut resp = WebResponse::new();
+    resp.set_header("Location", "/x\r\nSet-Cookie: evil=1");
+    let out = String::from_utf8(write_to_bytes(&mut resp)).unwrap();
+    assert!(out.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    assert!(!out.contains("evil"));
+
+    let mut resp = WebResponse::new();
+    resp.set_header("Bad Name", "1");
+    assert!(check_response_head(&resp).is_err());
+
+    let mut resp = WebResponse::new();
+    resp.set_header("X-Nul", "a\0b");
+    assert!(check_response_head(&resp).is_err());
+
+    let mut resp = WebResponse::new();
+    resp.set_code(200, "OK\r\nX-Evil: 1");
+    assert!(check_response_head(&resp).is_err());
+
+    let mut resp = WebResponse::new();
+    resp.set_code(20, "OK");
+    assert!(check_response_head(&resp).is_err());
+
+    let mut resp = WebResponse::new();
+    resp.set_code(404, "Not\tFound");
+    resp.set_header("X-Ok", "a b\tc");
+    assert!(check_response_head(&resp).is_ok());
+}
+
+#[test]
+fn test_no_body() {
+    let mut resp = WebResponse::new();
+    resp.set_code(304, "Not Modified");
+    resp.set_body_str("ignored");
+    let out = write_to_bytes(&mut resp);
+    assert_eq!(String::from_utf8(out).unwrap(),
+        "HTTP/1.1 304 Not Modified\r\nConnection: keep-alive\r\n\r\n");
+}
+
+#[test]
+fn test_stream_body() {
+    use std::io::Read;
+
+    // Chunked for HTTP/1.1, one chunk per read
+    let mut resp = WebResponse::new();
+    resp.set_body_reader(io::Cursor::new(&b"abc"[..])
+        .chain(io::Cursor::new(&b"de"[..])));
+    let (keep_alive, out) = write_for_request(None, &mut resp);
+    assert!(keep_alive);
+    assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\n\
+Connection: keep-alive\r\n\
+Transfer-Encoding: chunked\r\n\
+\r\n\
+3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n");
+
+    // HTTP/1.0 doesn't know chunked, so the body ends at connection close
+    let mut req = WebRequest::for_test("get", "/", &[]);
+    req.get_environ_mut().insert(b"protocol".to_vec(), b"http/1.0".to_vec());
+    let mut resp = WebResponse::new();
+    resp.set_body_reader(io::Cursor::new(&b"abc"[..]));
+    let (keep_alive, out) = write_for_request(Some(&req), &mut resp);
+    assert!(!keep_alive);
+    assert_eq!(String::from_utf8(out).unwrap(),
+        "HTTP/1.0 200 OK\r\nConnection: close\r\n\r\nabc");
+
+    // A reader shorter than its length leaves the response truncated, so
+    // the connection can't be used again
+    let mut resp = WebResponse::new();
+    resp.set_body_reader_with_length(io::Cursor::new(&b"abc"[..]), 5);
+    let (keep_alive, out) = write_for_request(None, &mut resp);
+    assert!(!keep_alive);
+    assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\n\
+Connection: keep-alive\r\n\
+Content-Length: 5\r\n\
+\r\n\
+abc");
+
+    // The handler framed the body itself
+    let mut resp = WebResponse::new();
+    resp.set_header("Transfer-Encoding", "chunked");
+    resp.set_body(b"3\r\nabc\r\n0\r\n\r\n");
+    let (keep_alive, out) = write_for_request(None, &mut resp);
+    assert!(!keep_alive);
+    assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\n\
+Connection: close\r\n\
+Transfer-Encoding: chunked\r\n\
+\r\n\
+3\r\nabc\r\n0\r\n\r\n");
+}
+
+#[test]
+fn test_framing_headers() {
+    // The handler's Content-Length and Connection are replaced by ours
+    let mut resp = WebResponse::new();
+    resp.set_header("Content-Length", "99");
+    resp.set_header("Connection", "upgrade");
+    resp.set_body_str("hi");
+    let (keep_alive, out) = write_for_request(None, &mut resp);
+    assert!(keep_alive);
+    assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\n\
+Connection: keep-alive\r\n\
+Content-Length: 2\r\n\
+\r\n\
+hi");
+
+    // But it can close the connection
+    let mut resp = WebResponse::new();
+    resp.set_header("connection", "Close");
+    resp.set_body_str("hi");
+    let (keep_alive, out) = write_for_request(None, &mut resp);
+    assert!(!keep_alive);
+    assert_eq!(String::from_utf8(out).unwrap(), "HTTP/1.1 200 OK\r\n\
+Connection: close\r\n\
+Content-Length: 2\r\n\
+\r\n\
+hi");
+
+    // A Content-Length can't go with the handler's own Transfer-Encoding
+    let mut resp = WebResponse::new();
+    resp.set_header("Transfer-Encoding", "chunked");
+    resp.set_header("Content-Length", "12");
+    resp.set_body(b"0\r\n\r\n");
+    let out = String::from_utf8(write_to_bytes(&mut resp)).unwrap();
+    assert!(!out.contains("Content-Length"));
 }